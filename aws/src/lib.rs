@@ -0,0 +1,222 @@
+//! AWS SDK credentials provider for [ci-id](https://docs.rs/ci-id): the ambient OIDC identity
+//! token of the CI job is exchanged for temporary AWS credentials with
+//! `AssumeRoleWithWebIdentity`.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use aws_config::BehaviorVersion;
+//! use ci_id_aws::CiIdCredentialsProvider;
+//!
+//! let provider = CiIdCredentialsProvider::builder()
+//!     .role_arn("arn:aws:iam::123456789012:role/ci-deploy")
+//!     .build();
+//! let config = aws_config::defaults(BehaviorVersion::latest())
+//!     .credentials_provider(provider)
+//!     .load()
+//!     .await;
+//! # }
+//! ```
+//!
+//! The SDK caches the credentials and calls the provider again before they expire: each call
+//! detects a new identity token, so short-lived CI tokens are not reused.
+
+use aws_credential_types::{
+    provider::{self, error::CredentialsError, future, ProvideCredentials},
+    Credentials,
+};
+use aws_sdk_sts::config::{BehaviorVersion, Region};
+use ci_id::CIIDError;
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Audience of the identity token unless set with [`Builder::audience`]
+pub const DEFAULT_AUDIENCE: &str = "sts.amazonaws.com";
+
+/// STS region unless set with [`Builder::region`], `AWS_REGION` or `AWS_DEFAULT_REGION`
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Provider name in the returned [`Credentials`]
+const PROVIDER_NAME: &str = "ci-id";
+
+/// Credentials provider that assumes an IAM role with the detected OIDC identity token
+#[derive(Debug, Clone)]
+pub struct CiIdCredentialsProvider {
+    role_arn: Option<String>,
+    session_name: Option<String>,
+    audience: String,
+    duration: Option<Duration>,
+    sts: aws_sdk_sts::Client,
+}
+
+/// Builder for [`CiIdCredentialsProvider`]
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    role_arn: Option<String>,
+    session_name: Option<String>,
+    audience: Option<String>,
+    duration: Option<Duration>,
+    region: Option<String>,
+    sts: Option<aws_sdk_sts::Client>,
+}
+
+impl CiIdCredentialsProvider {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    async fn credentials(&self) -> provider::Result {
+        let role_arn = match &self.role_arn {
+            Some(role_arn) => role_arn.clone(),
+            None => env::var("AWS_ROLE_ARN").map_err(|_| {
+                CredentialsError::invalid_configuration(
+                    "ci-id: role ARN is not set and AWS_ROLE_ARN is not defined",
+                )
+            })?,
+        };
+        let token = ci_id::detect_credentials_async(Some(&self.audience))
+            .await
+            .map_err(|e| match e {
+                // let a credentials provider chain continue to the next provider
+                CIIDError::EnvironmentNotDetected => CredentialsError::not_loaded(e),
+                e => CredentialsError::provider_error(e),
+            })?;
+        let session_name = self
+            .session_name
+            .clone()
+            .unwrap_or_else(default_session_name);
+        let output = self
+            .sts
+            .assume_role_with_web_identity()
+            .role_arn(role_arn)
+            .role_session_name(session_name)
+            .web_identity_token(token.expose())
+            .set_duration_seconds(self.duration.map(|duration| duration.as_secs() as i32))
+            .send()
+            .await
+            .map_err(CredentialsError::provider_error)?;
+        let credentials = output.credentials().ok_or_else(|| {
+            CredentialsError::unhandled("ci-id: AssumeRoleWithWebIdentity returned no credentials")
+        })?;
+        let expiry =
+            SystemTime::try_from(*credentials.expiration()).map_err(CredentialsError::unhandled)?;
+        Ok(Credentials::new(
+            credentials.access_key_id(),
+            credentials.secret_access_key(),
+            Some(credentials.session_token().into()),
+            Some(expiry),
+            PROVIDER_NAME,
+        ))
+    }
+}
+
+impl ProvideCredentials for CiIdCredentialsProvider {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::new(self.credentials())
+    }
+}
+
+/// Returns a role session name that identifies the session in CloudTrail
+fn default_session_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("ci-id-{}", now.as_secs())
+}
+
+impl Builder {
+    /// Sets the ARN of the role to assume. The default is `AWS_ROLE_ARN`.
+    pub fn role_arn(mut self, role_arn: impl Into<String>) -> Self {
+        self.role_arn = Some(role_arn.into());
+        self
+    }
+
+    /// Sets the role session name. The default is `ci-id-<unix time>`.
+    pub fn session_name(mut self, session_name: impl Into<String>) -> Self {
+        self.session_name = Some(session_name.into());
+        self
+    }
+
+    /// Sets the audience of the identity token, see [`DEFAULT_AUDIENCE`]
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Sets how long the credentials are valid. The default is the role's session duration
+    /// (one hour unless changed in the role).
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Sets the region of the STS endpoint. The default is `AWS_REGION`, `AWS_DEFAULT_REGION`
+    /// or us-east-1.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    /// Sets the STS client, e.g. to use a custom endpoint. [`region`](Self::region) is not used
+    /// with a custom client.
+    pub fn sts_client(mut self, client: aws_sdk_sts::Client) -> Self {
+        self.sts = Some(client);
+        self
+    }
+
+    pub fn build(self) -> CiIdCredentialsProvider {
+        let sts = self.sts.unwrap_or_else(|| {
+            let region = self
+                .region
+                .or_else(|| env::var("AWS_REGION").ok())
+                .or_else(|| env::var("AWS_DEFAULT_REGION").ok())
+                .unwrap_or_else(|| DEFAULT_REGION.into());
+            // AssumeRoleWithWebIdentity is not signed: no credentials are needed
+            let config = aws_sdk_sts::Config::builder()
+                .behavior_version(BehaviorVersion::latest())
+                .region(Region::new(region))
+                .build();
+            aws_sdk_sts::Client::from_conf(config)
+        });
+        CiIdCredentialsProvider {
+            role_arn: self.role_arn,
+            session_name: self.session_name,
+            audience: self.audience.unwrap_or_else(|| DEFAULT_AUDIENCE.into()),
+            duration: self.duration,
+            sts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults() {
+        let provider = CiIdCredentialsProvider::builder()
+            .role_arn("arn:aws:iam::123456789012:role/ci")
+            .region("eu-west-1")
+            .build();
+        assert_eq!(provider.audience, DEFAULT_AUDIENCE);
+        assert_eq!(provider.session_name, None);
+        assert_eq!(
+            provider.sts.config().region(),
+            Some(&Region::new("eu-west-1"))
+        );
+    }
+
+    #[test]
+    fn session_name() {
+        // role session names are 2-64 characters of [\w+=,.@-]
+        let name = default_session_name();
+        assert!(name.len() <= 64);
+        assert!(name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_+=,.@-".contains(c)));
+    }
+}
@@ -0,0 +1,125 @@
+//! Azure SDK credential for [ci-id](https://docs.rs/ci-id): the ambient OIDC identity token of
+//! the CI job is used as a federated client assertion (workload identity federation).
+//!
+//! ```no_run
+//! # async fn example() -> azure_core::Result<()> {
+//! use azure_core::credentials::TokenCredential;
+//! use ci_id_azure::CiIdCredential;
+//!
+//! let credential = CiIdCredential::from_env()?;
+//! let token = credential
+//!     .get_token(&["https://management.azure.com/.default"], None)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A new identity token is detected whenever Microsoft Entra ID is asked for an access token,
+//! so short-lived CI tokens are not reused.
+
+use async_trait::async_trait;
+use azure_core::{
+    credentials::{AccessToken, TokenCredential, TokenRequestOptions},
+    error::{Error, ErrorKind},
+};
+use azure_identity::{ClientAssertion, ClientAssertionCredential};
+use std::{env, sync::Arc};
+
+/// Audience of the identity token unless set with [`CiIdAssertion::new`]: the audience
+/// Microsoft Entra ID expects for federated credentials
+pub const DEFAULT_AUDIENCE: &str = "api://AzureADTokenExchange";
+
+/// Client assertion that returns the detected OIDC identity token
+#[derive(Debug, Clone)]
+pub struct CiIdAssertion {
+    audience: String,
+}
+
+impl CiIdAssertion {
+    /// Returns an assertion that detects identity tokens for `audience`
+    pub fn new(audience: impl Into<String>) -> Self {
+        CiIdAssertion {
+            audience: audience.into(),
+        }
+    }
+}
+
+impl Default for CiIdAssertion {
+    fn default() -> Self {
+        CiIdAssertion::new(DEFAULT_AUDIENCE)
+    }
+}
+
+#[async_trait]
+impl ClientAssertion for CiIdAssertion {
+    async fn secret(&self) -> azure_core::Result<String> {
+        let token = ci_id::detect_credentials_async(Some(&self.audience))
+            .await
+            .map_err(|e| Error::new(ErrorKind::Credential, e))?;
+        Ok(token.expose().into())
+    }
+}
+
+/// Credential that authenticates as the Microsoft Entra application `client_id` with the
+/// detected identity token
+#[derive(Debug)]
+pub struct CiIdCredential {
+    inner: Arc<ClientAssertionCredential<CiIdAssertion>>,
+}
+
+impl CiIdCredential {
+    /// Returns a credential for the application `client_id` in the tenant `tenant_id`, with
+    /// identity tokens for [`DEFAULT_AUDIENCE`]
+    pub fn new(
+        tenant_id: impl Into<String>,
+        client_id: impl Into<String>,
+    ) -> azure_core::Result<Arc<Self>> {
+        Self::with_assertion(tenant_id, client_id, CiIdAssertion::default())
+    }
+
+    /// Returns a credential with the tenant and client IDs from `AZURE_TENANT_ID` and
+    /// `AZURE_CLIENT_ID`
+    pub fn from_env() -> azure_core::Result<Arc<Self>> {
+        Self::new(required_var("AZURE_TENANT_ID")?, required_var("AZURE_CLIENT_ID")?)
+    }
+
+    /// Returns a credential that uses `assertion`, e.g. for a federated credential with a
+    /// custom audience
+    pub fn with_assertion(
+        tenant_id: impl Into<String>,
+        client_id: impl Into<String>,
+        assertion: CiIdAssertion,
+    ) -> azure_core::Result<Arc<Self>> {
+        let inner =
+            ClientAssertionCredential::new(tenant_id.into(), client_id.into(), assertion, None)?;
+        Ok(Arc::new(CiIdCredential { inner }))
+    }
+}
+
+#[async_trait]
+impl TokenCredential for CiIdCredential {
+    async fn get_token(
+        &self,
+        scopes: &[&str],
+        options: Option<TokenRequestOptions>,
+    ) -> azure_core::Result<AccessToken> {
+        self.inner.get_token(scopes, options).await
+    }
+}
+
+fn required_var(name: &str) -> azure_core::Result<String> {
+    env::var(name).map_err(|_| {
+        Error::with_message(ErrorKind::Credential, format!("ci-id: {} is not set", name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_assertion() {
+        assert_eq!(CiIdAssertion::default().audience, DEFAULT_AUDIENCE);
+        assert_eq!(CiIdAssertion::new("custom").audience, "custom");
+    }
+}
@@ -1,20 +1,96 @@
-use ci_id::{detect_credentials, CIIDError};
+use ci_id::{detect_credential_with_report, with_env, CIIDError, EnvReader, ProcessEnv, Provider};
 use clap::Parser;
-use std::process::exit;
+use std::{collections::HashMap, process::exit};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     /// Optional audience name
     audience: Option<String>,
+
+    /// Print the probed detectors and their outcomes to stderr
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Print the token, detector name and unverified claims as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Only use this detector, e.g. "gitlab" (same as CI_ID_PROVIDER)
+    #[arg(long, value_name = "ID")]
+    provider: Option<Provider>,
+
+    /// Skip this detector, e.g. "github" (same as CI_ID_DISABLE_<ID>=1). Can be repeated
+    #[arg(long, value_name = "ID")]
+    disable: Vec<Provider>,
+
+    /// Buildkite token lifetime in seconds (same as CI_ID_BUILDKITE_LIFETIME)
+    #[arg(long, value_name = "SECONDS")]
+    buildkite_lifetime: Option<u64>,
+
+    /// Include this optional claim in Buildkite tokens, e.g. "pipeline_id". Can be repeated
+    #[arg(long, value_name = "CLAIM")]
+    buildkite_claim: Vec<String>,
+}
+
+/// The process environment with the command line options layered over it
+struct CliEnv {
+    overrides: HashMap<String, String>,
+}
+
+impl EnvReader for CliEnv {
+    fn var(&self, name: &str) -> Option<String> {
+        match self.overrides.get(name) {
+            Some(value) => Some(value.clone()),
+            None => ProcessEnv.var(name),
+        }
+    }
+
+    fn vars(&self) -> Vec<(String, String)> {
+        let mut vars: HashMap<String, String> = ProcessEnv.vars().into_iter().collect();
+        vars.extend(self.overrides.clone());
+        vars.into_iter().collect()
+    }
 }
 
 fn main() {
     env_logger::init();
     let cli = Cli::parse();
 
-    match detect_credentials(cli.audience.as_deref()) {
-        Ok(token) => print!("{}", token),
+    // Command line options override the configuration variables
+    let mut vars = HashMap::new();
+    if let Some(provider) = cli.provider {
+        vars.insert("CI_ID_PROVIDER".into(), provider.id().into());
+    }
+    for provider in cli.disable {
+        let var_name = format!("CI_ID_DISABLE_{}", provider.id().to_uppercase());
+        vars.insert(var_name, "1".into());
+    }
+    if let Some(lifetime) = cli.buildkite_lifetime {
+        vars.insert("CI_ID_BUILDKITE_LIFETIME".into(), lifetime.to_string());
+    }
+    if !cli.buildkite_claim.is_empty() {
+        vars.insert(
+            "CI_ID_BUILDKITE_CLAIMS".into(),
+            cli.buildkite_claim.join(","),
+        );
+    }
+
+    let (result, report) = with_env(CliEnv { overrides: vars }, || {
+        detect_credential_with_report(cli.audience.as_deref())
+    });
+    if cli.verbose {
+        eprint!("{}", report);
+    }
+    match result {
+        Ok(credential) if cli.json => match serde_json::to_string_pretty(&credential) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(2);
+            }
+        },
+        Ok(credential) => print!("{}", credential.token.expose()),
         Err(CIIDError::EnvironmentNotDetected) => {
             eprintln!("No ambient OIDC tokens found");
             exit(1);
@@ -0,0 +1,52 @@
+#ifndef CI_ID_H
+#define CI_ID_H
+
+/* Generated with cbindgen from src/lib.rs: do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The token was detected and written to the buffer
+ */
+#define CI_ID_OK 0
+
+/**
+ * No supported environment was detected
+ */
+#define CI_ID_NOT_DETECTED 1
+
+/**
+ * An environment was detected but the token could not be provided
+ */
+#define CI_ID_ERROR 2
+
+/**
+ * The buffer is too small for the token
+ */
+#define CI_ID_BUFFER_TOO_SMALL 3
+
+/**
+ * The buffer is NULL or empty, or the audience is not valid UTF-8
+ */
+#define CI_ID_INVALID_ARGUMENT 4
+
+/**
+ * Detects the ambient OIDC identity token for `audience` (NULL for the default audience).
+ *
+ * On success the NUL terminated token is written to `buf` and `CI_ID_OK` is returned.
+ * Otherwise one of the other `CI_ID_*` codes is returned and `buf` contains a NUL terminated
+ * error message, truncated to fit the buffer. Tokens are typically a few kilobytes: a buffer of
+ * 16 KiB is plenty.
+ *
+ * # Safety
+ *
+ * `audience` must be NULL or point to a NUL terminated string, and `buf` must point to at
+ * least `buf_len` writable bytes.
+ */
+int32_t ci_id_detect(const char *audience, char *buf, size_t buf_len);
+
+#endif  /* CI_ID_H */
@@ -0,0 +1,169 @@
+//! C API for [ci-id](https://docs.rs/ci-id): ambient OIDC credentials detection on CI systems.
+//!
+//! The crate builds a shared and a static library (`libci_id_ffi`) for tooling that is not
+//! written in Rust: the C header is in `include/ci_id.h`.
+//!
+//! ```c
+//! #include <stdio.h>
+//! #include "ci_id.h"
+//!
+//! int main(void) {
+//!     char buf[16384];
+//!     int32_t result = ci_id_detect("sigstore", buf, sizeof(buf));
+//!     if (result != CI_ID_OK) {
+//!         fprintf(stderr, "Error: %s\n", buf);
+//!         return result;
+//!     }
+//!     printf("%s", buf);
+//!     return 0;
+//! }
+//! ```
+
+use ci_id::{detect_credentials, CIIDError};
+use std::{
+    ffi::{c_char, CStr},
+    panic::catch_unwind,
+};
+
+/// The token was detected and written to the buffer
+pub const CI_ID_OK: i32 = 0;
+/// No supported environment was detected
+pub const CI_ID_NOT_DETECTED: i32 = 1;
+/// An environment was detected but the token could not be provided
+pub const CI_ID_ERROR: i32 = 2;
+/// The buffer is too small for the token
+pub const CI_ID_BUFFER_TOO_SMALL: i32 = 3;
+/// The buffer is NULL or empty, or the audience is not valid UTF-8
+pub const CI_ID_INVALID_ARGUMENT: i32 = 4;
+
+/// Detects the ambient OIDC identity token for `audience` (NULL for the default audience).
+///
+/// On success the NUL terminated token is written to `buf` and `CI_ID_OK` is returned.
+/// Otherwise one of the other `CI_ID_*` codes is returned and `buf` contains a NUL terminated
+/// error message, truncated to fit the buffer. Tokens are typically a few kilobytes: a buffer of
+/// 16 KiB is plenty.
+///
+/// # Safety
+///
+/// `audience` must be NULL or point to a NUL terminated string, and `buf` must point to at
+/// least `buf_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ci_id_detect(
+    audience: *const c_char,
+    buf: *mut c_char,
+    buf_len: usize,
+) -> i32 {
+    if buf.is_null() || buf_len == 0 {
+        return CI_ID_INVALID_ARGUMENT;
+    }
+    let buf = std::slice::from_raw_parts_mut(buf.cast::<u8>(), buf_len);
+    let audience = match audience.is_null() {
+        true => None,
+        false => match CStr::from_ptr(audience).to_str() {
+            Ok(audience) => Some(audience),
+            Err(_) => {
+                write_message(buf, "audience is not valid UTF-8");
+                return CI_ID_INVALID_ARGUMENT;
+            }
+        },
+    };
+
+    // Panics must not unwind into the caller
+    match catch_unwind(|| detect_credentials(audience)) {
+        Ok(Ok(token)) => {
+            let token = token.expose().as_bytes();
+            if token.len() >= buf.len() {
+                write_message(buf, "buffer is too small for the token");
+                return CI_ID_BUFFER_TOO_SMALL;
+            }
+            buf[..token.len()].copy_from_slice(token);
+            buf[token.len()] = 0;
+            CI_ID_OK
+        }
+        Ok(Err(CIIDError::EnvironmentNotDetected)) => {
+            write_message(buf, "No ambient OIDC tokens found");
+            CI_ID_NOT_DETECTED
+        }
+        Ok(Err(e)) => {
+            write_message(buf, &e.to_string());
+            CI_ID_ERROR
+        }
+        Err(_) => {
+            write_message(buf, "credential detection panicked");
+            CI_ID_ERROR
+        }
+    }
+}
+
+/// Writes the NUL terminated message into `buf`, truncated to fit
+fn write_message(buf: &mut [u8], message: &str) {
+    let len = message.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&message.as_bytes()[..len]);
+    buf[len] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ci_id::with_env;
+    use std::collections::HashMap;
+
+    const TOKEN: &str = "header.payload.signature";
+
+    fn detect(env: &[(&str, &str)], audience: Option<&CStr>, buf: &mut [u8]) -> i32 {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let audience = audience.map_or(std::ptr::null(), CStr::as_ptr);
+        with_env(env, || unsafe {
+            ci_id_detect(audience, buf.as_mut_ptr().cast(), buf.len())
+        })
+    }
+
+    fn message(buf: &[u8]) -> &str {
+        CStr::from_bytes_until_nul(buf).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn detect_token() {
+        let mut buf = [0xff; 64];
+        assert_eq!(detect(&[("CI_ID_TOKEN", TOKEN)], None, &mut buf), CI_ID_OK);
+        assert_eq!(message(&buf), TOKEN);
+
+        let env = [("CI_ID_TOKEN_MY_AUD", TOKEN)];
+        assert_eq!(detect(&env, Some(c"my-aud"), &mut buf), CI_ID_OK);
+        assert_eq!(message(&buf), TOKEN);
+    }
+
+    #[test]
+    fn detect_failures() {
+        let mut buf = [0xff; 64];
+        assert_eq!(detect(&[], None, &mut buf), CI_ID_NOT_DETECTED);
+        assert_eq!(message(&buf), "No ambient OIDC tokens found");
+
+        assert_eq!(
+            detect(&[("CI_ID_TOKEN", "not a token")], None, &mut buf),
+            CI_ID_ERROR
+        );
+        assert!(!message(&buf).is_empty());
+
+        // the token and its NUL terminator do not fit
+        let mut small = [0xff; TOKEN.len()];
+        assert_eq!(
+            detect(&[("CI_ID_TOKEN", TOKEN)], None, &mut small),
+            CI_ID_BUFFER_TOO_SMALL
+        );
+        assert_eq!(
+            message(&small),
+            &"buffer is too small for the token"[..TOKEN.len() - 1]
+        );
+
+        assert_eq!(detect(&[], Some(c"\xff"), &mut buf), CI_ID_INVALID_ARGUMENT);
+        assert_eq!(
+            unsafe { ci_id_detect(std::ptr::null(), std::ptr::null_mut(), 0) },
+            CI_ID_INVALID_ARGUMENT
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Node.js bindings for [ci-id](https://docs.rs/ci-id), built with napi-rs as the `ci-id` npm
+//! package.
+
+use napi::{bindgen_prelude::AsyncTask, Env, Error, Result, Task};
+use napi_derive::napi;
+use std::time::UNIX_EPOCH;
+
+/// Detected OIDC identity token along with the detector name and the unverified token claims
+#[napi(object)]
+pub struct DetectedCredential {
+    /// The OIDC identity token
+    pub token: String,
+    /// Name of the environment (detector) that provided the token
+    pub provider: String,
+    /// Unverified token claims, null if the payload could not be decoded
+    pub claims: serde_json::Value,
+    /// Unverified `iss` claim
+    pub issuer: Option<String>,
+    /// Unverified `exp` claim, in seconds since the Unix epoch
+    pub expiry: Option<f64>,
+}
+
+impl From<ci_id::DetectedCredential> for DetectedCredential {
+    fn from(credential: ci_id::DetectedCredential) -> Self {
+        DetectedCredential {
+            token: credential.token.expose().into(),
+            provider: credential.provider,
+            claims: credential.claims,
+            issuer: credential.issuer,
+            expiry: credential
+                .expiry
+                .and_then(|expiry| expiry.duration_since(UNIX_EPOCH).ok())
+                .map(|expiry| expiry.as_secs_f64()),
+        }
+    }
+}
+
+/// Returns the detected credential, None if no environment was detected
+fn detect(audience: Option<&str>) -> Result<Option<ci_id::DetectedCredential>> {
+    match ci_id::detect_credential(audience) {
+        Ok(credential) => Ok(Some(credential)),
+        Err(ci_id::CIIDError::EnvironmentNotDetected) => Ok(None),
+        Err(e) => Err(Error::from_reason(e.to_string())),
+    }
+}
+
+/// Returns the detected OIDC identity token for `audience`, null if no supported environment
+/// was detected. Throws if the environment was detected but the token could not be provided.
+#[napi]
+pub fn detect_credentials(audience: Option<String>) -> Result<Option<String>> {
+    let credential = detect(audience.as_deref())?;
+    Ok(credential.map(|credential| credential.token.expose().into()))
+}
+
+/// Returns the detected OIDC identity token along with the detector name and the unverified
+/// token claims, null if no supported environment was detected.
+#[napi]
+pub fn detect_credential(audience: Option<String>) -> Result<Option<DetectedCredential>> {
+    Ok(detect(audience.as_deref())?.map(DetectedCredential::from))
+}
+
+/// Detection on the libuv thread pool, see [`detect_credential_async`]
+pub struct DetectTask {
+    audience: Option<String>,
+}
+
+impl Task for DetectTask {
+    type Output = Option<ci_id::DetectedCredential>;
+    type JsValue = Option<DetectedCredential>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        detect(self.audience.as_deref())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(output.map(DetectedCredential::from))
+    }
+}
+
+/// Async version of `detectCredential`: detection runs on the libuv thread pool so that token
+/// requests do not block the event loop.
+#[napi(ts_return_type = "Promise<DetectedCredential | null>")]
+pub fn detect_credential_async(audience: Option<String>) -> AsyncTask<DetectTask> {
+    AsyncTask::new(DetectTask { audience })
+}
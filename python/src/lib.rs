@@ -0,0 +1,113 @@
+//! Python bindings for [ci-id](https://docs.rs/ci-id), built with maturin as the `ci_id`
+//! Python module.
+
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+use std::time::UNIX_EPOCH;
+
+create_exception!(
+    ci_id,
+    CIIDError,
+    PyException,
+    "An environment was detected but the token could not be provided"
+);
+create_exception!(
+    ci_id,
+    EnvironmentNotDetected,
+    CIIDError,
+    "No supported environment was detected"
+);
+
+fn to_py_err(e: ci_id::CIIDError) -> PyErr {
+    match e {
+        ci_id::CIIDError::EnvironmentNotDetected => EnvironmentNotDetected::new_err(e.to_string()),
+        e => CIIDError::new_err(e.to_string()),
+    }
+}
+
+/// Detected OIDC identity token along with the detector name and the unverified token claims
+#[pyclass(module = "ci_id", frozen)]
+struct DetectedCredential {
+    /// The OIDC identity token
+    #[pyo3(get)]
+    token: String,
+    /// Name of the environment (detector) that provided the token
+    #[pyo3(get)]
+    provider: String,
+    /// Unverified `iss` claim
+    #[pyo3(get)]
+    issuer: Option<String>,
+    /// Unverified `exp` claim, in seconds since the Unix epoch
+    #[pyo3(get)]
+    expiry: Option<f64>,
+    claims: String,
+}
+
+#[pymethods]
+impl DetectedCredential {
+    /// Unverified token claims, None if the payload could not be decoded
+    #[getter]
+    fn claims(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let json = py.import_bound("json")?;
+        Ok(json.call_method1("loads", (&self.claims,))?.unbind())
+    }
+
+    fn __repr__(&self) -> String {
+        // The token is a bearer credential: keep it out of logs
+        format!(
+            "DetectedCredential(provider={:?}, issuer={:?}, token=<redacted>)",
+            self.provider, self.issuer
+        )
+    }
+}
+
+impl From<ci_id::DetectedCredential> for DetectedCredential {
+    fn from(credential: ci_id::DetectedCredential) -> Self {
+        DetectedCredential {
+            token: credential.token.expose().into(),
+            provider: credential.provider,
+            issuer: credential.issuer,
+            expiry: credential
+                .expiry
+                .and_then(|expiry| expiry.duration_since(UNIX_EPOCH).ok())
+                .map(|expiry| expiry.as_secs_f64()),
+            claims: credential.claims.to_string(),
+        }
+    }
+}
+
+/// Returns the detected OIDC identity token for `audience`.
+///
+/// Raises EnvironmentNotDetected if no supported environment is found and CIIDError if the
+/// token could not be provided.
+#[pyfunction]
+#[pyo3(signature = (audience=None))]
+fn detect_credentials(py: Python<'_>, audience: Option<&str>) -> PyResult<String> {
+    py.allow_threads(|| ci_id::detect_credentials(audience))
+        .map(|token| token.expose().into())
+        .map_err(to_py_err)
+}
+
+/// Returns the detected OIDC identity token along with the detector name and the unverified
+/// token claims.
+#[pyfunction]
+#[pyo3(signature = (audience=None))]
+fn detect_credential(py: Python<'_>, audience: Option<&str>) -> PyResult<DetectedCredential> {
+    py.allow_threads(|| ci_id::detect_credential(audience))
+        .map(DetectedCredential::from)
+        .map_err(to_py_err)
+}
+
+#[pymodule]
+#[pyo3(name = "ci_id")]
+fn ci_id_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add("CIIDError", m.py().get_type_bound::<CIIDError>())?;
+    m.add(
+        "EnvironmentNotDetected",
+        m.py().get_type_bound::<EnvironmentNotDetected>(),
+    )?;
+    m.add_class::<DetectedCredential>()?;
+    m.add_function(wrap_pyfunction!(detect_credentials, m)?)?;
+    m.add_function(wrap_pyfunction!(detect_credential, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,320 @@
+//! Async API, enabled with the `async` feature
+
+use super::*;
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Future returned by [`AsyncDetector::detect`]
+pub type DetectFuture<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+
+/// An application provided asynchronous token source.
+///
+/// Custom detectors are probed with [`detect_credentials_async_with`] before the built-in
+/// detectors. Like the built-in detectors, `detect` should return
+/// [`CIIDError::EnvironmentNotDetected`] if the token source is not present so that probing can
+/// continue.
+///
+/// ```
+/// use ci_id::{AsyncDetector, CIIDError, DetectFuture};
+///
+/// struct MetadataService;
+///
+/// impl AsyncDetector for MetadataService {
+///     fn name(&self) -> &str {
+///         "Metadata service"
+///     }
+///
+///     fn detect<'a>(&'a self, audience: Option<&'a str>) -> DetectFuture<'a> {
+///         Box::pin(async move {
+///             // fetch the token from the service here
+///             Err(CIIDError::EnvironmentNotDetected)
+///         })
+///     }
+/// }
+/// ```
+pub trait AsyncDetector: Send + Sync {
+    /// Name used in log and error messages
+    fn name(&self) -> &str;
+
+    /// Returns an identity token for the audience
+    fn detect<'a>(&'a self, audience: Option<&'a str>) -> DetectFuture<'a>;
+}
+
+/// Returns detected OIDC identity token: async version of [`detect_credentials`].
+///
+/// This must be called within a tokio runtime. Detectors that request the token over HTTP use the
+/// non-blocking reqwest client, or without the built-in client the transport set with
+/// [`with_transport`] on the blocking thread pool. Detectors that read local files or sockets, or
+/// run a helper program, run as in the blocking API on the tokio blocking thread pool, so they do
+/// not block the runtime threads.
+///
+/// ```no_run
+/// # async fn example() {
+/// match ci_id::detect_credentials_async(Some("my-audience")).await {
+///     Ok(token) => println!("{}", token.expose()),
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// # }
+/// ```
+pub async fn detect_credentials_async(audience: Option<&str>) -> Result<SecretToken> {
+    detect_credentials_async_with(&[], audience).await
+}
+
+/// Returns detected OIDC identity token, probing the custom `detectors` in order before the
+/// built-in detectors.
+pub async fn detect_credentials_async_with(
+    detectors: &[&dyn AsyncDetector],
+    audience: Option<&str>,
+) -> Result<SecretToken> {
+    detect_credential_async_with(detectors, audience)
+        .await
+        .map(|credential| credential.token)
+}
+
+/// Returns detected OIDC identity token along with the detector name and the unverified token
+/// claims: async version of [`detect_credential`].
+pub async fn detect_credential_async(audience: Option<&str>) -> Result<DetectedCredential> {
+    detect_credential_async_with(&[], audience).await
+}
+
+/// Async version of [`detect_credential`], probing the custom `detectors` in order before the
+/// built-in detectors.
+pub async fn detect_credential_async_with(
+    detectors: &[&dyn AsyncDetector],
+    audience: Option<&str>,
+) -> Result<DetectedCredential> {
+    let config = Arc::new(load_config()?);
+    let context = RequestContext {
+        config: config.clone(),
+        ..Default::default()
+    };
+    let audience = audience.or(config.audience.as_deref());
+    let providers = default_providers(&config)?;
+    let plugins = default_plugins();
+    let configured = config.detectors.iter().map(|d| d as &dyn AsyncDetector);
+    let plugged = plugins.iter().map(|d| d as &dyn AsyncDetector);
+    let builtin = providers
+        .iter()
+        .map(|provider| provider as &dyn AsyncDetector);
+    let mut failures = vec![];
+    let all = detectors
+        .iter()
+        .copied()
+        .chain(configured)
+        .chain(plugged)
+        .chain(builtin);
+    for detector in all {
+        let probe = async {
+            instrument::probe_started(detector.name());
+            let future = InContext {
+                context: context.clone(),
+                future: detector.detect(audience),
+            };
+            let result = detection_result(detector.name(), future.await);
+            instrument::probe_outcome(detector.name(), &ProbeOutcome::of(&result));
+            result
+        };
+        match instrument::probe_future(detector.name(), probe).await {
+            Some(Ok(credential)) => return Ok(credential),
+            Some(Err(e)) => {
+                log::debug!("{}: {}", detector.name(), e);
+                failures.push((detector.name().to_string(), e));
+            }
+            None => {}
+        }
+    }
+
+    Err(CIIDError::from_failures(failures))
+}
+
+/// Detector future that sets the request context on the thread that polls it: the future may be
+/// polled on a different runtime thread each time
+struct InContext<'a> {
+    context: RequestContext,
+    future: DetectFuture<'a>,
+}
+
+impl Future for InContext<'_> {
+    type Output = Result<String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let previous = REQUEST_CONTEXT.replace(std::mem::take(&mut this.context));
+        let poll = this.future.as_mut().poll(cx);
+        this.context = REQUEST_CONTEXT.replace(previous);
+        poll
+    }
+}
+
+/// Runs blocking detection `f` on the tokio blocking thread pool with the detection state of the
+/// calling thread
+pub(crate) async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let state = ThreadState::capture();
+    tokio::task::spawn_blocking(move || state.scoped(f))
+        .await
+        .map_err(|e| CIIDError::EnvironmentError(format!("Detection task failed: {}", e)))?
+}
+
+/// Built-in detectors that request the token over HTTP use the async client, the others run as
+/// in the blocking API on the blocking thread pool
+impl AsyncDetector for Provider {
+    fn name(&self) -> &str {
+        Provider::name(*self)
+    }
+
+    fn detect<'a>(&'a self, audience: Option<&'a str>) -> DetectFuture<'a> {
+        Box::pin(async move {
+            match self {
+                #[cfg(feature = "forgejo")]
+                Provider::Environment(CIEnvironment::ForgejoActions) => {
+                    detect_forgejo_async(audience).await
+                }
+                #[cfg(feature = "gitea")]
+                Provider::Environment(CIEnvironment::GiteaActions) => {
+                    detect_gitea_async(audience).await
+                }
+                #[cfg(feature = "github")]
+                Provider::Environment(CIEnvironment::GitHubActions) => {
+                    detect_github_async(audience).await
+                }
+                #[cfg(feature = "buildkite")]
+                Provider::Environment(CIEnvironment::Buildkite) => {
+                    detect_buildkite_async(audience).await
+                }
+                #[cfg(feature = "codefresh")]
+                Provider::Environment(CIEnvironment::Codefresh) => {
+                    detect_codefresh_async(audience).await
+                }
+                provider => {
+                    let provider = *provider;
+                    let audience = audience.map(String::from);
+                    blocking(move || Detector::detect(&provider, audience.as_deref())).await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+impl TokenRequest {
+    /// Sends the request with the transport set with [`with_transport`] on the blocking thread
+    /// pool: there is no built-in client without the `reqwest` feature
+    #[cfg(not(feature = "reqwest"))]
+    async fn send_async<T: DeserializeOwned + Send + 'static>(self) -> Result<T> {
+        blocking(move || self.send()).await
+    }
+
+    /// Sends the request with the async client and parses the JSON response
+    #[cfg(feature = "reqwest")]
+    async fn send_async<T: DeserializeOwned>(self) -> Result<T> {
+        let request = self.http_request()?;
+        let client = async_http_client(self.name)?;
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .map_err(|e| request_error(self.name, e))?;
+        log::debug!("{}: Requesting token", self.name);
+        let mut retries = 0;
+        loop {
+            let mut builder = client.request(method.clone(), &request.url);
+            for (name, value) in &request.headers {
+                builder = builder.header(*name, value);
+            }
+            if let Some(body) = &request.body {
+                builder = builder.body(body.clone());
+            }
+            let start = Instant::now();
+            let response = builder.send().await;
+            let status = response
+                .as_ref()
+                .ok()
+                .map(|response| response.status().as_u16());
+            instrument::http_request(self.name, request.method, status, start.elapsed());
+            let response = response.map_err(|e| request_error(self.name, e))?;
+            let status = response.status().as_u16();
+            let headers = response_headers(response.headers());
+            let body = response
+                .bytes()
+                .await
+                .map_err(|e| request_error(self.name, e))?;
+            let response = HttpResponse {
+                status,
+                headers,
+                body: body.into(),
+            };
+            match self.retry_delay(&response, retries) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return self.parse_response(response),
+            }
+            retries += 1;
+        }
+    }
+}
+
+/// Returns an async HTTP client for token requests. `name` is used as the error message prefix.
+///
+/// Unlike the blocking client, the async client is not reused: its connections belong to the
+/// runtime it was used in.
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn async_http_client(name: &str) -> Result<reqwest::Client> {
+    let settings = REQUEST_CONTEXT.with_borrow(ClientSettings::new)?;
+    let mut builder = reqwest::Client::builder()
+        .timeout(settings.timeouts.request)
+        .connect_timeout(settings.timeouts.connect)
+        .no_proxy();
+    for proxy in &settings.proxies {
+        builder = builder.proxy(proxy.build(name)?);
+    }
+    #[cfg(any(feature = "rustls-tls", feature = "native-tls"))]
+    {
+        for cert in root_certificates(name, &settings)? {
+            builder = builder.add_root_certificate(cert);
+        }
+        builder = builder.tls_built_in_root_certs(!settings.no_built_in_roots);
+    }
+    builder.build().map_err(|e| client_error(name, e))
+}
+
+#[cfg(feature = "github")]
+async fn detect_github_async(audience: Option<&str>) -> Result<String> {
+    let response: GitHubTokenResponse = github_request(audience)?.send_async().await?;
+    check_github_issuer(response.value)
+}
+
+#[cfg(feature = "gitea")]
+async fn detect_gitea_async(audience: Option<&str>) -> Result<String> {
+    let response: GitHubTokenResponse = gitea_request(audience)?.send_async().await?;
+    Ok(response.value)
+}
+
+#[cfg(feature = "forgejo")]
+async fn detect_forgejo_async(audience: Option<&str>) -> Result<String> {
+    let response: GitHubTokenResponse = forgejo_request(audience)?.send_async().await?;
+    Ok(response.value)
+}
+
+#[cfg(feature = "codefresh")]
+async fn detect_codefresh_async(audience: Option<&str>) -> Result<String> {
+    let response: CodefreshTokenResponse = codefresh_request(audience)?.send_async().await?;
+    Ok(response.id_token)
+}
+
+#[cfg(feature = "buildkite")]
+async fn detect_buildkite_async(audience: Option<&str>) -> Result<String> {
+    if env_var("BUILDKITE").is_err() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    // Only the agent API fallback makes a HTTP request
+    if program_available("buildkite-agent") {
+        let audience = audience.map(String::from);
+        return blocking(move || detect_buildkite(audience.as_deref())).await;
+    }
+    log::debug!("Buildkite: buildkite-agent not found, using agent API");
+    let response: BuildkiteTokenResponse = buildkite_api_request(audience)?.send_async().await?;
+    Ok(response.token)
+}
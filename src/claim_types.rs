@@ -0,0 +1,114 @@
+//! Typed token claims for the most common providers. All claims are unverified, see
+//! [`crate::claims`].
+
+use serde::Deserialize;
+
+/// Claims in GitHub Actions tokens
+///
+/// See <https://docs.github.com/en/actions/security-for-github-actions/security-hardening-your-deployments/about-security-hardening-with-openid-connect#understanding-the-oidc-token>
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[non_exhaustive]
+pub struct GitHubClaims {
+    pub iss: String,
+    pub sub: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub repository: String,
+    pub repository_id: String,
+    pub repository_owner: String,
+    pub repository_owner_id: String,
+    pub repository_visibility: Option<String>,
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub ref_type: String,
+    pub sha: String,
+    pub workflow: String,
+    pub workflow_ref: String,
+    pub job_workflow_ref: String,
+    pub event_name: String,
+    pub actor: String,
+    pub actor_id: String,
+    pub run_id: String,
+    pub run_number: String,
+    pub run_attempt: String,
+    pub runner_environment: Option<String>,
+    pub environment: Option<String>,
+}
+
+/// Claims in GitLab CI/CD ID tokens
+///
+/// See <https://docs.gitlab.com/ee/ci/secrets/id_token_authentication.html#token-payload>
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[non_exhaustive]
+pub struct GitLabClaims {
+    pub iss: String,
+    pub sub: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub namespace_id: String,
+    pub namespace_path: String,
+    pub project_id: String,
+    pub project_path: String,
+    pub user_id: String,
+    pub user_login: String,
+    pub user_email: Option<String>,
+    pub pipeline_id: String,
+    pub pipeline_source: String,
+    pub job_id: String,
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub ref_type: String,
+    pub ref_path: Option<String>,
+    pub ref_protected: String,
+    pub environment: Option<String>,
+    pub environment_protected: Option<String>,
+    pub runner_id: Option<u64>,
+    pub runner_environment: Option<String>,
+    pub sha: Option<String>,
+    pub ci_config_ref_uri: Option<String>,
+    pub ci_config_sha: Option<String>,
+    pub project_visibility: Option<String>,
+}
+
+/// Claims in CircleCI OIDC tokens
+///
+/// See <https://circleci.com/docs/openid-connect-tokens/#format-of-the-openid-connect-id-token>
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[non_exhaustive]
+pub struct CircleCIClaims {
+    pub iss: String,
+    pub sub: String,
+    pub exp: u64,
+    pub iat: u64,
+    #[serde(rename = "oidc.circleci.com/project-id")]
+    pub project_id: String,
+    #[serde(rename = "oidc.circleci.com/context-ids", default)]
+    pub context_ids: Vec<String>,
+    #[serde(rename = "oidc.circleci.com/vcs-origin")]
+    pub vcs_origin: Option<String>,
+    #[serde(rename = "oidc.circleci.com/vcs-ref")]
+    pub vcs_ref: Option<String>,
+    #[serde(rename = "oidc.circleci.com/ssh-rerun")]
+    pub ssh_rerun: Option<bool>,
+}
+
+/// Claims in Buildkite OIDC tokens
+///
+/// See <https://buildkite.com/docs/agent/v3/cli-oidc#claims>
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[non_exhaustive]
+pub struct BuildkiteClaims {
+    pub iss: String,
+    pub sub: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub organization_slug: String,
+    pub pipeline_slug: String,
+    pub build_number: u64,
+    pub build_branch: String,
+    pub build_tag: Option<String>,
+    pub build_commit: String,
+    pub step_key: Option<String>,
+    pub job_id: String,
+    pub agent_id: String,
+}
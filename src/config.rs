@@ -0,0 +1,291 @@
+//! Optional configuration file (`ci-id.toml`)
+//!
+//! The file is only read from the path in `CI_ID_CONFIG`: the current directory is usually the
+//! checked out repository, so a file there is not trusted. Only the subset of TOML needed for the
+//! configuration is supported: `key = value` pairs with string, integer, boolean and single line
+//! array values, and `[[detector]]` tables.
+
+use super::*;
+
+/// Settings from the configuration file
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// Built-in detectors to probe, in order
+    pub(crate) providers: Option<Vec<Provider>>,
+    /// Built-in detectors to skip
+    pub(crate) disable: Vec<Provider>,
+    /// Audience used when the caller does not request one
+    pub(crate) audience: Option<String>,
+    /// HTTP request timeout
+    pub(crate) timeout: Option<Duration>,
+    /// HTTP connect timeout
+    pub(crate) connect_timeout: Option<Duration>,
+    /// Helper program timeout
+    pub(crate) command_timeout: Option<Duration>,
+    /// PEM file with additional trusted root certificates
+    pub(crate) ca_bundle: Option<String>,
+    /// Custom detectors, probed before the built-in detectors
+    pub(crate) detectors: Vec<ConfigDetector>,
+}
+
+/// Returns the configuration: the default configuration if `CI_ID_CONFIG` is not set.
+///
+/// Detection calls read the configuration once, token requests get it from the request context.
+pub(crate) fn load_config() -> Result<Config> {
+    let Ok(path) = env_var("CI_ID_CONFIG") else {
+        return Ok(Config::default());
+    };
+    let text = fs::read_to_string(&path).map_err(|e| {
+        CIIDError::io_error(format!("Failed to read configuration {}: {}", path, e), e)
+    })?;
+    log::debug!("Reading configuration from {}", path);
+    parse_config(&text)
+        .map_err(|e| CIIDError::EnvironmentError(format!("Invalid configuration {}: {}", path, e)))
+}
+
+/// A custom detector defined in a `[[detector]]` table
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConfigDetector {
+    name: String,
+    source: TokenSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenSource {
+    /// Token is read from the environment variable
+    Env(String),
+    /// Token is read from the file
+    File(String),
+    /// Token is the output of the shell command
+    Command(String),
+}
+
+impl Detector for ConfigDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect(&self, audience: Option<&str>) -> Result<String> {
+        match &self.source {
+            TokenSource::Env(var_name) => match env_var(var_name) {
+                Ok(token) => Ok(token.trim().into()),
+                Err(_) => Err(CIIDError::EnvironmentNotDetected),
+            },
+            TokenSource::File(path) => match fs::read_to_string(path) {
+                Ok(token) => Ok(token.trim().into()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Err(CIIDError::EnvironmentNotDetected)
+                }
+                Err(e) => Err(CIIDError::io_error(
+                    format!("{}: Failed to read token from {}: {}", self.name, path, e),
+                    e,
+                )),
+            },
+            TokenSource::Command(command) => {
+                let call = CommandCall::shell(command).env("CI_ID_AUDIENCE", audience);
+                Ok(run_command(&self.name, call)?.trim().into())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncDetector for ConfigDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect<'a>(&'a self, audience: Option<&'a str>) -> DetectFuture<'a> {
+        let detector = self.clone();
+        let audience = audience.map(String::from);
+        Box::pin(asynchronous::blocking(move || {
+            Detector::detect(&detector, audience.as_deref())
+        }))
+    }
+}
+
+/// A value in the supported TOML subset
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+type Table = Vec<(String, Value)>;
+
+fn parse_config(text: &str) -> std::result::Result<Config, String> {
+    let (root, detector_tables) = parse_tables(text)?;
+
+    let mut config = Config::default();
+    for (key, value) in root {
+        match key.as_str() {
+            "providers" => config.providers = Some(parse_providers(&key, value)?),
+            "disable" => config.disable = parse_providers(&key, value)?,
+            "audience" => config.audience = Some(expect_string(&key, value)?),
+            "timeout" => config.timeout = Some(parse_timeout(&key, value)?),
+            "connect_timeout" => config.connect_timeout = Some(parse_timeout(&key, value)?),
+            "command_timeout" => config.command_timeout = Some(parse_timeout(&key, value)?),
+            "ca_bundle" => config.ca_bundle = Some(expect_string(&key, value)?),
+            _ => return Err(format!("Unknown setting '{}'", key)),
+        }
+    }
+    for table in detector_tables {
+        config.detectors.push(parse_detector(table)?);
+    }
+    Ok(config)
+}
+
+fn parse_detector(table: Table) -> std::result::Result<ConfigDetector, String> {
+    let mut name = None;
+    let mut sources = vec![];
+    for (key, value) in table {
+        match key.as_str() {
+            "name" => name = Some(expect_string(&key, value)?),
+            "env" => sources.push(TokenSource::Env(expect_string(&key, value)?)),
+            "file" => sources.push(TokenSource::File(expect_string(&key, value)?)),
+            "command" => sources.push(TokenSource::Command(expect_string(&key, value)?)),
+            _ => return Err(format!("Unknown detector setting '{}'", key)),
+        }
+    }
+    let name = name.ok_or("detector must have a name")?;
+    match <[TokenSource; 1]>::try_from(sources) {
+        Ok([source]) => Ok(ConfigDetector { name, source }),
+        Err(_) => Err(format!(
+            "detector '{}' must have exactly one of 'env', 'file' or 'command'",
+            name
+        )),
+    }
+}
+
+fn parse_timeout(key: &str, value: Value) -> std::result::Result<Duration, String> {
+    match value {
+        Value::Integer(secs) if secs > 0 => Ok(Duration::from_secs(secs as u64)),
+        _ => Err(format!("{} must be a number of seconds", key)),
+    }
+}
+
+fn expect_string(key: &str, value: Value) -> std::result::Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(format!("{} must be a string", key)),
+    }
+}
+
+fn parse_providers(key: &str, value: Value) -> std::result::Result<Vec<Provider>, String> {
+    let Value::Array(values) = value else {
+        return Err(format!("{} must be an array of detector ids", key));
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let id = expect_string(key, value)?;
+            id.parse::<Provider>()
+                .map_err(|_| format!("Unknown provider '{}' in {}", id, key))
+        })
+        .collect()
+}
+
+/// Returns the root table and the `[[detector]]` tables
+fn parse_tables(text: &str) -> std::result::Result<(Table, Vec<Table>), String> {
+    let mut root = vec![];
+    let mut detectors: Vec<Table> = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: &str| format!("line {}: {}", index + 1, message);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let (name, rest) = header
+                .strip_prefix('[')
+                .and_then(|header| header.split_once("]]"))
+                .ok_or_else(|| error("only [[detector]] tables are supported"))?;
+            if name.trim() != "detector" || !is_line_end(rest) {
+                return Err(error("only [[detector]] tables are supported"));
+            }
+            detectors.push(vec![]);
+            continue;
+        }
+
+        let (key, rest) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected 'key = value'"))?;
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(error("invalid key"));
+        }
+        let (value, rest) = parse_value(rest.trim_start()).ok_or_else(|| error("invalid value"))?;
+        if !is_line_end(rest) {
+            return Err(error("unexpected characters after value"));
+        }
+        let table = detectors.last_mut().unwrap_or(&mut root);
+        if table.iter().any(|(existing, _)| existing == key) {
+            return Err(error("duplicate key"));
+        }
+        table.push((key.into(), value));
+    }
+    Ok((root, detectors))
+}
+
+/// Returns true if only whitespace or a comment follows
+fn is_line_end(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// Parses a value from the start of `input`, returns the value and the remaining input
+fn parse_value(input: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = input.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::String(value), &rest[i + 1..])),
+                '\\' => value.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    c @ ('"' | '\\') => c,
+                    _ => return None,
+                }),
+                c => value.push(c),
+            }
+        }
+        None
+    } else if let Some(rest) = input.strip_prefix('\'') {
+        let (value, rest) = rest.split_once('\'')?;
+        Some((Value::String(value.into()), rest))
+    } else if let Some(mut rest) = input.strip_prefix('[') {
+        let mut values = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Some((Value::Array(values), rest));
+            }
+            let (value, remaining) = parse_value(rest)?;
+            values.push(value);
+            rest = remaining.trim_start();
+            match rest.strip_prefix(',') {
+                Some(remaining) => rest = remaining,
+                None if rest.starts_with(']') => {}
+                None => return None,
+            }
+        }
+    } else if let Some(rest) = input.strip_prefix("true") {
+        Some((Value::Boolean(true), rest))
+    } else if let Some(rest) = input.strip_prefix("false") {
+        Some((Value::Boolean(false), rest))
+    } else {
+        let end = input
+            .find(|c: char| !(c.is_ascii_digit() || c == '_' || c == '+' || c == '-'))
+            .unwrap_or(input.len());
+        let value = input[..end].replace('_', "").parse().ok()?;
+        Some((Value::Integer(value), &input[end..]))
+    }
+}
@@ -0,0 +1,185 @@
+//! Probe instrumentation: progress callbacks set with [`with_progress`], the metrics hook set
+//! with [`set_metrics_hook`] and `tracing` spans and events, enabled with the `tracing` feature.
+
+use super::*;
+
+use std::{rc::Rc, sync::RwLock};
+
+/// Detection progress, see [`with_progress`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum ProgressEvent<'a> {
+    /// The detector is about to be probed
+    Probing { detector: &'a str },
+    /// The detector was probed
+    Probed {
+        detector: &'a str,
+        outcome: &'a ProbeOutcome,
+    },
+}
+
+type ProgressCallback = Rc<dyn Fn(ProgressEvent)>;
+
+thread_local! {
+    static PROGRESS: RefCell<Option<ProgressCallback>> = RefCell::default();
+}
+
+/// Runs `f` so that `callback` is called as each detector is probed by detection on this
+/// thread, e.g. to show progress in a CLI.
+///
+/// ```
+/// use ci_id::{ProbeOutcome, ProgressEvent};
+///
+/// let token = ci_id::with_progress(
+///     |event| match event {
+///         ProgressEvent::Probing { detector } => eprintln!("Probing {}...", detector),
+///         ProgressEvent::Probed {
+///             detector,
+///             outcome: ProbeOutcome::Failed(e),
+///         } => eprintln!("{} failed: {}", detector, e),
+///         _ => {}
+///     },
+///     || ci_id::detect_credentials(Some("my-audience")),
+/// );
+/// ```
+pub fn with_progress<T>(callback: impl Fn(ProgressEvent) + 'static, f: impl FnOnce() -> T) -> T {
+    with_thread_local(&PROGRESS, Rc::new(callback), f)
+}
+
+fn progress(event: ProgressEvent) {
+    if let Some(callback) = PROGRESS.with_borrow(|callback| callback.clone()) {
+        callback(event);
+    }
+}
+
+/// Records that `detector` is about to be probed
+pub(crate) fn probe_started(detector: &str) {
+    progress(ProgressEvent::Probing { detector });
+}
+
+/// Receives detection metrics, see [`set_metrics_hook`].
+///
+/// The methods are called on the thread that runs detection and should return quickly, e.g. by
+/// incrementing a counter.
+pub trait MetricsHook: Send + Sync {
+    /// Called after a detector is probed
+    fn probe(&self, _detector: &str, _outcome: &ProbeOutcome) {}
+
+    /// Called after a token request: `status` is None if the request failed without a response
+    fn token_request(&self, _detector: &str, _status: Option<u16>, _elapsed: Duration) {}
+}
+
+static METRICS_HOOK: RwLock<Option<Arc<dyn MetricsHook>>> = RwLock::new(None);
+
+/// Sets the hook that receives metrics from all credential detection in the process, replacing
+/// any previous hook.
+///
+/// ```
+/// use ci_id::{MetricsHook, ProbeOutcome};
+/// use std::sync::atomic::{AtomicU64, Ordering};
+///
+/// #[derive(Default)]
+/// struct FailureCounter(AtomicU64);
+///
+/// impl MetricsHook for FailureCounter {
+///     fn probe(&self, _detector: &str, outcome: &ProbeOutcome) {
+///         if matches!(outcome, ProbeOutcome::Failed(_)) {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+/// }
+///
+/// ci_id::set_metrics_hook(FailureCounter::default());
+/// ```
+pub fn set_metrics_hook(hook: impl MetricsHook + 'static) {
+    *METRICS_HOOK.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(hook));
+}
+
+/// Removes the hook set with [`set_metrics_hook`]
+pub fn clear_metrics_hook() {
+    *METRICS_HOOK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+fn metrics_hook() -> Option<Arc<dyn MetricsHook>> {
+    METRICS_HOOK
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+#[cfg(all(feature = "tracing", feature = "async"))]
+use tracing::Instrument;
+
+/// Span covering the probe of a single detector
+#[cfg(feature = "tracing")]
+pub(crate) type ProbeSpan = tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct ProbeSpan;
+
+/// Enters a span for probing `detector`: the span is exited when the returned guard is dropped
+pub(crate) fn probe_span(detector: &str) -> ProbeSpan {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!("ci_id.probe", detector).entered();
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = detector;
+        ProbeSpan
+    }
+}
+
+/// Instruments the async probe of `detector` with a probe span
+#[cfg(feature = "async")]
+pub(crate) fn probe_future<F: std::future::Future>(
+    detector: &str,
+    future: F,
+) -> impl std::future::Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    return future.instrument(tracing::debug_span!("ci_id.probe", detector));
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = detector;
+        future
+    }
+}
+
+/// Records the outcome of a probe within the probe span
+pub(crate) fn probe_outcome(detector: &str, outcome: &ProbeOutcome) {
+    progress(ProgressEvent::Probed { detector, outcome });
+    if let Some(hook) = metrics_hook() {
+        hook.probe(detector, outcome);
+    }
+    #[cfg(feature = "tracing")]
+    match outcome {
+        ProbeOutcome::NotDetected => tracing::debug!(outcome = "not_detected"),
+        ProbeOutcome::Failed(e) => tracing::warn!(outcome = "failed", error = %e),
+        ProbeOutcome::Succeeded => tracing::info!(outcome = "succeeded"),
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = outcome;
+}
+
+/// Records a detector that is skipped without probing
+pub(crate) fn probe_skipped(detector: &str, reason: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(detector, reason, "ci_id.probe skipped");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (detector, reason);
+}
+
+/// Records a token request: `status` is None if the request failed without a response
+#[cfg(feature = "http")]
+pub(crate) fn http_request(name: &str, method: &str, status: Option<u16>, elapsed: Duration) {
+    if let Some(hook) = metrics_hook() {
+        hook.token_request(name, status, elapsed);
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        detector = name,
+        method,
+        status,
+        elapsed_ms = elapsed.as_millis() as u64,
+        "ci_id.http_request"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (name, method, status, elapsed);
+}
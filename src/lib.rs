@@ -3,15 +3,55 @@
 //!
 //! ```
 //! match ci_id::detect_credentials(Some("my-audience")) {
-//!     Ok(token) => println!("{}", token),
+//!     Ok(token) => println!("{}", token.expose()),
 //!     Err(e) => eprintln!("{}", e)
 //! }
 //! ```
 //!
+//! With the `async` feature, `detect_credentials_async` provides the same detection for async
+//! applications: token requests are then made with the non-blocking reqwest client.
+//!
+//! `detect_environment` reports which supported environment is present without requesting a
+//! token.
+//! `detect_credentials_or_wait` retries detection for a while, for jobs that may start before
+//! the token source is ready.
+//! [`PROVIDERS`] lists the supported environments with their marker variables, audience
+//! support and whether detection needs network access or a helper program.
+//!
+//! `with_env` runs detection against a captured environment instead of the process
+//! environment.
+//! Similarly `with_transport` sends the token requests with an application provided HTTP
+//! client.
+//!
+//! With the `testing` feature, the `testing` module provides a fake detector, fake tokens and
+//! a scoped detection environment for testing applications outside of a CI system.
+//!
+//! With the `verify` feature, `verify_token` verifies the token signature using the issuer
+//! JWKS, as well as the token audience and expiry.
+//!
+//! `with_progress` calls a callback as each detector is probed, e.g. to show progress in a CLI.
+//! `set_metrics_hook` installs a [`MetricsHook`] that is called for each probed detector and
+//! each token request, e.g. to count detection failures per provider.
+//!
+//! With the `tracing` feature, each probed detector gets a `ci_id.probe` span with events for
+//! the probe outcome, skipped detectors and token request latency, in addition to the `log`
+//! records.
+//!
+//! HTTP token requests use rustls with the system CA bundle by default (`rustls-tls` feature),
+//! so no OpenSSL is needed e.g. on musl or distroless images. With `default-features = false`
+//! and the `native-tls` feature, the platform TLS library is used instead. With the
+//! `custom-transport` feature instead of a TLS feature, reqwest is not compiled at all: token
+//! requests are only sent with the [`HttpTransport`] set with [`with_transport`], e.g. one
+//! that uses a smaller HTTP client.
+//!
 //! # Environment specific setup
 //!
 //! Typically the CI environment needs to allow OIDC identity access.
 //!
+//! Some environments decide the token audience themselves ([`AudienceSupport::Fixed`]): a
+//! different audience cannot be requested, and if an audience is requested from ci-id, it must
+//! match the token audience.
+//!
 //! ## GitHub Actions
 //!
 //! Workflow must be given the [permission](https://docs.github.com/en/actions/security-for-github-actions/security-hardening-your-deployments/about-security-hardening-with-openid-connect#adding-permissions-settings)
@@ -22,6 +62,20 @@
 //!     id-token: write
 //! ```
 //!
+//! ### GitHub Enterprise
+//!
+//! GitHub Enterprise Server and GitHub Enterprise Cloud with data residency are supported: the
+//! expected token issuer is derived from `GITHUB_SERVER_URL`. Enterprises with a customized
+//! issuer (`<issuer>/<enterprise-slug>`) are accepted as well. A token with an unexpected issuer
+//! is only logged as a warning, unless the expected issuer is set with `CI_ID_GITHUB_ISSUER`:
+//! then the token issuer must match it. Additional trusted root certificates for the
+//! token request can be provided in a PEM file pointed to by `CI_ID_CA_BUNDLE`, and the
+//! standard `HTTPS_PROXY` and `NO_PROXY` variables are honored.
+//!
+//! ## Gitea Actions and Forgejo Actions
+//!
+//! Like on GitHub Actions, the workflow must be given the `id-token: write` permission.
+//!
 //! ## GitLab Pipelines
 //!
 //! An [ID token](https://docs.gitlab.com/ee/ci/secrets/id_token_authentication.html)
@@ -35,24 +89,425 @@
 //!
 //! The ID token name must be based on the audience so that token name is `<AUD>_ID_TOKEN` where
 //! `<AUD>` is the audience string sanitized for environment variable names (uppercased and all
-//! characters outside of ascii letters and digits are replaced with "_").
+//! characters outside of ascii letters and digits are replaced with "_"): [`id_token_variable`]
+//! returns the name for an audience.
+//!
+//! If this naming scheme does not work for the pipeline, the variable names can be mapped
+//! explicitly with `CI_ID_GITLAB_TOKEN_VARIABLES`:
+//!
+//! ```yaml
+//! variables:
+//!     CI_ID_GITLAB_TOKEN_VARIABLES: "sigstore=SIGNING_TOKEN,sts.amazonaws.com=AWS_TOKEN"
+//! ```
+//!
+//! Alternatively, with `CI_ID_GITLAB_DISCOVER_TOKENS=1` ci-id looks at all `*_ID_TOKEN`
+//! variables and returns the token whose audience matches the requested one, so the variable
+//! can be named freely.
+//!
+//! Applications can also name the variable directly with
+//! [`CredentialRequest::gitlab_token_variable`]: the token is then read from that variable,
+//! whatever the audience.
+//!
+//! If `CI_ID_ALLOW_LEGACY_TOKENS=1` is set and no matching ID token is found, the deprecated
+//! `CI_JOB_JWT_V2` is used instead (if its audience matches the requested audience). The
+//! credential then names the variable in [`DetectedCredential::legacy_source`].
 //!
 //! ## CircleCI
 //!
-//! No configuration is needed.
+//! No configuration is needed. Tokens for custom audiences are requested with the `circleci`
+//! CLI: if it is not available in `PATH`, only the default audience (the organization id) is
+//! supported.
+//!
+//! If `CI_ID_ALLOW_LEGACY_TOKENS=1` is set and `CIRCLE_OIDC_TOKEN_V2` is not available, the
+//! legacy v1 token `CIRCLE_OIDC_TOKEN` is used for the default audience. The credential then
+//! names the variable in [`DetectedCredential::legacy_source`].
 //!
 //! ## Buildkite
 //!
+//! No configuration is needed. If `buildkite-agent` is not available in `PATH` (e.g. in a job
+//! container), the token is requested from the agent API using `BUILDKITE_AGENT_ACCESS_TOKEN`:
+//! the variable must be propagated into the container.
+//!
+//! `CI_ID_BUILDKITE_LIFETIME` sets the token lifetime in seconds and `CI_ID_BUILDKITE_CLAIMS`
+//! a comma separated list of optional claims to include in the token (e.g.
+//! `organization_id,pipeline_id`), see also [`CredentialRequest::buildkite_lifetime`] and
+//! [`CredentialRequest::buildkite_claim`].
+//!
+//! ## Jenkins
+//!
+//! The [OpenID Connect Provider](https://plugins.jenkins.io/oidc-provider/) plugin must be
+//! installed and an id token credential must be bound to an environment variable in the job:
+//!
+//! ```groovy
+//! withCredentials([string(credentialsId: 'my-audience-token', variable: 'MY_AUDIENCE_ID_TOKEN')]) {
+//!     sh 'ci-id my-audience'
+//! }
+//! ```
+//!
+//! The variable name follows the same `<AUD>_ID_TOKEN` convention as GitLab. If no audience is
+//! requested, the token is read from `ID_TOKEN`.
+//!
+//! ## TeamCity
+//!
+//! TeamCity does not issue id tokens itself: the token (e.g. from a plugin or an earlier build
+//! step) must be exposed to the build as an environment variable parameter:
+//!
+//! ```text
+//! env.MY_AUDIENCE_ID_TOKEN = <token>
+//! ```
+//!
+//! The variable name follows the same `<AUD>_ID_TOKEN` convention as GitLab. If no audience is
+//! requested, the token is read from `ID_TOKEN`.
+//!
+//! ## Codefresh
+//!
 //! No configuration is needed.
+//!
+//! ## Harness
+//!
+//! OIDC must be enabled for the stage so that Harness provides the token in
+//! `PLUGIN_OIDC_TOKEN_ID`. The token audience is set in the Harness configuration.
+//!
+//! ## Bitrise
+//!
+//! The workflow must run the
+//! [Get OIDC Identity Token](https://bitrise.io/integrations/steps/get-identity-token) step
+//! before ci-id is used: the token is read from `BITRISE_IDENTITY_TOKEN`. The step `audience`
+//! input decides the token audience.
+//!
+//! ```yaml
+//! - get-identity-token@0:
+//!     inputs:
+//!     - audience: my-audience
+//! ```
+//!
+//! ## Spacelift
+//!
+//! No configuration is needed: the token is read from `SPACELIFT_OIDC_TOKEN`. The token audience
+//! is the Spacelift account hostname (e.g. `myaccount.app.spacelift.io`).
+//!
+//! ## env0
+//!
+//! No configuration is needed: the token is read from `ENV0_OIDC_TOKEN`.
+//!
+//! ## HCP Terraform
+//!
+//! The workspace must define the audience of the workload identity token to make HCP Terraform
+//! (formerly Terraform Cloud) generate it. Additional audiences can be defined with a tag: ci-id
+//! requires the tag to be the sanitized audience string (using the same rules as with GitLab ID
+//! tokens):
+//!
+//! ```text
+//! TFC_WORKLOAD_IDENTITY_AUDIENCE = my-audience
+//! TFC_WORKLOAD_IDENTITY_AUDIENCE_SIGSTORE = sigstore
+//! ```
+//!
+//! Tagged tokens are preferred, the default token is returned if its audience matches.
+//!
+//! ## Pulumi Deployments
+//!
+//! OIDC must be configured in the stack deployment settings: the token is read from
+//! `PULUMI_OIDC_TOKEN`.
+//!
+//! ## Vercel
+//!
+//! [OIDC federation](https://vercel.com/docs/oidc) must be enabled for the project. Vercel sets
+//! the token audience to `https://vercel.com/<team>`. The token is read from `VERCEL_OIDC_TOKEN`.
+//!
+//! ## Depot
+//!
+//! No configuration is needed: the token is read from `DEPOT_OIDC_TOKEN`. Depot hosted GitHub
+//! Actions runners use the GitHub Actions identity instead.
+//!
+//! ## Garnix
+//!
+//! Identity tokens must be enabled for the Garnix action: the token is read from
+//! `GARNIX_ID_TOKEN`.
+//!
+//! ## Fly.io
+//!
+//! No configuration is needed: the token is requested from the Machines API socket
+//! (`/.fly/api`) for the requested audience. The request uses the HTTP request timeout
+//! (`CI_ID_HTTP_TIMEOUT`).
+//!
+//! ## Railway
+//!
+//! OIDC tokens must be enabled for the service: the token is read from `RAILWAY_OIDC_TOKEN`.
+//!
+//! ## Scaleway Serverless Jobs
+//!
+//! Workload identity must be enabled for the job definition: the token is read from
+//! `SCW_ID_TOKEN`.
+//!
+//! ## Namespace
+//!
+//! No configuration is needed: the token is issued with `nsc auth issue-id-token` so the `nsc`
+//! CLI must be available in `PATH`.
+//!
+//! ## Concourse
+//!
+//! A token from the [idtoken credential manager](https://concourse-ci.org/idtoken-credential-manager.html)
+//! must be passed to the task in `CONCOURSE_ID_TOKEN`. The token audience is set in the var
+//! source configuration.
+//!
+//! ```yaml
+//! params:
+//!     CONCOURSE_ID_TOKEN: ((idtoken:token))
+//! ```
+//!
+//! ## AWS web identity
+//!
+//! On EKS (IRSA or EKS Pod Identity) the web identity token is read from
+//! `AWS_WEB_IDENTITY_TOKEN_FILE`, if the requested audience matches the token audience (usually
+//! `sts.amazonaws.com`).
+//!
+//! ## Kubernetes
+//!
+//! A [projected service account token](https://kubernetes.io/docs/concepts/storage/projected-volumes/#serviceaccounttoken)
+//! with the required audience should be mounted in the pod and the path given in
+//! `KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE`:
+//!
+//! ```yaml
+//! volumes:
+//!   - name: id-token
+//!     projected:
+//!       sources:
+//!         - serviceAccountToken:
+//!             path: token
+//!             audience: my-audience
+//! ```
+//!
+//! Without `KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE`, the default service account token is returned
+//! only if an audience is requested and it matches the token audience: the default token is a
+//! Kubernetes API credential and is never returned without an audience.
+//!
+//! ## SPIFFE
+//!
+//! A JWT-SVID is fetched from the SPIFFE Workload API at `SPIFFE_ENDPOINT_SOCKET` for the
+//! requested audience. An audience must be requested.
+//!
+//! ci-id does not implement the gRPC Workload API: the call is made with the `spire-agent` CLI,
+//! which must be available in `PATH`. Without it the environment is not detected, even if
+//! `SPIFFE_ENDPOINT_SOCKET` is set: this is typically the case with other SPIFFE
+//! implementations (e.g. Istio) or in distroless images.
+//!
+//! ## Other environments
+//!
+//! If none of the supported environments is detected, the token is read from
+//! `CI_ID_TOKEN_<AUD>` (where `<AUD>` is the audience sanitized like GitLab ID token names) or
+//! `CI_ID_TOKEN`. This allows unsupported CI systems and wrapper scripts to provide the token.
+//!
+//! Similarly the token is read from the file pointed to by `CI_ID_TOKEN_FILE_<AUD>` or
+//! `CI_ID_TOKEN_FILE`, for platforms that provide tokens as mounted files.
+//!
+//! When an audience is requested, a token from `CI_ID_TOKEN` or `CI_ID_TOKEN_FILE` is only
+//! returned if its `aud` claim contains the audience.
+//!
+//! Finally, if `CI_ID_TOKEN_COMMAND` is set, it is run as a shell command (`sh -c`, or `cmd /C`
+//! on Windows) and its output is used as the token. The requested audience is available to the
+//! command in `CI_ID_AUDIENCE`:
+//!
+//! ```bash
+//! export CI_ID_TOKEN_COMMAND='my-token-tool --audience "$CI_ID_AUDIENCE"'
+//! ```
+//!
+//! ## Plugins
+//!
+//! With `CI_ID_ENABLE_PLUGINS=1`, executables named `ci-id-provider-<name>` in `PATH` are
+//! probed before the built-in detectors. Plugins are opt-in because any directory in `PATH`
+//! (e.g. `node_modules/.bin`) could otherwise provide a plugin that replaces the token. This
+//! allows providing detectors for other environments without changes to
+//! ci-id. A plugin is run with the requested audience in `CI_ID_AUDIENCE` and writes the token
+//! to stdout, either as is or as JSON:
+//!
+//! ```json
+//! {"token": "eyJhbGciOi..."}
+//! ```
+//!
+//! A plugin that does not detect its environment writes nothing and exits successfully. If the
+//! token can not be provided, the plugin exits with a non-zero exit code or writes
+//! `{"error": "<message>"}`. Plugins are not probed if `CI_ID_PROVIDER` is set. `PATH` is
+//! searched for plugins once per `PATH` value.
+//!
+//! ## GitHub Codespaces
+//!
+//! Codespaces does not provide ambient OIDC credentials: ci-id recognizes the environment and
+//! returns an error explaining this.
+//!
+//! # Choosing the detector
+//!
+//! When multiple environments are present (e.g. a GitLab runner on a GitHub hosted machine),
+//! `CI_ID_PROVIDER` forces a specific detector and `CI_ID_DISABLE_<ID>=1` skips a detector.
+//! The detector ids are listed in [`Provider::id`]:
+//!
+//! ```bash
+//! export CI_ID_PROVIDER=gitlab
+//! export CI_ID_DISABLE_GITHUB=1
+//! ```
+//!
+//! These apply to detection with the built-in detector order: explicitly chosen providers
+//! (e.g. [`detect_credentials_from`] or [`DetectorRegistry`]) are used as is.
+//!
+//! # Detector features
+//!
+//! Each environment's detector is behind a cargo feature named after its id (e.g. `github`,
+//! `gitlab` or `kubernetes`). All of them are enabled by default with the `all-detectors`
+//! feature: to build only some detectors, disable the default features and keep a TLS backend:
+//!
+//! ```toml
+//! [dependencies]
+//! ci-id = { version = "0.3", default-features = false, features = ["rustls-tls", "gitlab"] }
+//! ```
+//!
+//! HTTP token requests are only included for the detectors that request the token over HTTP
+//! (`forgejo`, `gitea`, `github`, `codefresh` and `buildkite`) and for `verify`. Running
+//! helper programs (the `subprocess` feature) is only included for the detectors that call a CLI
+//! (`circleci`, `buildkite`, `namespace` and `spiffe`): without it `CI_ID_TOKEN_COMMAND`,
+//! `command` detectors in the configuration file and plugins fail or are not used. A detector
+//! that is disabled at build time reports its environment as not detected, or returns an error
+//! if the environment is present: see [`CIEnvironment::is_enabled`]. The generic `CI_ID_TOKEN*`
+//! detectors are always included.
+//!
+//! The `minimal` feature includes the detectors that only read environment variables or files:
+//! GitLab, Jenkins, TeamCity, Harness, Bitrise, Spacelift, env0, HCP Terraform, Pulumi
+//! Deployments, Vercel, Depot, Garnix, Railway, Scaleway, Concourse, AWS web identity, Kubernetes
+//! and GitHub Codespaces. It builds without an HTTP client, TLS library or subprocess support:
+//!
+//! ```toml
+//! [dependencies]
+//! ci-id = { version = "0.3", default-features = false, features = ["minimal"] }
+//! ```
+//!
+//! The `minimal` build (or any set of detectors without the `http` feature) also compiles for
+//! WASI (`wasm32-wasip1`), e.g. for WASM based CI steps and plugins. The token is read from the
+//! environment variables and files that the WASM runtime exposes to the module.
+//! [`CredentialRequest::parallel`] probes sequentially on WASM targets.
+//!
+//! # Configuration file
+//!
+//! The same settings, a default audience, the HTTP timeout and additional generic detectors can
+//! be set in a configuration file pointed to by `CI_ID_CONFIG`. A `ci-id.toml` in the current
+//! directory is not read: in CI that is the checked out repository, which may come from an
+//! untrusted pull request. `CI_ID_PROVIDER` and `CI_ID_DISABLE_<ID>` take precedence over the
+//! file.
+//!
+//! ```toml
+//! # built-in detectors to probe, in order
+//! providers = ["github", "gitlab", "env"]
+//! disable = ["gitlab"]
+//! # audience used when the caller does not request one
+//! audience = "sigstore"
+//! # HTTP request and connect timeouts in seconds
+//! timeout = 30
+//! connect_timeout = 10
+//! # helper program timeout in seconds
+//! command_timeout = 60
+//!
+//! # custom detectors are probed before the built-in detectors
+//! [[detector]]
+//! name = "Vault"
+//! command = "vault read -field=token identity/oidc/token/ci"
+//! ```
+//!
+//! A `[[detector]]` reads the token from an environment variable (`env`), a file (`file`) or
+//! the output of a shell command (`command`, with the audience in `CI_ID_AUDIENCE`). Only a
+//! subset of TOML is supported: single line values and `[[detector]]` tables.
+//!
+//! Rate limited token requests (HTTP status 429) are retried up to 3 times when the response has
+//! a `Retry-After` of at most 30 seconds: otherwise [`CIIDError::RateLimited`] is returned.
+//!
+//! HTTP token requests time out after 30 seconds, or 10 seconds if connecting to the token
+//! endpoint does not succeed. `CI_ID_HTTP_TIMEOUT` and `CI_ID_CONNECT_TIMEOUT` set the timeouts
+//! in seconds, overriding the configuration file.
+//!
+//! Token requests are sent with the User-Agent `ci-id/<version>` so that the requests can be
+//! identified in the token service logs. `CI_ID_USER_AGENT` (or
+//! [`CredentialRequest::user_agent`]) replaces it, e.g. with the name of the application.
+//!
+//! Helper programs (e.g. `buildkite-agent` or the `circleci` CLI) are killed if they do not finish
+//! in 60 seconds: `CI_ID_COMMAND_TIMEOUT` (or `command_timeout` in the configuration file) sets
+//! the timeout in seconds.
+//!
+//! HTTP token requests use the proxies set with `HTTPS_PROXY` and `HTTP_PROXY` (or the lowercase
+//! variants), except for the hosts listed in `NO_PROXY`. [`CredentialRequest::proxy`] sets a
+//! proxy for all token requests instead.
+//!
+//! Additional trusted root certificates can be provided in a PEM file pointed to by
+//! `CI_ID_CA_BUNDLE` (or `ca_bundle` in the configuration file), or with
+//! [`CredentialRequest::root_certificates`].
 
-use regex::Regex;
-use serde::Deserialize;
-use std::{collections::HashMap, env, fmt, process::Command};
-pub type Result<T> = std::result::Result<T, CIIDError>;
+#[cfg(all(
+    feature = "reqwest",
+    not(any(feature = "rustls-tls", feature = "native-tls"))
+))]
+compile_error!("either the rustls-tls or the native-tls feature must be enabled");
+
+#[cfg(all(
+    feature = "http",
+    not(any(feature = "reqwest", feature = "custom-transport"))
+))]
+compile_error!(
+    "HTTP token requests need the built-in client: enable the rustls-tls or the native-tls \
+    feature, or the custom-transport feature to send the requests with with_transport"
+);
+
+#[cfg(all(feature = "http", target_family = "wasm"))]
+compile_error!(
+    "HTTP token requests are not supported on WASM targets: disable the default features and \
+    enable the detectors that do not need the http feature, e.g. with the minimal feature"
+);
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "subprocess")]
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Instant;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use zeroize::Zeroizing;
 
-#[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
+mod config;
+use config::{load_config, Config};
+
+mod plugin;
+use plugin::{discover_plugins, PluginDetector};
+
+mod sha256;
+
+mod instrument;
+pub use instrument::{
+    clear_metrics_hook, set_metrics_hook, with_progress, MetricsHook, ProgressEvent,
+};
+
+mod claim_types;
+pub use claim_types::{BuildkiteClaims, CircleCIClaims, GitHubClaims, GitLabClaims};
+
+mod metadata;
+pub use metadata::{run_metadata, RunMetadata};
+
+#[cfg(feature = "verify")]
+mod verify;
+#[cfg(feature = "verify")]
+pub use verify::verify_token;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::{
+    detect_credential_async, detect_credential_async_with, detect_credentials_async,
+    detect_credentials_async_with, AsyncDetector, DetectFuture,
+};
+
+pub type Result<T> = std::result::Result<T, CIIDError>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum CIIDError {
@@ -62,11 +517,171 @@ pub enum CIIDError {
     EnvironmentError(String),
     /// Identity token was found but it does not look like JSON Web Token
     MalformedToken,
+    /// Identity token was found but token verification failed
+    VerificationFailed(String),
+    /// Identity token was found but its audience does not match the requested audience
+    AudienceMismatch {
+        expected: String,
+        found: Vec<String>,
+    },
+    /// Identity token was found but it has expired
+    TokenExpired,
+    /// Identity token was found but it expires sooner than the required minimum validity
+    TokenTooShortLived { expires_in: Duration },
+    /// Environment was found but the HTTP token request failed
+    RequestFailed {
+        message: String,
+        source: ErrorSource,
+    },
+    /// Environment was found but reading a token file, or running a helper program failed
+    IoError {
+        message: String,
+        source: ErrorSource,
+    },
+    /// Environment was found but the job is not allowed to request identity tokens
+    MissingPermission { message: String },
+    /// Environment was found but the environment variable `name` is not set
+    MissingVariable { name: String, message: String },
+    /// Environment was found but the token request failed with HTTP status `status`
+    HttpError { status: u16, message: String },
+    /// Environment was found but the token endpoint rate limited the request (HTTP status 429)
+    /// even after retrying. `retry_after` is the delay the endpoint asked for, if any
+    RateLimited {
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    /// Environment was found but the helper program failed. `exit_code` is None if the program
+    /// was terminated by a signal
+    SubprocessFailed {
+        exit_code: Option<i32>,
+        message: String,
+    },
+    /// Multiple environments were found but detection failed in all of them: contains the
+    /// detector names and errors in probing order
+    DetectionFailed { failures: Vec<(String, CIIDError)> },
+}
+
+impl CIIDError {
+    #[cfg(feature = "http")]
+    fn request_failed(message: String, source: impl Into<BoxError>) -> Self {
+        CIIDError::RequestFailed {
+            message,
+            source: ErrorSource(Arc::from(source.into())),
+        }
+    }
+
+    fn io_error(message: String, source: std::io::Error) -> Self {
+        CIIDError::IoError {
+            message,
+            source: ErrorSource(Arc::new(source)),
+        }
+    }
+
+    #[cfg_attr(not(feature = "all-detectors"), allow(dead_code))]
+    fn missing_variable(name: &str, message: impl Into<String>) -> Self {
+        CIIDError::MissingVariable {
+            name: name.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Returns the error for failed probing: the only failure as is, or all failures combined
+    fn from_failures(mut failures: Vec<(String, CIIDError)>) -> Self {
+        match failures.len() {
+            0 => CIIDError::EnvironmentNotDetected,
+            1 => failures.remove(0).1,
+            _ => CIIDError::DetectionFailed { failures },
+        }
+    }
+
+    /// Returns false if retrying detection can not fix the error
+    fn is_transient(&self) -> bool {
+        match self {
+            CIIDError::MissingPermission { .. }
+            | CIIDError::AudienceMismatch { .. }
+            | CIIDError::MalformedToken
+            | CIIDError::VerificationFailed(_) => false,
+            CIIDError::DetectionFailed { failures } => {
+                failures.iter().all(|(_, e)| e.is_transient())
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the detailed error message, if the error has one
+    fn message(&self) -> Option<&str> {
+        match self {
+            CIIDError::EnvironmentError(message)
+            | CIIDError::RequestFailed { message, .. }
+            | CIIDError::IoError { message, .. }
+            | CIIDError::MissingPermission { message }
+            | CIIDError::MissingVariable { message, .. }
+            | CIIDError::HttpError { message, .. }
+            | CIIDError::RateLimited { message, .. }
+            | CIIDError::SubprocessFailed { message, .. } => Some(message),
+            _ => None,
+        }
+    }
+}
+
+/// Error type for application provided implementations, see [`HttpTransport`]
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The underlying error of a [`CIIDError`], also available as [`std::error::Error::source`]
+#[derive(Debug, Clone)]
+pub struct ErrorSource(Arc<dyn std::error::Error + Send + Sync>);
+
+impl fmt::Display for ErrorSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// The underlying error types do not implement PartialEq: compare the messages
+impl PartialEq for ErrorSource {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl std::error::Error for CIIDError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CIIDError::RequestFailed { source, .. } | CIIDError::IoError { source, .. } => {
+                Some(source.0.as_ref())
+            }
+            _ => None,
+        }
+    }
 }
 impl fmt::Display for CIIDError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(message) = self.message() {
+            return write!(f, "credential detection failed: {}", message);
+        }
         match self {
-            CIIDError::EnvironmentError(s) => write!(f, "credential detection failed: {}", s),
+            CIIDError::VerificationFailed(s) => write!(f, "token verification failed: {}", s),
+            CIIDError::AudienceMismatch { expected, found } => write!(
+                f,
+                "token audience {:?} does not match requested audience '{}'",
+                found, expected
+            ),
+            CIIDError::TokenExpired => write!(f, "token has expired"),
+            CIIDError::TokenTooShortLived { expires_in } => write!(
+                f,
+                "token expires in {} seconds, sooner than required",
+                expires_in.as_secs()
+            ),
+            CIIDError::DetectionFailed { failures } => {
+                write!(f, "credential detection failed in all found environments")?;
+                for (name, error) in failures {
+                    match error.message() {
+                        Some(message) => write!(f, "\n  {}", message)?,
+                        None => write!(f, "\n  {}: {}", name, error)?,
+                    }
+                }
+                Ok(())
+            }
             _ => write!(f, "credential detection failed"),
         }
     }
@@ -74,538 +689,8863 @@ impl fmt::Display for CIIDError {
 
 type DetectFn = fn(Option<&str>) -> Result<String>;
 
+/// Returns the claims in the token payload. The token signature is not verified: the claims are
+/// meant for logging and diagnostics, not for making trust decisions.
+///
+/// ```
+/// # let token = "eyJhbGciOiJub25lIn0.eyJzdWIiOiJyZXBvOmprdS9jaS1pZCJ9.";
+/// let claims = ci_id::claims(token).unwrap();
+/// println!("Token subject: {}", claims["sub"]);
+/// ```
+pub fn claims(token: &str) -> Result<serde_json::Value> {
+    token_claims(token).ok_or(CIIDError::MalformedToken)
+}
+
+/// Returns a short fingerprint of the token: the first 12 hex digits of the SHA-256 digest.
+///
+/// The fingerprint identifies the token in logs without revealing it, e.g. to correlate token
+/// usage across systems.
+///
+/// ```
+/// let fingerprint = ci_id::token_fingerprint("header.payload.signature");
+/// println!("Using token {}…", fingerprint);
+/// # assert_eq!(fingerprint.len(), 12);
+/// ```
+pub fn token_fingerprint(token: &str) -> String {
+    sha256::sha256(token.as_bytes())[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the (unverified) token payload
+fn token_claims(token: &str) -> Option<serde_json::Value> {
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Returns the audiences found in the (unverified) token payload
+fn token_audiences(token: &str) -> Option<Vec<String>> {
+    match token_claims(token)?.get("aud")? {
+        serde_json::Value::String(aud) => Some(vec![aud.clone()]),
+        serde_json::Value::Array(auds) => Some(
+            auds.iter()
+                .filter_map(|aud| aud.as_str().map(String::from))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Checks that token matches the requested audience, see [`AudienceSupport::Fixed`]
+#[cfg_attr(not(feature = "all-detectors"), allow(dead_code))]
+fn check_audience(name: &str, token: String, audience: Option<&str>) -> Result<String> {
+    let Some(audience) = audience else {
+        return Ok(token);
+    };
+    match token_audiences(&token) {
+        Some(auds) if auds.iter().any(|aud| aud == audience) => Ok(token),
+        Some(auds) => Err(CIIDError::EnvironmentError(format!(
+            "{}: Token audience {:?} does not match requested audience '{}'. The audience \
+            is set in the {} configuration",
+            name, auds, audience, name
+        ))),
+        None => Err(CIIDError::EnvironmentError(format!(
+            "{}: Failed to read token audience",
+            name
+        ))),
+    }
+}
+
+/// Returns true if `token` could be a JWT: three dot separated base64url segments
+fn is_jwt_like(token: &str) -> bool {
+    token.split('.').count() == 3
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'=' | b'.'))
+}
+
 fn validate_token(token: String) -> Result<String> {
     // very, very shallow validation: could this be a JWT token?
-    match token.split(".").collect::<Vec<&str>>().len() {
-        3 => Ok(token),
-        _ => Err(CIIDError::MalformedToken),
+    match is_jwt_like(&token) {
+        true => Ok(token),
+        false => Err(CIIDError::MalformedToken),
     }
 }
 
-/// Returns detected OIDC identity token.
+/// An OIDC identity token.
 ///
-/// The supported environments are probed in order, the identity token
-/// for the first found environment is returned.
+/// Tokens are bearer credentials: the value is not included in `Debug` or `Display` output and
+/// the memory is zeroized when the token is dropped. Use [`SecretToken::expose`] to access the
+/// token.
 ///
 /// ```
-/// match ci_id::detect_credentials(Some("my-audience")) {
-///     Ok(token) => println!("{}", token),
-///     Err(e) => eprintln!("{}", e)
-/// }
+/// let token = ci_id::SecretToken::from("header.payload.signature");
+/// assert_eq!(format!("{:?}", token), "SecretToken(<redacted>)");
+/// assert_eq!(token.to_string(), "<redacted>");
+/// assert_eq!(token.expose(), "header.payload.signature");
 /// ```
-pub fn detect_credentials(audience: Option<&str>) -> Result<String> {
-    for (name, detect) in [
-        ("GitHub Actions", detect_github as DetectFn),
-        ("GitLab Pipelines", detect_gitlab as DetectFn),
-        ("CircleCI", detect_circleci as DetectFn),
-        ("Buildkite", detect_buildkite as DetectFn),
-    ] {
-        match detect(audience) {
-            Ok(token) => {
-                let token = validate_token(token)?;
-                log::debug!("{}: Token found", name);
-                return Ok(token);
-            }
-            Err(CIIDError::EnvironmentNotDetected) => {
-                log::debug!("{}: Environment not detected", name);
-            }
-            Err(e) => return Err(e),
-        }
+#[derive(Clone, PartialEq, Eq)]
+pub struct SecretToken(Zeroizing<String>);
+
+impl SecretToken {
+    /// Returns the token value
+    pub fn expose(&self) -> &str {
+        &self.0
     }
 
-    Err(CIIDError::EnvironmentNotDetected)
-}
+    /// Returns a fingerprint that identifies the token in logs, see [`token_fingerprint`]
+    pub fn fingerprint(&self) -> String {
+        token_fingerprint(&self.0)
+    }
 
-// Github implementation
+    /// Returns the expiry time from the unverified `exp` claim, None if the token has no expiry
+    /// time or can not be decoded
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        token_expiry(&token_claims(&self.0)?)
+    }
 
-#[derive(Deserialize)]
-struct GitHubTokenResponse {
-    value: String,
+    /// Returns the time until the token expires, zero if it has expired. See
+    /// [`expires_at`](Self::expires_at).
+    pub fn time_to_expiry(&self) -> Option<Duration> {
+        self.expires_at().map(time_until)
+    }
 }
 
-fn detect_github(audience: Option<&str>) -> Result<String> {
-    if env::var("GITHUB_ACTIONS").is_err() {
-        return Err(CIIDError::EnvironmentNotDetected);
-    };
+/// Returns the `exp` claim as a time
+fn token_expiry(claims: &serde_json::Value) -> Option<SystemTime> {
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(exp))
+}
 
-    let Ok(token_token) = env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN") else {
-        return Err(CIIDError::EnvironmentError(
-            "GitHub Actions: ACTIONS_ID_TOKEN_REQUEST_TOKEN is not set. This could \
-            imply that the job does not have 'id-token: write' permission"
-                .into(),
-        ));
-    };
-    let Ok(token_url) = env::var("ACTIONS_ID_TOKEN_REQUEST_URL") else {
-        return Err(CIIDError::EnvironmentError(
-            "GitHub Actions: ACTIONS_ID_TOKEN_REQUEST_URL is not set".into(),
-        ));
-    };
-    let mut params = HashMap::new();
-    if let Some(aud) = audience {
-        params.insert("audience", aud);
-    }
+/// Returns the time until `time`, zero if it has passed
+fn time_until(time: SystemTime) -> Duration {
+    time.duration_since(SystemTime::now()).unwrap_or_default()
+}
 
-    log::debug!("GitHub Actions: Requesting token");
-    let client = reqwest::blocking::Client::new();
-    let http_response = match client
-        .get(token_url)
-        .header(
-            reqwest::header::AUTHORIZATION,
-            format!("bearer {}", token_token),
-        )
-        .query(&params)
-        .send()
-    {
-        Ok(response) => response,
-        Err(e) => {
-            return Err(CIIDError::EnvironmentError(format!(
-                "GitHub Actions: Token request failed: {}",
-                e
-            )))
-        }
-    };
-    match http_response.json::<GitHubTokenResponse>() {
-        Ok(token_response) => Ok(token_response.value),
-        Err(e) => Err(CIIDError::EnvironmentError(format!(
-            "GitHub Actions: Failed to parse token reponse: {}",
-            e
-        ))),
+impl From<String> for SecretToken {
+    fn from(token: String) -> Self {
+        SecretToken(Zeroizing::new(token))
     }
 }
 
-fn detect_gitlab(audience: Option<&str>) -> Result<String> {
-    // gitlab tokens can be in any environment variable: we require the variable name to be
-    // * "<AUDIENCE>_ID_TOKEN" where <AUDIENCE> is the audience string.
+impl From<&str> for SecretToken {
+    fn from(token: &str) -> Self {
+        SecretToken::from(token.to_string())
+    }
+}
 
-    if env::var("GITLAB_CI").is_err() {
-        return Err(CIIDError::EnvironmentNotDetected);
-    };
+/// Serializes the token value: the serialized form is as sensitive as the token itself
+impl Serialize for SecretToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
 
-    let var_name = match audience {
-        None => {
-            return Err(CIIDError::EnvironmentError(
-                "GitLab: audience must be set".into(),
-            ));
-        }
-        Some(audience) => {
-            let upper_audience = audience.to_uppercase();
-            let re = Regex::new(r"[^A-Z0-9_]|^[^A-Z_]").unwrap();
-            format!("{}_ID_TOKEN", re.replace_all(&upper_audience, "_"))
-        }
-    };
-    log::debug!("GitLab Pipelines: Looking for token in {}", var_name);
-    match env::var(&var_name) {
-        Ok(token) => Ok(token),
-        Err(_) => Err(CIIDError::EnvironmentError(format!(
-            "GitLab Pipelines: {} is not set. This could imply that the \
-            pipeline does not define an id token with that name",
-            var_name
-        ))),
+impl<'de> Deserialize<'de> for SecretToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretToken::from)
     }
 }
 
-fn detect_circleci(audience: Option<&str>) -> Result<String> {
-    if env::var("CIRCLECI").is_err() {
-        return Err(CIIDError::EnvironmentNotDetected);
-    };
-    let payload;
-    match audience {
-        None => match env::var("CIRCLE_OIDC_TOKEN_V2") {
-            Ok(token) => Ok(token),
-            Err(_) => Err(CIIDError::EnvironmentError(
-                "CircleCI: CIRCLE_OIDC_TOKEN_V2 is not set.".into(),
-            )),
-        },
-        Some(audience) => {
-            // TODO Use serde here? the audience string could be anything...
-            payload = format!("{{\"aud\":\"{}\"}}", audience);
-            let args = ["run", "oidc", "get", "--claims", &payload];
-            match Command::new("circleci").args(args).output() {
-                Ok(output) => match String::from_utf8(output.stdout) {
-                    Ok(token) => Ok(token.trim_end().to_string()),
-                    Err(_) => Err(CIIDError::EnvironmentError(
-                        "CircleCI; Failed to read token".into(),
-                    )),
-                },
-                Err(e) => Err(CIIDError::EnvironmentError(format!(
-                    "CircleCI: Call to circle CLI failed: {}",
-                    e
-                ))),
+impl fmt::Debug for SecretToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretToken(<redacted>)")
+    }
+}
+
+impl fmt::Display for SecretToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// A detected identity token and information about it.
+///
+/// The claims are decoded from the token payload without verifying the token signature: they are
+/// meant for logging and diagnostics, not for making trust decisions.
+///
+/// The credential can be serialized e.g. to cache it on disk: the serialized form includes the
+/// token. `expiry` is serialized as seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct DetectedCredential {
+    /// The OIDC identity token
+    pub token: SecretToken,
+    /// Name of the environment (detector) that provided the token
+    pub provider: String,
+    /// Unverified token claims, `Null` if the payload could not be decoded
+    pub claims: serde_json::Value,
+    /// Unverified `iss` claim
+    pub issuer: Option<String>,
+    /// Unverified `exp` claim
+    #[serde(with = "unix_time")]
+    pub expiry: Option<SystemTime>,
+    /// The deprecated variable the token was read from, if the detector fell back to a legacy
+    /// token (see `CI_ID_ALLOW_LEGACY_TOKENS`)
+    #[serde(default)]
+    pub legacy_source: Option<String>,
+}
+
+/// Serializes an optional time as seconds since the Unix epoch
+mod unix_time {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        time.map(|time| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Option<SystemTime>, D::Error> {
+        let secs = Option::<u64>::deserialize(deserializer)?;
+        Ok(secs.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}
+
+impl DetectedCredential {
+    /// Returns an unverified claim, e.g. `sub` or `repository`
+    pub fn claim(&self, name: &str) -> Option<&serde_json::Value> {
+        self.claims.get(name)
+    }
+
+    /// Returns the unverified claims deserialized into a typed claims struct, e.g.
+    /// [`GitHubClaims`]. Returns [`CIIDError::MalformedToken`] if the claims do not match.
+    ///
+    /// ```no_run
+    /// use ci_id::GitHubClaims;
+    ///
+    /// let credential = ci_id::detect_credential(Some("my-audience")).unwrap();
+    /// let claims: GitHubClaims = credential.claims_as().unwrap();
+    /// println!("Token for {} at {}", claims.repository, claims.git_ref);
+    /// ```
+    pub fn claims_as<T: DeserializeOwned>(&self) -> Result<T> {
+        T::deserialize(&self.claims).map_err(|_| CIIDError::MalformedToken)
+    }
+
+    /// Returns the expiry time from the unverified `exp` claim, None if the token has no expiry
+    /// time
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expiry
+    }
+
+    /// Returns the time until the token expires, zero if it has expired. This can be used to
+    /// schedule a refresh before the token expires:
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// let credential = ci_id::detect_credential(Some("my-audience")).unwrap();
+    /// if let Some(remaining) = credential.time_to_expiry() {
+    ///     let refresh_in = remaining.saturating_sub(Duration::from_secs(60));
+    ///     println!("Refreshing the token in {} seconds", refresh_in.as_secs());
+    /// }
+    /// ```
+    pub fn time_to_expiry(&self) -> Option<Duration> {
+        self.expiry.map(time_until)
+    }
+
+    /// Returns the identity a verifier should expect for signatures made with this token, e.g.
+    /// in a sigstore verification policy.
+    ///
+    /// The identity is the Fulcio certificate SAN for the environments where it is derived from
+    /// workflow claims: the workflow URL
+    /// (`https://github.com/<org>/<repo>/.github/workflows/<file>@<ref>`) for GitHub Actions,
+    /// the CI configuration URL for GitLab and the pipeline URL for Buildkite. For other
+    /// environments the identity is the `sub` claim. Returns None if the claims needed for the
+    /// identity are missing.
+    ///
+    /// ```no_run
+    /// let credential = ci_id::detect_credential(Some("sigstore")).unwrap();
+    /// if let Some(expected) = credential.expected_identity() {
+    ///     println!(
+    ///         "cosign verify --certificate-identity {} --certificate-oidc-issuer {}",
+    ///         expected.identity, expected.issuer
+    ///     );
+    /// }
+    /// ```
+    pub fn expected_identity(&self) -> Option<ExpectedIdentity> {
+        let claim = |name: &str| self.claim(name).and_then(|value| value.as_str());
+        let identity = if self.provider == CIEnvironment::GitHubActions.name() {
+            let server_url =
+                env_var("GITHUB_SERVER_URL").unwrap_or_else(|_| "https://github.com".into());
+            format!(
+                "{}/{}",
+                server_url.trim_end_matches('/'),
+                claim("job_workflow_ref")?
+            )
+        } else if self.provider == CIEnvironment::GitLab.name() {
+            format!("https://{}", claim("ci_config_ref_uri")?)
+        } else if self.provider == CIEnvironment::Buildkite.name() {
+            format!(
+                "https://buildkite.com/{}/{}",
+                claim("organization_slug")?,
+                claim("pipeline_slug")?
+            )
+        } else {
+            claim("sub")?.into()
+        };
+        Some(ExpectedIdentity {
+            issuer: self.issuer.clone()?,
+            identity,
+        })
+    }
+
+    fn new(provider: &str, token: String) -> Self {
+        let claims = token_claims(&token).unwrap_or_default();
+        let issuer = claims
+            .get("iss")
+            .and_then(|iss| iss.as_str())
+            .map(String::from);
+        let expiry = token_expiry(&claims);
+        DetectedCredential {
+            token: token.into(),
+            provider: provider.into(),
+            claims,
+            issuer,
+            expiry,
+            legacy_source: None,
+        }
+    }
+}
+
+/// The identity a verifier should expect for a token, see
+/// [`DetectedCredential::expected_identity`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ExpectedIdentity {
+    /// The token issuer
+    pub issuer: String,
+    /// The identity within the issuer, e.g. the Fulcio certificate SAN
+    pub identity: String,
+}
+
+/// A supported CI or workload environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CIEnvironment {
+    ForgejoActions,
+    GiteaActions,
+    GitHubActions,
+    GitLab,
+    CircleCI,
+    Buildkite,
+    Jenkins,
+    TeamCity,
+    Codefresh,
+    Harness,
+    Bitrise,
+    Spacelift,
+    Env0,
+    HcpTerraform,
+    PulumiDeployments,
+    Vercel,
+    Depot,
+    Garnix,
+    FlyIo,
+    Railway,
+    Scaleway,
+    Namespace,
+    Concourse,
+    AwsWebIdentity,
+    Kubernetes,
+    Spiffe,
+    GitHubCodespaces,
+}
+
+impl CIEnvironment {
+    /// All supported environments in the order they are probed
+    pub const ALL: &'static [CIEnvironment] = &[
+        CIEnvironment::ForgejoActions,
+        CIEnvironment::GiteaActions,
+        CIEnvironment::GitHubActions,
+        CIEnvironment::GitLab,
+        CIEnvironment::CircleCI,
+        CIEnvironment::Buildkite,
+        CIEnvironment::Jenkins,
+        CIEnvironment::TeamCity,
+        CIEnvironment::Codefresh,
+        CIEnvironment::Harness,
+        CIEnvironment::Bitrise,
+        CIEnvironment::Spacelift,
+        CIEnvironment::Env0,
+        CIEnvironment::HcpTerraform,
+        CIEnvironment::PulumiDeployments,
+        CIEnvironment::Vercel,
+        CIEnvironment::Depot,
+        CIEnvironment::Garnix,
+        CIEnvironment::FlyIo,
+        CIEnvironment::Railway,
+        CIEnvironment::Scaleway,
+        CIEnvironment::Namespace,
+        CIEnvironment::Concourse,
+        CIEnvironment::AwsWebIdentity,
+        CIEnvironment::Kubernetes,
+        CIEnvironment::Spiffe,
+        CIEnvironment::GitHubCodespaces,
+    ];
+
+    /// Returns the human readable environment name
+    pub const fn name(self) -> &'static str {
+        match self {
+            CIEnvironment::ForgejoActions => "Forgejo Actions",
+            CIEnvironment::GiteaActions => "Gitea Actions",
+            CIEnvironment::GitHubActions => "GitHub Actions",
+            CIEnvironment::GitLab => "GitLab Pipelines",
+            CIEnvironment::CircleCI => "CircleCI",
+            CIEnvironment::Buildkite => "Buildkite",
+            CIEnvironment::Jenkins => "Jenkins",
+            CIEnvironment::TeamCity => "TeamCity",
+            CIEnvironment::Codefresh => "Codefresh",
+            CIEnvironment::Harness => "Harness",
+            CIEnvironment::Bitrise => "Bitrise",
+            CIEnvironment::Spacelift => "Spacelift",
+            CIEnvironment::Env0 => "env0",
+            CIEnvironment::HcpTerraform => "HCP Terraform",
+            CIEnvironment::PulumiDeployments => "Pulumi Deployments",
+            CIEnvironment::Vercel => "Vercel",
+            CIEnvironment::Depot => "Depot",
+            CIEnvironment::Garnix => "Garnix",
+            CIEnvironment::FlyIo => "Fly.io",
+            CIEnvironment::Railway => "Railway",
+            CIEnvironment::Scaleway => "Scaleway Serverless Jobs",
+            CIEnvironment::Namespace => "Namespace",
+            CIEnvironment::Concourse => "Concourse",
+            CIEnvironment::AwsWebIdentity => "AWS web identity",
+            CIEnvironment::Kubernetes => "Kubernetes",
+            CIEnvironment::Spiffe => "SPIFFE",
+            CIEnvironment::GitHubCodespaces => "GitHub Codespaces",
+        }
+    }
+
+    /// Returns the short lowercase environment id, e.g. "gitlab", see [`Provider::id`]
+    pub const fn id(self) -> &'static str {
+        match self {
+            CIEnvironment::ForgejoActions => "forgejo",
+            CIEnvironment::GiteaActions => "gitea",
+            CIEnvironment::GitHubActions => "github",
+            CIEnvironment::GitLab => "gitlab",
+            CIEnvironment::CircleCI => "circleci",
+            CIEnvironment::Buildkite => "buildkite",
+            CIEnvironment::Jenkins => "jenkins",
+            CIEnvironment::TeamCity => "teamcity",
+            CIEnvironment::Codefresh => "codefresh",
+            CIEnvironment::Harness => "harness",
+            CIEnvironment::Bitrise => "bitrise",
+            CIEnvironment::Spacelift => "spacelift",
+            CIEnvironment::Env0 => "env0",
+            CIEnvironment::HcpTerraform => "terraform",
+            CIEnvironment::PulumiDeployments => "pulumi",
+            CIEnvironment::Vercel => "vercel",
+            CIEnvironment::Depot => "depot",
+            CIEnvironment::Garnix => "garnix",
+            CIEnvironment::FlyIo => "fly",
+            CIEnvironment::Railway => "railway",
+            CIEnvironment::Scaleway => "scaleway",
+            CIEnvironment::Namespace => "namespace",
+            CIEnvironment::Concourse => "concourse",
+            CIEnvironment::AwsWebIdentity => "aws",
+            CIEnvironment::Kubernetes => "kubernetes",
+            CIEnvironment::Spiffe => "spiffe",
+            CIEnvironment::GitHubCodespaces => "codespaces",
+        }
+    }
+
+    /// Returns the well-known issuers of the environment's hosted service, e.g.
+    /// `https://token.actions.githubusercontent.com` for GitHub Actions.
+    ///
+    /// Environments where the issuer depends on the instance, account or cluster (e.g. Jenkins,
+    /// Kubernetes or SPIFFE) have no well-known issuers: see [`Self::expected_issuer`] for the
+    /// issuer in the current environment.
+    ///
+    /// ```
+    /// use ci_id::CIEnvironment;
+    ///
+    /// for env in CIEnvironment::ALL {
+    ///     for issuer in env.known_issuers() {
+    ///         println!("{}: {}", env, issuer);
+    ///     }
+    /// }
+    /// ```
+    pub const fn known_issuers(self) -> &'static [&'static str] {
+        match self {
+            CIEnvironment::GitHubActions => &["https://token.actions.githubusercontent.com"],
+            CIEnvironment::GitLab => &["https://gitlab.com"],
+            CIEnvironment::Buildkite => &["https://agent.buildkite.com"],
+            CIEnvironment::Codefresh => &["https://oidc.codefresh.io"],
+            CIEnvironment::HcpTerraform => &["https://app.terraform.io"],
+            CIEnvironment::PulumiDeployments => &["https://api.pulumi.com/oidc"],
+            CIEnvironment::Vercel => &["https://oidc.vercel.com"],
+            CIEnvironment::Depot => &["https://oidc.depot.dev"],
+            _ => &[],
+        }
+    }
+
+    /// Returns the issuer that tokens in the current environment are expected to have, taking
+    /// self-hosted instances into account: e.g. the GitLab instance URL from `CI_SERVER_URL`.
+    /// Returns None if the issuer can not be determined.
+    ///
+    /// The result is based on the environment variables and is only meaningful when the
+    /// environment is present.
+    pub fn expected_issuer(self) -> Option<String> {
+        match self {
+            CIEnvironment::GitHubActions => Some(github_issuer()),
+            CIEnvironment::GitLab => Some(match env_var("CI_SERVER_URL") {
+                Ok(url) => url.trim_end_matches('/').into(),
+                Err(_) => "https://gitlab.com".into(),
+            }),
+            env => match env.known_issuers() {
+                [issuer] => Some(issuer.to_string()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the environment variable that marks the environment as present
+    const fn marker(self) -> &'static str {
+        match self {
+            CIEnvironment::ForgejoActions => "FORGEJO_ACTIONS",
+            CIEnvironment::GiteaActions => "GITEA_ACTIONS",
+            CIEnvironment::GitHubActions => "GITHUB_ACTIONS",
+            CIEnvironment::GitLab => "GITLAB_CI",
+            CIEnvironment::CircleCI => "CIRCLECI",
+            CIEnvironment::Buildkite => "BUILDKITE",
+            CIEnvironment::Jenkins => "JENKINS_URL",
+            CIEnvironment::TeamCity => "TEAMCITY_VERSION",
+            CIEnvironment::Codefresh => "CF_BUILD_ID",
+            CIEnvironment::Harness => "HARNESS_BUILD_ID",
+            CIEnvironment::Bitrise => "BITRISE_IO",
+            CIEnvironment::Spacelift => "TF_VAR_spacelift_run_id",
+            CIEnvironment::Env0 => "ENV0_ENVIRONMENT_ID",
+            CIEnvironment::HcpTerraform => "TFC_RUN_ID",
+            CIEnvironment::PulumiDeployments => "PULUMI_DEPLOYMENT_ID",
+            CIEnvironment::Vercel => "VERCEL",
+            CIEnvironment::Depot => "DEPOT_PROJECT_ID",
+            CIEnvironment::Garnix => "GARNIX_CI",
+            CIEnvironment::FlyIo => "FLY_MACHINE_ID",
+            CIEnvironment::Railway => "RAILWAY_ENVIRONMENT_ID",
+            CIEnvironment::Scaleway => "SCW_JOB_RUN_ID",
+            CIEnvironment::Namespace => "NSC_INSTANCE_ID",
+            CIEnvironment::Concourse => "CONCOURSE_ID_TOKEN",
+            CIEnvironment::AwsWebIdentity => "AWS_WEB_IDENTITY_TOKEN_FILE",
+            CIEnvironment::Kubernetes => "KUBERNETES_SERVICE_HOST",
+            CIEnvironment::Spiffe => "SPIFFE_ENDPOINT_SOCKET",
+            CIEnvironment::GitHubCodespaces => "CODESPACES",
+        }
+    }
+
+    /// Returns the detector function, or None if the detector is disabled at build time
+    fn detect_fn(self) -> Option<DetectFn> {
+        match self {
+            #[cfg(feature = "forgejo")]
+            CIEnvironment::ForgejoActions => Some(detect_forgejo),
+            #[cfg(feature = "gitea")]
+            CIEnvironment::GiteaActions => Some(detect_gitea),
+            #[cfg(feature = "github")]
+            CIEnvironment::GitHubActions => Some(detect_github),
+            #[cfg(feature = "gitlab")]
+            CIEnvironment::GitLab => Some(detect_gitlab),
+            #[cfg(feature = "circleci")]
+            CIEnvironment::CircleCI => Some(detect_circleci),
+            #[cfg(feature = "buildkite")]
+            CIEnvironment::Buildkite => Some(detect_buildkite),
+            #[cfg(feature = "jenkins")]
+            CIEnvironment::Jenkins => Some(detect_jenkins),
+            #[cfg(feature = "teamcity")]
+            CIEnvironment::TeamCity => Some(detect_teamcity),
+            #[cfg(feature = "codefresh")]
+            CIEnvironment::Codefresh => Some(detect_codefresh),
+            #[cfg(feature = "harness")]
+            CIEnvironment::Harness => Some(detect_harness),
+            #[cfg(feature = "bitrise")]
+            CIEnvironment::Bitrise => Some(detect_bitrise),
+            #[cfg(feature = "spacelift")]
+            CIEnvironment::Spacelift => Some(detect_spacelift),
+            #[cfg(feature = "env0")]
+            CIEnvironment::Env0 => Some(detect_env0),
+            #[cfg(feature = "terraform")]
+            CIEnvironment::HcpTerraform => Some(detect_terraform),
+            #[cfg(feature = "pulumi")]
+            CIEnvironment::PulumiDeployments => Some(detect_pulumi),
+            #[cfg(feature = "vercel")]
+            CIEnvironment::Vercel => Some(detect_vercel),
+            #[cfg(feature = "depot")]
+            CIEnvironment::Depot => Some(detect_depot),
+            #[cfg(feature = "garnix")]
+            CIEnvironment::Garnix => Some(detect_garnix),
+            #[cfg(feature = "fly")]
+            CIEnvironment::FlyIo => Some(detect_fly),
+            #[cfg(feature = "railway")]
+            CIEnvironment::Railway => Some(detect_railway),
+            #[cfg(feature = "scaleway")]
+            CIEnvironment::Scaleway => Some(detect_scaleway),
+            #[cfg(feature = "namespace")]
+            CIEnvironment::Namespace => Some(detect_namespace),
+            #[cfg(feature = "concourse")]
+            CIEnvironment::Concourse => Some(detect_concourse),
+            #[cfg(feature = "aws")]
+            CIEnvironment::AwsWebIdentity => Some(detect_aws),
+            #[cfg(feature = "kubernetes")]
+            CIEnvironment::Kubernetes => Some(detect_kubernetes),
+            #[cfg(feature = "spiffe")]
+            CIEnvironment::Spiffe => Some(detect_spiffe),
+            #[cfg(feature = "codespaces")]
+            CIEnvironment::GitHubCodespaces => Some(detect_codespaces),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Returns true if the detector for the environment is included in this build, see the
+    /// [detector features](crate#detector-features)
+    pub const fn is_enabled(self) -> bool {
+        match self {
+            CIEnvironment::ForgejoActions => cfg!(feature = "forgejo"),
+            CIEnvironment::GiteaActions => cfg!(feature = "gitea"),
+            CIEnvironment::GitHubActions => cfg!(feature = "github"),
+            CIEnvironment::GitLab => cfg!(feature = "gitlab"),
+            CIEnvironment::CircleCI => cfg!(feature = "circleci"),
+            CIEnvironment::Buildkite => cfg!(feature = "buildkite"),
+            CIEnvironment::Jenkins => cfg!(feature = "jenkins"),
+            CIEnvironment::TeamCity => cfg!(feature = "teamcity"),
+            CIEnvironment::Codefresh => cfg!(feature = "codefresh"),
+            CIEnvironment::Harness => cfg!(feature = "harness"),
+            CIEnvironment::Bitrise => cfg!(feature = "bitrise"),
+            CIEnvironment::Spacelift => cfg!(feature = "spacelift"),
+            CIEnvironment::Env0 => cfg!(feature = "env0"),
+            CIEnvironment::HcpTerraform => cfg!(feature = "terraform"),
+            CIEnvironment::PulumiDeployments => cfg!(feature = "pulumi"),
+            CIEnvironment::Vercel => cfg!(feature = "vercel"),
+            CIEnvironment::Depot => cfg!(feature = "depot"),
+            CIEnvironment::Garnix => cfg!(feature = "garnix"),
+            CIEnvironment::FlyIo => cfg!(feature = "fly"),
+            CIEnvironment::Railway => cfg!(feature = "railway"),
+            CIEnvironment::Scaleway => cfg!(feature = "scaleway"),
+            CIEnvironment::Namespace => cfg!(feature = "namespace"),
+            CIEnvironment::Concourse => cfg!(feature = "concourse"),
+            CIEnvironment::AwsWebIdentity => cfg!(feature = "aws"),
+            CIEnvironment::Kubernetes => cfg!(feature = "kubernetes"),
+            CIEnvironment::Spiffe => cfg!(feature = "spiffe"),
+            CIEnvironment::GitHubCodespaces => cfg!(feature = "codespaces"),
+        }
+    }
+
+    /// Returns true if the environment is present. This only checks the environment markers:
+    /// it does not mean that a token is available.
+    pub fn is_present(self) -> bool {
+        let set = |name| env_var(name).is_ok();
+        match self {
+            // Gitea and Forgejo runners also set GITHUB_ACTIONS, Forgejo runners may also set
+            // GITEA_ACTIONS
+            CIEnvironment::GitHubActions => {
+                set("GITHUB_ACTIONS") && !set("GITEA_ACTIONS") && !set("FORGEJO_ACTIONS")
             }
+            CIEnvironment::GiteaActions => set("GITEA_ACTIONS") && !set("FORGEJO_ACTIONS"),
+            env => set(env.marker()),
         }
     }
 }
 
-fn detect_buildkite(audience: Option<&str>) -> Result<String> {
-    if env::var("BUILDKITE").is_err() {
-        return Err(CIIDError::EnvironmentNotDetected);
-    };
+/// How a detector supports the requested audience, see [`ProviderInfo`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum AudienceSupport {
+    /// Tokens are issued for the requested audience
+    Requested,
+    /// A token must be configured for each audience, e.g. GitLab ID tokens in the pipeline
+    Configured,
+    /// The environment decides the token audience and a different audience cannot be
+    /// requested: if an audience is requested, it must match the token audience
+    Fixed,
+    /// The environment does not provide tokens
+    Unsupported,
+}
 
-    let args = match audience {
-        Some(audience) => vec!["oidc", "request-token", "--audience", audience],
-        None => vec!["oidc", "request-token"],
+/// Static information about a supported environment and its detector, see [`PROVIDERS`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct ProviderInfo {
+    pub environment: CIEnvironment,
+    /// Short lowercase id, see [`CIEnvironment::id`]
+    pub id: &'static str,
+    /// Human readable name, see [`CIEnvironment::name`]
+    pub name: &'static str,
+    /// Environment variable that marks the environment as present
+    pub marker: &'static str,
+    /// The detector may request the token over the network
+    pub network: bool,
+    /// The detector may run a helper program
+    pub subprocess: bool,
+    /// The ci-id documentation for setting up the environment
+    pub docs_url: &'static str,
+    pub audience: AudienceSupport,
+}
+
+/// Information about all supported environments in the order they are probed, e.g. for listing
+/// the supported environments in a CLI or in generated documentation.
+///
+/// ```
+/// for provider in ci_id::PROVIDERS {
+///     println!("{} ({}): detected with {}", provider.name, provider.id, provider.marker);
+/// }
+/// ```
+pub const PROVIDERS: &[ProviderInfo] = &[
+    CIEnvironment::ForgejoActions.info(),
+    CIEnvironment::GiteaActions.info(),
+    CIEnvironment::GitHubActions.info(),
+    CIEnvironment::GitLab.info(),
+    CIEnvironment::CircleCI.info(),
+    CIEnvironment::Buildkite.info(),
+    CIEnvironment::Jenkins.info(),
+    CIEnvironment::TeamCity.info(),
+    CIEnvironment::Codefresh.info(),
+    CIEnvironment::Harness.info(),
+    CIEnvironment::Bitrise.info(),
+    CIEnvironment::Spacelift.info(),
+    CIEnvironment::Env0.info(),
+    CIEnvironment::HcpTerraform.info(),
+    CIEnvironment::PulumiDeployments.info(),
+    CIEnvironment::Vercel.info(),
+    CIEnvironment::Depot.info(),
+    CIEnvironment::Garnix.info(),
+    CIEnvironment::FlyIo.info(),
+    CIEnvironment::Railway.info(),
+    CIEnvironment::Scaleway.info(),
+    CIEnvironment::Namespace.info(),
+    CIEnvironment::Concourse.info(),
+    CIEnvironment::AwsWebIdentity.info(),
+    CIEnvironment::Kubernetes.info(),
+    CIEnvironment::Spiffe.info(),
+    CIEnvironment::GitHubCodespaces.info(),
+];
+
+impl CIEnvironment {
+    /// Returns the static information about the environment, see [`PROVIDERS`]
+    pub const fn info(self) -> ProviderInfo {
+        let (network, subprocess) = match self {
+            CIEnvironment::ForgejoActions
+            | CIEnvironment::GiteaActions
+            | CIEnvironment::GitHubActions
+            | CIEnvironment::Codefresh => (true, false),
+            // buildkite-agent, or the agent API if it is not available
+            CIEnvironment::Buildkite => (true, true),
+            CIEnvironment::CircleCI | CIEnvironment::Namespace | CIEnvironment::Spiffe => {
+                (false, true)
+            }
+            _ => (false, false),
+        };
+        ProviderInfo {
+            environment: self,
+            id: self.id(),
+            name: self.name(),
+            marker: self.marker(),
+            network,
+            subprocess,
+            docs_url: self.docs_url(),
+            audience: self.audience_support(),
+        }
+    }
+
+    const fn docs_url(self) -> &'static str {
+        match self {
+            CIEnvironment::ForgejoActions => {
+                "https://docs.rs/ci-id/latest/ci_id/#gitea-actions-and-forgejo-actions"
+            }
+            CIEnvironment::GiteaActions => {
+                "https://docs.rs/ci-id/latest/ci_id/#gitea-actions-and-forgejo-actions"
+            }
+            CIEnvironment::GitHubActions => "https://docs.rs/ci-id/latest/ci_id/#github-actions",
+            CIEnvironment::GitLab => "https://docs.rs/ci-id/latest/ci_id/#gitlab-pipelines",
+            CIEnvironment::CircleCI => "https://docs.rs/ci-id/latest/ci_id/#circleci",
+            CIEnvironment::Buildkite => "https://docs.rs/ci-id/latest/ci_id/#buildkite",
+            CIEnvironment::Jenkins => "https://docs.rs/ci-id/latest/ci_id/#jenkins",
+            CIEnvironment::TeamCity => "https://docs.rs/ci-id/latest/ci_id/#teamcity",
+            CIEnvironment::Codefresh => "https://docs.rs/ci-id/latest/ci_id/#codefresh",
+            CIEnvironment::Harness => "https://docs.rs/ci-id/latest/ci_id/#harness",
+            CIEnvironment::Bitrise => "https://docs.rs/ci-id/latest/ci_id/#bitrise",
+            CIEnvironment::Spacelift => "https://docs.rs/ci-id/latest/ci_id/#spacelift",
+            CIEnvironment::Env0 => "https://docs.rs/ci-id/latest/ci_id/#env0",
+            CIEnvironment::HcpTerraform => "https://docs.rs/ci-id/latest/ci_id/#hcp-terraform",
+            CIEnvironment::PulumiDeployments => {
+                "https://docs.rs/ci-id/latest/ci_id/#pulumi-deployments"
+            }
+            CIEnvironment::Vercel => "https://docs.rs/ci-id/latest/ci_id/#vercel",
+            CIEnvironment::Depot => "https://docs.rs/ci-id/latest/ci_id/#depot",
+            CIEnvironment::Garnix => "https://docs.rs/ci-id/latest/ci_id/#garnix",
+            CIEnvironment::FlyIo => "https://docs.rs/ci-id/latest/ci_id/#flyio",
+            CIEnvironment::Railway => "https://docs.rs/ci-id/latest/ci_id/#railway",
+            CIEnvironment::Scaleway => {
+                "https://docs.rs/ci-id/latest/ci_id/#scaleway-serverless-jobs"
+            }
+            CIEnvironment::Namespace => "https://docs.rs/ci-id/latest/ci_id/#namespace",
+            CIEnvironment::Concourse => "https://docs.rs/ci-id/latest/ci_id/#concourse",
+            CIEnvironment::AwsWebIdentity => "https://docs.rs/ci-id/latest/ci_id/#aws-web-identity",
+            CIEnvironment::Kubernetes => "https://docs.rs/ci-id/latest/ci_id/#kubernetes",
+            CIEnvironment::Spiffe => "https://docs.rs/ci-id/latest/ci_id/#spiffe",
+            CIEnvironment::GitHubCodespaces => {
+                "https://docs.rs/ci-id/latest/ci_id/#github-codespaces"
+            }
+        }
+    }
+
+    const fn audience_support(self) -> AudienceSupport {
+        match self {
+            CIEnvironment::ForgejoActions
+            | CIEnvironment::GiteaActions
+            | CIEnvironment::GitHubActions
+            | CIEnvironment::CircleCI
+            | CIEnvironment::Buildkite
+            | CIEnvironment::Codefresh
+            | CIEnvironment::FlyIo
+            | CIEnvironment::Namespace
+            | CIEnvironment::Spiffe => AudienceSupport::Requested,
+            CIEnvironment::GitLab
+            | CIEnvironment::Jenkins
+            | CIEnvironment::TeamCity
+            | CIEnvironment::HcpTerraform
+            | CIEnvironment::Kubernetes => AudienceSupport::Configured,
+            CIEnvironment::Harness
+            | CIEnvironment::Bitrise
+            | CIEnvironment::Spacelift
+            | CIEnvironment::Env0
+            | CIEnvironment::PulumiDeployments
+            | CIEnvironment::Vercel
+            | CIEnvironment::Depot
+            | CIEnvironment::Garnix
+            | CIEnvironment::Railway
+            | CIEnvironment::Scaleway
+            | CIEnvironment::Concourse
+            | CIEnvironment::AwsWebIdentity => AudienceSupport::Fixed,
+            CIEnvironment::GitHubCodespaces => AudienceSupport::Unsupported,
+        }
+    }
+}
+
+impl fmt::Display for CIEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Returns the first supported environment that is present, without requesting a token.
+///
+/// ```
+/// if let Some(env) = ci_id::detect_environment() {
+///     println!("Running in {}", env);
+/// }
+/// ```
+pub fn detect_environment() -> Option<CIEnvironment> {
+    CIEnvironment::ALL
+        .iter()
+        .copied()
+        .find(|env| env.is_present())
+}
+
+/// Returns true if a supported environment that provides ambient credentials is present. This
+/// does not request a token: use it e.g. to choose between ambient credentials and an
+/// interactive login.
+///
+/// GitHub Codespaces is recognized by [`detect_environment`] but does not provide credentials,
+/// so `is_ci()` returns false there.
+pub fn is_ci() -> bool {
+    detect_environment().is_some_and(|env| env != CIEnvironment::GitHubCodespaces)
+}
+
+/// Returns the name of the supported environment that is present, see [`detect_environment`]
+pub fn environment_name() -> Option<&'static str> {
+    detect_environment().map(CIEnvironment::name)
+}
+
+/// A source of identity tokens probed during credential detection.
+///
+/// Applications can implement this for their own token sources and pass them to
+/// [`detect_credentials_with`]. Like the built-in detectors, `detect` should return
+/// [`CIIDError::EnvironmentNotDetected`] if the token source is not present so that probing can
+/// continue.
+///
+/// ```
+/// use ci_id::{CIIDError, Detector};
+///
+/// struct InternalCI;
+///
+/// impl Detector for InternalCI {
+///     fn name(&self) -> &str {
+///         "Internal CI"
+///     }
+///
+///     fn detect(&self, _audience: Option<&str>) -> ci_id::Result<String> {
+///         std::env::var("INTERNAL_CI_ID_TOKEN").map_err(|_| CIIDError::EnvironmentNotDetected)
+///     }
+/// }
+///
+/// let token = ci_id::detect_credentials_with(&[&InternalCI], Some("my-audience"));
+/// ```
+pub trait Detector: Send + Sync {
+    /// Name used in log and error messages
+    fn name(&self) -> &str;
+
+    /// Returns an identity token for the audience
+    fn detect(&self, audience: Option<&str>) -> Result<String>;
+}
+
+/// A built-in detector: a supported environment or one of the generic token sources
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Provider {
+    /// A supported environment
+    Environment(CIEnvironment),
+    /// Token in `CI_ID_TOKEN` environment variable
+    EnvironmentVariable,
+    /// Token in a file pointed to by `CI_ID_TOKEN_FILE`
+    TokenFile,
+    /// Token as the output of `CI_ID_TOKEN_COMMAND`
+    TokenCommand,
+}
+
+impl Provider {
+    /// Built-in detectors in the order they are probed
+    pub const ALL: &'static [Provider] = &[
+        Provider::Environment(CIEnvironment::ForgejoActions),
+        Provider::Environment(CIEnvironment::GiteaActions),
+        Provider::Environment(CIEnvironment::GitHubActions),
+        Provider::Environment(CIEnvironment::GitLab),
+        Provider::Environment(CIEnvironment::CircleCI),
+        Provider::Environment(CIEnvironment::Buildkite),
+        Provider::Environment(CIEnvironment::Jenkins),
+        Provider::Environment(CIEnvironment::TeamCity),
+        Provider::Environment(CIEnvironment::Codefresh),
+        Provider::Environment(CIEnvironment::Harness),
+        Provider::Environment(CIEnvironment::Bitrise),
+        Provider::Environment(CIEnvironment::Spacelift),
+        Provider::Environment(CIEnvironment::Env0),
+        Provider::Environment(CIEnvironment::HcpTerraform),
+        Provider::Environment(CIEnvironment::PulumiDeployments),
+        Provider::Environment(CIEnvironment::Vercel),
+        Provider::Environment(CIEnvironment::Depot),
+        Provider::Environment(CIEnvironment::Garnix),
+        Provider::Environment(CIEnvironment::FlyIo),
+        Provider::Environment(CIEnvironment::Railway),
+        Provider::Environment(CIEnvironment::Scaleway),
+        Provider::Environment(CIEnvironment::Namespace),
+        Provider::Environment(CIEnvironment::Concourse),
+        Provider::Environment(CIEnvironment::AwsWebIdentity),
+        Provider::Environment(CIEnvironment::Kubernetes),
+        Provider::Environment(CIEnvironment::Spiffe),
+        // Generic detectors are only used if no supported environment was found
+        Provider::EnvironmentVariable,
+        Provider::TokenFile,
+        Provider::TokenCommand,
+        // Codespaces never returns a token so it goes last
+        Provider::Environment(CIEnvironment::GitHubCodespaces),
+    ];
+
+    /// Returns the human readable detector name
+    pub const fn name(self) -> &'static str {
+        match self {
+            Provider::Environment(env) => env.name(),
+            Provider::EnvironmentVariable => "Environment variable",
+            Provider::TokenFile => "Token file",
+            Provider::TokenCommand => "Token command",
+        }
+    }
+
+    /// Returns the short lowercase detector id used in configuration like `CI_ID_PROVIDER`.
+    ///
+    /// The environment ids are "forgejo", "gitea", "github", "gitlab", "circleci",
+    /// "buildkite", "jenkins", "teamcity", "codefresh", "harness", "bitrise", "spacelift",
+    /// "env0", "terraform", "pulumi", "vercel", "depot", "garnix", "fly", "railway",
+    /// "scaleway", "namespace", "concourse", "aws", "kubernetes", "spiffe" and "codespaces". The
+    /// generic detectors are "env", "file" and "command".
+    pub const fn id(self) -> &'static str {
+        match self {
+            Provider::Environment(env) => env.id(),
+            Provider::EnvironmentVariable => "env",
+            Provider::TokenFile => "file",
+            Provider::TokenCommand => "command",
+        }
+    }
+
+    fn detect_fn(self) -> Option<DetectFn> {
+        match self {
+            Provider::Environment(env) => env.detect_fn(),
+            Provider::EnvironmentVariable => Some(detect_env_token),
+            Provider::TokenFile => Some(detect_token_file),
+            Provider::TokenCommand => Some(detect_token_command),
+        }
+    }
+}
+
+impl From<CIEnvironment> for Provider {
+    fn from(env: CIEnvironment) -> Self {
+        Provider::Environment(env)
+    }
+}
+
+/// Parses a detector id (see [`Provider::id`]), ignoring case
+impl std::str::FromStr for Provider {
+    type Err = CIIDError;
+
+    fn from_str(id: &str) -> Result<Self> {
+        Provider::ALL
+            .iter()
+            .copied()
+            .find(|provider| provider.id().eq_ignore_ascii_case(id.trim()))
+            .ok_or_else(|| CIIDError::EnvironmentError(format!("Unknown provider '{}'", id)))
+    }
+}
+
+/// Serializes the detector id, see [`Provider::id`]
+impl Serialize for Provider {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.id())
+    }
+}
+
+impl<'de> Deserialize<'de> for Provider {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serializes the environment id, see [`CIEnvironment::id`]
+impl Serialize for CIEnvironment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.id())
+    }
+}
+
+impl<'de> Deserialize<'de> for CIEnvironment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        match Provider::deserialize(deserializer)? {
+            Provider::Environment(env) => Ok(env),
+            provider => Err(serde::de::Error::custom(format!(
+                "'{}' is not a CI environment",
+                provider.id()
+            ))),
+        }
+    }
+}
+
+/// Returns the built-in detectors in probing order, as configured with `CI_ID_PROVIDER`,
+/// `CI_ID_DISABLE_<ID>` and the configuration file
+fn default_providers(config: &Config) -> Result<Vec<Provider>> {
+    let providers = match env_var("CI_ID_PROVIDER") {
+        Ok(id) if !id.is_empty() => {
+            let provider = id.parse::<Provider>().map_err(|_| {
+                CIIDError::EnvironmentError(format!("CI_ID_PROVIDER: Unknown provider '{}'", id))
+            })?;
+            log::debug!("CI_ID_PROVIDER: Only probing {}", provider);
+            vec![provider]
+        }
+        _ => config
+            .providers
+            .clone()
+            .unwrap_or_else(|| Provider::ALL.to_vec()),
     };
-    match Command::new("buildkite-agent").args(args).output() {
-        Ok(output) => match String::from_utf8(output.stdout) {
-            Ok(token) => Ok(token.trim_end().to_string()),
-            Err(_) => Err(CIIDError::EnvironmentError(
-                "Buildkite; Failed to read token".into(),
-            )),
-        },
-        Err(e) => Err(CIIDError::EnvironmentError(format!(
-            "Buildkite: Call to buildkite-agent failed: {}",
-            e
-        ))),
+    Ok(providers
+        .into_iter()
+        .filter(|provider| {
+            let var_name = format!("CI_ID_DISABLE_{}", provider.id().to_uppercase());
+            let reason = if env_flag(&var_name) {
+                format!("Disabled with {}", var_name)
+            } else if config.disable.contains(provider) {
+                "Disabled in configuration".into()
+            } else {
+                return true;
+            };
+            log::debug!("{}: {}", provider, reason);
+            instrument::probe_skipped(Provider::name(*provider), &reason);
+            false
+        })
+        .collect())
+}
+
+/// Returns the plugin detectors if enabled with `CI_ID_ENABLE_PLUGINS`, unless a single
+/// detector is forced with `CI_ID_PROVIDER`. Plugins are not used without the `subprocess`
+/// feature.
+fn default_plugins() -> Vec<PluginDetector> {
+    if !cfg!(feature = "subprocess")
+        || !env_flag("CI_ID_ENABLE_PLUGINS")
+        || env_var("CI_ID_PROVIDER").is_ok_and(|id| !id.is_empty())
+    {
+        return vec![];
     }
+    discover_plugins()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Returns the detectors probed by default: the custom detectors from the configuration file,
+/// the plugins and the built-in detectors, in probing order
+fn default_detectors(config: &Config) -> Result<Vec<Arc<dyn Detector>>> {
+    let providers = default_providers(config)?;
+    let configured = config.detectors.iter().cloned().map(|d| Arc::new(d) as _);
+    let plugged = default_plugins().into_iter().map(|d| Arc::new(d) as _);
+    let builtin = providers
+        .into_iter()
+        .map(|provider| Arc::new(provider) as _);
+    Ok(configured.chain(plugged).chain(builtin).collect())
+}
 
-    use std::{
-        fs::{self, File},
-        io::Write,
-        os::unix::fs::PermissionsExt,
-        sync::{Mutex, MutexGuard},
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(Provider::name(*self))
+    }
+}
+
+impl Detector for Provider {
+    fn name(&self) -> &str {
+        Provider::name(*self)
+    }
+
+    fn detect(&self, audience: Option<&str>) -> Result<String> {
+        match self.detect_fn() {
+            Some(detect) => detect(audience),
+            None => Err(disabled_error(*self)),
+        }
+    }
+}
+
+/// Returns the error for a detector that is disabled at build time: the environment is reported
+/// as not detected unless it is present
+fn disabled_error(provider: Provider) -> CIIDError {
+    match provider {
+        Provider::Environment(env) if env.is_present() => CIIDError::EnvironmentError(format!(
+            "{}: Detector is disabled at build time, enable the ci-id '{}' feature",
+            env.name(),
+            env.id()
+        )),
+        _ => CIIDError::EnvironmentNotDetected,
+    }
+}
+
+/// Handles the result of a single detector: returns `None` if the environment was not detected
+/// and probing should continue
+fn detection_result(name: &str, result: Result<String>) -> Option<Result<DetectedCredential>> {
+    match result {
+        Ok(token) => {
+            let token = validate_token(token);
+            if let Ok(token) = &token {
+                log::debug!("{}: Token found ({})", name, token_fingerprint(token));
+            }
+            Some(token.map(|token| {
+                let mut credential = DetectedCredential::new(name, token);
+                credential.legacy_source = legacy_source(name, credential.token.expose());
+                credential
+            }))
+        }
+        Err(CIIDError::EnvironmentNotDetected) => {
+            log::debug!("{}: Environment not detected", name);
+            None
+        }
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Returns the deprecated variable that provided `token`, if the detector `name` has a legacy
+/// fallback that was used
+fn legacy_source(name: &str, token: &str) -> Option<String> {
+    let var_name = if name == CIEnvironment::CircleCI.name() {
+        "CIRCLE_OIDC_TOKEN"
+    } else if name == CIEnvironment::GitLab.name() {
+        "CI_JOB_JWT_V2"
+    } else {
+        return None;
     };
+    (legacy_tokens_allowed() && env_var(var_name).is_ok_and(|value| value == token))
+        .then(|| var_name.into())
+}
 
-    const TOKEN: &str = "eyJhbGciOiJSUzI1NiIsImtpZCI6IjMxNjA2OGMzM2ZhMjg2OTZhZmI5YzM5YWI2OTMxMjY1ZDk0Y2I3NTUifQ.eyJpc3MiOiJodHRwczovL29hdXRoMi5zaWdzdG9yZS5kZXYvYXV0aCIsInN1YiI6IkNnVXpNVGc0T1JJbWFIUjBjSE02SlRKR0pUSkdaMmwwYUhWaUxtTnZiU1V5Um14dloybHVKVEpHYjJGMWRHZyIsImF1ZCI6InNpZ3N0b3JlIiwiZXhwIjoxNzI5NTEyOTMwLCJpYXQiOjE3Mjk1MTI4NzAsIm5vbmNlIjoiNTI3NjM3Y2UtN2Q2MS00MDA5LThkM2EtNGNjZGM3OGJiZDg1IiwiYXRfaGFzaCI6IktmMUNPTXB5TVJDTkdzWWp1QXczclEiLCJlbWFpbCI6ImprdUBnb3RvLmZpIiwiZW1haWxfdmVyaWZpZWQiOnRydWUsImZlZGVyYXRlZF9jbGFpbXMiOnsiY29ubmVjdG9yX2lkIjoiaHR0cHM6Ly9naXRodWIuY29tL2xvZ2luL29hdXRoIiwidXNlcl9pZCI6IjMxODg5In19.s27uZ3vpIzRS4eWdC3pM0FSsYkHNvScQoii_TcSRVZhtrcPAbA4D95Pw_R_UB-qRquMK1BHepKmeN1b1-CQ00jiFZgUOf9sDLC3Hy3oQejGJsYKb-7oeHs7amLz3SBzPwDwVd09e-7Yu1x9YV5k6aezqruLLt42C_kyOTsHeCIWWMEVmGp32105Jkj8YT5uEYXS-aOEvQFvAYsDfKgGuiJtGybUycVcJEfqyWI3cami7fkjU5PcCx8oFyP2E7YNRw4UeNWCTn7WFtL2onrgDm0oa2AqF3gtH4Q-9ByksVq3y6xQdoLj1ydzWcoCzsF43oZ6O6DkLmWk5fu3FxNyewg";
+/// Returns detected OIDC identity token.
+///
+/// The supported environments are probed in order, the identity token
+/// for the first found environment is returned. If detection fails in a found environment,
+/// probing continues: when no environment provides a token, the error from the found
+/// environment (or [`CIIDError::DetectionFailed`] if several were found) is returned.
+///
+/// ```
+/// match ci_id::detect_credentials(Some("my-audience")) {
+///     Ok(token) => println!("{}", token.expose()),
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// ```
+pub fn detect_credentials(audience: Option<&str>) -> Result<SecretToken> {
+    detect_credential(audience).map(|credential| credential.token)
+}
+
+/// Returns detected OIDC identity token along with the detector name and the unverified token
+/// claims.
+///
+/// ```
+/// match ci_id::detect_credential(Some("my-audience")) {
+///     Ok(credential) => {
+///         println!("Token from {} expires {:?}", credential.provider, credential.expiry)
+///     }
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// ```
+pub fn detect_credential(audience: Option<&str>) -> Result<DetectedCredential> {
+    detect_credential_with(&[], audience)
+}
+
+/// Returns detected OIDC identity token like [`detect_credentials`], retrying detection for up
+/// to `timeout` if it fails.
+///
+/// This is meant for jobs that may start before the token source is ready, e.g. before an agent
+/// socket exists or a metadata service responds. Detection is retried every second: when
+/// `timeout` has passed, the error from the last attempt is returned. Errors that waiting does
+/// not fix ([`CIIDError::MissingPermission`], [`CIIDError::AudienceMismatch`],
+/// [`CIIDError::MalformedToken`] and [`CIIDError::VerificationFailed`]) are returned
+/// immediately. With `Duration::MAX` detection is retried until it succeeds or fails
+/// permanently.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let token = ci_id::detect_credentials_or_wait(Some("my-audience"), Duration::from_secs(30));
+/// ```
+pub fn detect_credentials_or_wait(
+    audience: Option<&str>,
+    timeout: Duration,
+) -> Result<SecretToken> {
+    retry_until(timeout, WAIT_POLL_INTERVAL, || detect_credentials(audience))
+}
+
+/// Interval between detection attempts in [`detect_credentials_or_wait`]
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Calls `detect` every `interval` until it succeeds, fails permanently or `timeout` has passed.
+/// A timeout too large to represent as a deadline (e.g. `Duration::MAX`) means no timeout.
+fn retry_until<T>(
+    timeout: Duration,
+    interval: Duration,
+    mut detect: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        let err = match detect() {
+            Ok(value) => return Ok(value),
+            Err(e) if !e.is_transient() => return Err(e),
+            Err(e) => e,
+        };
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => interval,
+        };
+        if remaining.is_zero() {
+            return Err(err);
+        }
+        log::debug!("Detection failed, retrying: {}", err);
+        std::thread::sleep(interval.min(remaining));
+    }
+}
+
+/// Returns detected OIDC identity token, probing the custom `detectors` in order before the
+/// built-in detectors.
+pub fn detect_credentials_with(
+    detectors: &[&dyn Detector],
+    audience: Option<&str>,
+) -> Result<SecretToken> {
+    detect_credential_with(detectors, audience).map(|credential| credential.token)
+}
+
+/// Returns detected OIDC identity token along with the detector name and the unverified token
+/// claims, probing the custom `detectors` in order before the built-in detectors.
+pub fn detect_credential_with(
+    detectors: &[&dyn Detector],
+    audience: Option<&str>,
+) -> Result<DetectedCredential> {
+    let (config, _context) = config_context(load_config()?);
+    let defaults = default_detectors(&config)?;
+    probe(
+        detectors
+            .iter()
+            .copied()
+            .chain(defaults.iter().map(AsRef::as_ref)),
+        audience.or(config.audience.as_deref()),
+    )
+}
+
+/// Outcome of probing a single detector, see [`ProbeReport`]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ProbeOutcome {
+    /// The environment was not detected, probing continued
+    NotDetected,
+    /// The environment was detected but acquiring the token failed, probing continued
+    Failed(CIIDError),
+    /// The token was found, probing stopped
+    Succeeded,
+}
 
-    // Mutex for all tests that modify environment variables
-    lazy_static! {
-        static ref ENV_MUTEX: Mutex<()> = Mutex::new(());
+impl ProbeOutcome {
+    /// Returns the outcome of a probe with detection `result`
+    pub(crate) fn of(result: &Option<Result<DetectedCredential>>) -> Self {
+        match result {
+            Some(Ok(_)) => ProbeOutcome::Succeeded,
+            Some(Err(e)) => ProbeOutcome::Failed(e.clone()),
+            None => ProbeOutcome::NotDetected,
+        }
+    }
+}
+
+/// Diagnostics from credential detection: the probed detectors in probing order along with
+/// the outcome for each. Detectors after the successful one are not probed.
+///
+/// The report is meant for troubleshooting: the [`Display`](fmt::Display) implementation lists
+/// each probed detector on its own line.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct ProbeReport {
+    pub probes: Vec<(String, ProbeOutcome)>,
+}
+
+impl ProbeReport {
+    /// Returns the name of the detector that provided the token
+    pub fn succeeded(&self) -> Option<&str> {
+        self.probes
+            .iter()
+            .find(|(_, outcome)| *outcome == ProbeOutcome::Succeeded)
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Serializes the probes as a list of `{"detector": name, "outcome": outcome}` objects where
+/// outcome is "not_detected", "failed" or "succeeded". Failed probes include the error message
+/// in "error".
+impl Serialize for ProbeReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Probe<'a> {
+            detector: &'a str,
+            outcome: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            error: Option<String>,
+        }
+
+        serializer.collect_seq(self.probes.iter().map(|(name, outcome)| {
+            let (outcome, error) = match outcome {
+                ProbeOutcome::NotDetected => ("not_detected", None),
+                ProbeOutcome::Failed(e) => ("failed", Some(e.to_string())),
+                ProbeOutcome::Succeeded => ("succeeded", None),
+            };
+            Probe {
+                detector: name,
+                outcome,
+                error,
+            }
+        }))
     }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, outcome) in &self.probes {
+            match outcome {
+                ProbeOutcome::NotDetected => writeln!(f, "{}: not detected", name)?,
+                ProbeOutcome::Failed(e) => writeln!(f, "{}: {}", name, e)?,
+                ProbeOutcome::Succeeded => writeln!(f, "{}: token found", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns detected OIDC identity token like [`detect_credentials`] along with a report of the
+/// probed detectors. The report is returned also when detection fails.
+///
+/// ```
+/// let (result, report) = ci_id::detect_credentials_with_report(Some("my-audience"));
+/// if result.is_err() {
+///     eprint!("Probed detectors:\n{}", report);
+/// }
+/// ```
+pub fn detect_credentials_with_report(
+    audience: Option<&str>,
+) -> (Result<SecretToken>, ProbeReport) {
+    let (result, report) = detect_credential_with_report(audience);
+    (result.map(|credential| credential.token), report)
+}
+
+/// Returns detected OIDC identity token along with the detector name and the unverified token
+/// claims, and a report of the probed detectors: see [`detect_credentials_with_report`]
+pub fn detect_credential_with_report(
+    audience: Option<&str>,
+) -> (Result<DetectedCredential>, ProbeReport) {
+    let mut report = ProbeReport::default();
+    let (config, _context) = match load_config() {
+        Ok(config) => config_context(config),
+        Err(e) => return (Err(e), report),
+    };
+    let detectors = match default_detectors(&config) {
+        Ok(detectors) => detectors,
+        Err(e) => return (Err(e), report),
+    };
+    let result = probe_with_report(
+        detectors.iter().map(AsRef::as_ref),
+        audience.or(config.audience.as_deref()),
+        &mut report,
+    );
+    (result, report)
+}
+
+/// Returns OIDC identity token from a specific provider without probing other detectors.
+///
+/// This fails with [`CIIDError::EnvironmentError`] if the provider is not present, so a
+/// pipeline that expects e.g. GitHub Actions credentials never falls back to another token
+/// source.
+///
+/// ```
+/// use ci_id::CIEnvironment;
+///
+/// match ci_id::detect_credentials_from(CIEnvironment::GitHubActions, Some("my-audience")) {
+///     Ok(token) => println!("{}", token.expose()),
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// ```
+pub fn detect_credentials_from(
+    provider: impl Into<Provider>,
+    audience: Option<&str>,
+) -> Result<SecretToken> {
+    detect_credential_from(provider, audience).map(|credential| credential.token)
+}
+
+/// Returns OIDC identity token from a specific provider along with the unverified token claims,
+/// see [`detect_credentials_from`]
+pub fn detect_credential_from(
+    provider: impl Into<Provider>,
+    audience: Option<&str>,
+) -> Result<DetectedCredential> {
+    let provider = provider.into();
+    let (_, _context) = config_context(load_config()?);
+    match record_probe(provider.name(), || Detector::detect(&provider, audience)) {
+        Some(result) => result,
+        None => Err(CIIDError::EnvironmentError(format!(
+            "{}: Environment not detected",
+            provider
+        ))),
+    }
+}
+
+/// Probes every detector that [`detect_credential`] probes and returns the results for all
+/// detectors whose environment is present, by detector name in probing order.
+///
+/// Unlike [`detect_credential`], probing does not stop at the first token or error: detectors
+/// that do not detect their environment are left out, the others are included with either the
+/// credential or the error. An error is only returned if the configuration (e.g.
+/// `CI_ID_PROVIDER`) is invalid.
+///
+/// ```
+/// # fn main() -> ci_id::Result<()> {
+/// for (detector, result) in ci_id::detect_all_credentials(Some("my-audience"))? {
+///     match result {
+///         Ok(credential) => println!("{}: token expires {:?}", detector, credential.expiry),
+///         Err(e) => eprintln!("{}", e),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn detect_all_credentials(
+    audience: Option<&str>,
+) -> Result<Vec<(String, Result<DetectedCredential>)>> {
+    let (config, _context) = config_context(load_config()?);
+    let audience = audience.or(config.audience.as_deref());
+    let detectors = default_detectors(&config)?;
+    Ok(detectors
+        .iter()
+        .filter_map(|detector| {
+            let result = record_probe(detector.name(), || detector.detect(audience));
+            result.map(|result| (detector.name().to_string(), result))
+        })
+        .collect())
+}
+
+/// Returns OIDC identity tokens for multiple audiences, in the same order as `audiences`.
+///
+/// The detector that returned the previous token is probed first for each audience. If that
+/// detector does not return a token for an audience, the other detectors are probed as in
+/// [`detect_credentials`].
+///
+/// ```
+/// match ci_id::detect_credentials_multi(&["sigstore", "sts.amazonaws.com"]) {
+///     Ok(tokens) => println!("{} tokens found", tokens.len()),
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// ```
+pub fn detect_credentials_multi(audiences: &[&str]) -> Result<Vec<SecretToken>> {
+    let (config, _context) = config_context(load_config()?);
+    let detectors = default_detectors(&config)?;
+    let mut previous: Option<&dyn Detector> = None;
+    let mut tokens = vec![];
+    for audience in audiences.iter().copied() {
+        let others = detectors
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|detector| previous.is_none_or(|previous| previous.name() != detector.name()));
+        let credential = probe(previous.into_iter().chain(others), Some(audience))?;
+        previous = detectors
+            .iter()
+            .map(AsRef::as_ref)
+            .find(|detector| detector.name() == credential.provider);
+        tokens.push(credential.token);
+    }
+    Ok(tokens)
+}
+
+/// Builder for credential detection with options.
+///
+/// ```
+/// use ci_id::{CIEnvironment, CredentialRequest};
+/// use std::time::Duration;
+///
+/// let result = CredentialRequest::new()
+///     .audience("my-audience")
+///     .providers([CIEnvironment::GitHubActions, CIEnvironment::GitLab])
+///     .timeout(Duration::from_secs(10))
+///     .detect();
+/// match result {
+///     Ok(credential) => println!("{}", credential.token.expose()),
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CredentialRequest {
+    audience: Option<String>,
+    providers: Option<Vec<Provider>>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    user_agent: Option<String>,
+    proxy: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+    no_built_in_roots: bool,
+    cache: bool,
+    check_audience: bool,
+    min_validity: Option<Duration>,
+    parallel: bool,
+    circleci_claims: serde_json::Map<String, serde_json::Value>,
+    buildkite_lifetime: Option<Duration>,
+    buildkite_claims: Vec<String>,
+    gitlab_token_variable: Option<String>,
+    #[cfg(feature = "verify")]
+    verify_issuer: Option<String>,
+}
+
+/// Cached tokens are not returned if they expire within this time
+const CACHE_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+type TokenCache = HashMap<(String, Option<String>), DetectedCredential>;
+
+/// Detection state of the calling thread (see [`with_env`], [`with_transport`] and
+/// [`with_command_runner`]) for the probe threads of [`CredentialRequest::parallel`] and the
+/// blocking tasks of the async API
+struct ThreadState {
+    env: Option<Arc<dyn EnvReader>>,
+    #[cfg(feature = "http")]
+    transport: Option<Arc<dyn HttpTransport>>,
+    runner: Option<Arc<dyn CommandRunner>>,
+    context: RequestContext,
+}
+
+impl ThreadState {
+    fn capture() -> Self {
+        ThreadState {
+            env: current_env(),
+            #[cfg(feature = "http")]
+            transport: HTTP_TRANSPORT.with_borrow(|transport| transport.clone()),
+            runner: COMMAND_RUNNER.with_borrow(|runner| runner.clone()),
+            context: REQUEST_CONTEXT.with_borrow(|context| context.clone()),
+        }
+    }
+
+    /// Sets the state on the current thread: only used on the probe threads
+    fn install(self) {
+        ENV_READER.set(self.env);
+        #[cfg(feature = "http")]
+        HTTP_TRANSPORT.set(self.transport);
+        COMMAND_RUNNER.set(self.runner);
+        REQUEST_CONTEXT.set(self.context);
+    }
+
+    /// Runs `f` with the state set on the current thread, restoring the previous state
+    /// afterwards: used on threads that are shared with other tasks
+    #[cfg(feature = "async")]
+    fn scoped<T>(self, f: impl FnOnce() -> T) -> T {
+        struct Restore(Option<ThreadState>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                if let Some(previous) = self.0.take() {
+                    previous.install();
+                }
+            }
+        }
+
+        let _restore = Restore(Some(ThreadState::capture()));
+        self.install();
+        f()
+    }
+}
+
+/// Probes `detectors` concurrently. Returns the result like [`probe`] does with sequential
+/// probing, without waiting for the detectors after the one that provided the token.
+fn probe_parallel(
+    detectors: &[Arc<dyn Detector>],
+    audience: Option<&str>,
+) -> Result<DetectedCredential> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for (index, detector) in detectors.iter().cloned().enumerate() {
+        let sender = sender.clone();
+        let state = ThreadState::capture();
+        let audience = audience.map(String::from);
+        std::thread::spawn(move || {
+            state.install();
+            let _span = instrument::probe_span(detector.name());
+            let result = detector.detect(audience.as_deref());
+            // The receiver is gone if an earlier detector already provided the token
+            let _ = sender.send((index, result));
+        });
+    }
+    drop(sender);
+
+    // Results are handled and recorded in probe order: a result waits until the earlier
+    // detectors finish
+    let mut results: Vec<Option<Result<String>>> = vec![None; detectors.len()];
+    let mut next = 0;
+    let mut failures = vec![];
+    for (index, result) in receiver {
+        results[index] = Some(result);
+        while let Some(result) = results.get_mut(next).and_then(Option::take) {
+            let name = detectors[next].name();
+            next += 1;
+            match record_probe(name, || result) {
+                Some(Ok(credential)) => return Ok(credential),
+                Some(Err(e)) => {
+                    log::debug!("{}: {}", name, e);
+                    failures.push((name.to_string(), e));
+                }
+                None => {}
+            }
+        }
+    }
+    Err(CIIDError::from_failures(failures))
+}
+
+/// Tokens cached by [`CredentialRequest::cache`], keyed by detector name and audience
+static TOKEN_CACHE: Mutex<Option<TokenCache>> = Mutex::new(None);
+
+/// Removes all tokens from the cache used by [`CredentialRequest::cache`]
+pub fn clear_token_cache() {
+    *TOKEN_CACHE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+impl CredentialRequest {
+    /// Returns a request with default options: no audience, all built-in providers and no
+    /// timeout
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the requested token audience
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Probes only the given providers, in the given order. By default the detectors are the same
+    /// as with [`detect_credential`]: custom detectors from the configuration file, plugins and
+    /// the built-in detectors.
+    pub fn providers<P: Into<Provider>>(mut self, providers: impl IntoIterator<Item = P>) -> Self {
+        self.providers = Some(providers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Sets the timeout for HTTP token requests. The default is `CI_ID_HTTP_TIMEOUT` seconds
+    /// or 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends the HTTP token requests through the proxy at `url`. By default the proxies are set
+    /// with `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY`.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Trusts the root certificates in the PEM bundle `pem` for the HTTP token requests, in
+    /// addition to the built-in root certificates and `CI_ID_CA_BUNDLE`. This is needed e.g. for
+    /// a GitHub Enterprise Server or a GitLab instance behind TLS interception.
+    pub fn root_certificates(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Sets whether the built-in root certificates are trusted for the HTTP token requests. With
+    /// `false`, only the certificates set with [`root_certificates`](Self::root_certificates) and
+    /// `CI_ID_CA_BUNDLE` are trusted.
+    pub fn built_in_root_certificates(mut self, enabled: bool) -> Self {
+        self.no_built_in_roots = !enabled;
+        self
+    }
+
+    /// Sets the timeout for connecting to the token endpoint. The default is
+    /// `CI_ID_CONNECT_TIMEOUT` seconds or 10 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the User-Agent of the HTTP token requests. The default is `CI_ID_USER_AGENT` or
+    /// `ci-id/<version>`.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Enables the in-process token cache: a cached token for the same provider and audience is
+    /// returned if it is valid for at least another minute, otherwise a new token is detected
+    /// and cached. Tokens without an expiry time are not cached. Requests without `cache(true)`
+    /// bypass the cache, [`clear_token_cache`] empties it.
+    pub fn cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled;
+        self
+    }
+
+    /// Decodes the detected token and checks that its `aud` claim contains the requested
+    /// audience: a mismatch is returned as [`CIIDError::AudienceMismatch`]. This catches
+    /// misconfigured environments (e.g. a GitLab ID token with the wrong `aud`) early.
+    pub fn check_audience(mut self, enabled: bool) -> Self {
+        self.check_audience = enabled;
+        self
+    }
+
+    /// Requires the token to be valid for at least `min_validity`. A token that expires sooner
+    /// is requested once more, and if the new token is not valid long enough either,
+    /// [`CIIDError::TokenExpired`] or [`CIIDError::TokenTooShortLived`] is returned. Tokens
+    /// without an expiry time are accepted.
+    pub fn min_validity(mut self, min_validity: Duration) -> Self {
+        self.min_validity = Some(min_validity);
+        self
+    }
+
+    /// Probes the providers concurrently, each in its own thread, instead of one after another.
+    ///
+    /// The result is the same as with sequential probing: the first provider in probe order
+    /// that detects its environment is used, even if a later provider finishes first. Detection
+    /// returns as soon as the result is known: providers that are still running (e.g. waiting
+    /// for a token endpoint to time out) finish in the background. On WASM targets the providers
+    /// are probed sequentially.
+    pub fn parallel(mut self, enabled: bool) -> Self {
+        self.parallel = enabled;
+        self
+    }
+
+    /// Adds a custom claim to CircleCI token requests, e.g. a claim that the CircleCI
+    /// organization uses in its OIDC trust policies. The `aud` claim is set from the requested
+    /// audience. Custom claims require the `circleci` CLI, and tokens with custom claims are not
+    /// cached.
+    ///
+    /// ```
+    /// let request = ci_id::CredentialRequest::new()
+    ///     .audience("sigstore")
+    ///     .circleci_claim("deployment", "production");
+    /// ```
+    pub fn circleci_claim(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.circleci_claims.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the lifetime of Buildkite tokens, in whole seconds. The default is
+    /// `CI_ID_BUILDKITE_LIFETIME` seconds or the Buildkite default lifetime.
+    pub fn buildkite_lifetime(mut self, lifetime: Duration) -> Self {
+        self.buildkite_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Adds an optional claim to Buildkite tokens, e.g. "organization_id" or "pipeline_id". The
+    /// default claims are set with `CI_ID_BUILDKITE_CLAIMS`.
+    pub fn buildkite_claim(mut self, claim: impl Into<String>) -> Self {
+        self.buildkite_claims.push(claim.into());
+        self
+    }
+
+    /// Reads the GitLab ID token from the variable `name` instead of the variable derived from
+    /// the audience, e.g. for pipelines that name their ID tokens by a house convention. The
+    /// token is not checked against the requested audience: combine with
+    /// [`check_audience`](Self::check_audience) for that.
+    pub fn gitlab_token_variable(mut self, name: impl Into<String>) -> Self {
+        self.gitlab_token_variable = Some(name.into());
+        self
+    }
+
+    /// Verifies the token signature, audience and expiry before returning it, see
+    /// [`verify_token`]. `issuer` is the trusted issuer the token must be signed by.
+    #[cfg(feature = "verify")]
+    pub fn verify(mut self, issuer: impl Into<String>) -> Self {
+        self.verify_issuer = Some(issuer.into());
+        self
+    }
+
+    /// Returns the detected OIDC identity token along with the detector name and the unverified
+    /// token claims
+    pub fn detect(&self) -> Result<DetectedCredential> {
+        let config = Arc::new(load_config()?);
+        let detectors = match &self.providers {
+            Some(providers) => providers
+                .iter()
+                .map(|provider| Arc::new(*provider) as Arc<dyn Detector>)
+                .collect(),
+            None => default_detectors(&config)?,
+        };
+        let audience = self.audience.as_deref().or(config.audience.as_deref());
+        let _context = RequestContextGuard::new(RequestContext {
+            config: config.clone(),
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+            user_agent: self.user_agent.clone(),
+            proxy: self.proxy.clone(),
+            root_certificates: self.root_certificates.clone(),
+            no_built_in_roots: self.no_built_in_roots,
+            circleci_claims: self.circleci_claims.clone(),
+            buildkite_lifetime: self.buildkite_lifetime,
+            buildkite_claims: self.buildkite_claims.clone(),
+            gitlab_token_variable: self.gitlab_token_variable.clone(),
+        });
+        let cache = self.cache && !self.has_token_options();
+        if cache {
+            let margin = CACHE_EXPIRY_MARGIN.max(self.min_validity.unwrap_or_default());
+            if let Some(credential) = cached_credential(&detectors, audience, margin) {
+                log::debug!("{}: Using cached token", credential.provider);
+                return self.validate(credential, audience);
+            }
+        }
+
+        // WASM targets have no threads to probe in
+        let credential = if self.parallel && cfg!(not(target_family = "wasm")) {
+            probe_parallel(&detectors, audience)?
+        } else {
+            probe(detectors.iter().map(AsRef::as_ref), audience)?
+        };
+        let name = credential.provider.clone();
+        let credential = match self.validate(credential, audience) {
+            Err(CIIDError::TokenExpired | CIIDError::TokenTooShortLived { .. }) => {
+                log::debug!("{}: Token expires too soon, requesting again", name);
+                let detector = detectors.iter().find(|detector| detector.name() == name);
+                match detector.and_then(|d| record_probe(&name, || d.detect(audience))) {
+                    Some(result) => self.validate(result?, audience)?,
+                    None => return Err(CIIDError::EnvironmentNotDetected),
+                }
+            }
+            result => result?,
+        };
+        if cache && credential.expiry.is_some() {
+            let mut cache = TOKEN_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+            cache
+                .get_or_insert_with(HashMap::new)
+                .insert((name, audience.map(String::from)), credential.clone());
+        }
+        Ok(credential)
+    }
+
+    /// Returns true if provider specific options change the token: the cache is keyed by
+    /// provider and audience, so these tokens are not cached
+    fn has_token_options(&self) -> bool {
+        !self.circleci_claims.is_empty()
+            || !self.buildkite_claims.is_empty()
+            || self.gitlab_token_variable.is_some()
+            || self.buildkite_lifetime.is_some()
+    }
+
+    /// Applies the validation options to a detected credential. `audience` is the requested
+    /// audience, possibly from the configuration file.
+    fn validate(
+        &self,
+        credential: DetectedCredential,
+        audience: Option<&str>,
+    ) -> Result<DetectedCredential> {
+        if let (true, Some(audience)) = (self.check_audience, audience) {
+            let found = token_audiences(credential.token.expose()).unwrap_or_default();
+            if !found.iter().any(|found| found == audience) {
+                return Err(CIIDError::AudienceMismatch {
+                    expected: audience.into(),
+                    found,
+                });
+            }
+        }
+        if let (Some(min_validity), Some(expiry)) = (self.min_validity, credential.expiry) {
+            let now = SystemTime::now();
+            match expiry.duration_since(now) {
+                Err(_) | Ok(Duration::ZERO) => return Err(CIIDError::TokenExpired),
+                Ok(expires_in) if expires_in < min_validity => {
+                    return Err(CIIDError::TokenTooShortLived { expires_in })
+                }
+                Ok(_) => {}
+            }
+        }
+        #[cfg(feature = "verify")]
+        if let Some(issuer) = &self.verify_issuer {
+            verify_token(credential.token.expose(), issuer, audience)?;
+        }
+        Ok(credential)
+    }
+}
+
+/// Identity token source for long running jobs: [`CredentialProvider::token`] returns the current
+/// token and detects a new one when the current token is about to expire.
+///
+/// ```no_run
+/// use ci_id::{CredentialProvider, CredentialRequest};
+///
+/// let provider = CredentialProvider::new(CredentialRequest::new().audience("my-audience"));
+/// loop {
+///     let token = provider.token().unwrap();
+///     // use the token
+/// #   break;
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CredentialProvider {
+    request: CredentialRequest,
+    refresh_margin: Duration,
+    current: Mutex<Option<DetectedCredential>>,
+}
+
+impl CredentialProvider {
+    /// Returns a provider that detects tokens with `request`
+    pub fn new(request: CredentialRequest) -> Self {
+        CredentialProvider {
+            request,
+            refresh_margin: CACHE_EXPIRY_MARGIN,
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Sets how long before expiry the token is refreshed: the default is one minute
+    pub fn refresh_margin(mut self, margin: Duration) -> Self {
+        self.refresh_margin = margin;
+        self
+    }
+
+    /// Returns a token that is valid for at least the refresh margin. Tokens without an expiry
+    /// time are detected again on every call.
+    pub fn token(&self) -> Result<SecretToken> {
+        self.credential().map(|credential| credential.token)
+    }
+
+    /// Returns a credential that is valid for at least the refresh margin, see [`Self::token`]
+    pub fn credential(&self) -> Result<DetectedCredential> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(credential) = current.as_ref() {
+            match credential.expiry {
+                Some(expiry) if expiry > SystemTime::now() + self.refresh_margin => {
+                    return Ok(credential.clone());
+                }
+                _ => log::debug!("{}: Refreshing token", credential.provider),
+            }
+        }
+        let credential = self.request.detect()?;
+        *current = Some(credential.clone());
+        Ok(credential)
+    }
+}
+
+/// Returns the first cached token for `detectors` that is valid for at least `margin`
+fn cached_credential(
+    detectors: &[Arc<dyn Detector>],
+    audience: Option<&str>,
+    margin: Duration,
+) -> Option<DetectedCredential> {
+    let cache = TOKEN_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let valid_until = SystemTime::now().checked_add(margin)?;
+    detectors.iter().find_map(|detector| {
+        let credential = cache
+            .as_ref()?
+            .get(&(detector.name().to_string(), audience.map(String::from)))?;
+        match credential.expiry {
+            Some(expiry) if expiry > valid_until => Some(credential.clone()),
+            _ => None,
+        }
+    })
+}
+
+/// Probes the detectors in order, returns the first token found
+fn probe<'a>(
+    detectors: impl IntoIterator<Item = &'a dyn Detector>,
+    audience: Option<&str>,
+) -> Result<DetectedCredential> {
+    probe_with_report(detectors, audience, &mut ProbeReport::default())
+}
+
+/// Probes the detectors in order, returns the first token found. The outcome for each probed
+/// detector is added to `report`.
+fn probe_with_report<'a>(
+    detectors: impl IntoIterator<Item = &'a dyn Detector>,
+    audience: Option<&str>,
+    report: &mut ProbeReport,
+) -> Result<DetectedCredential> {
+    let mut failures = vec![];
+    for detector in detectors {
+        let name = detector.name();
+        let result = record_probe(name, || detector.detect(audience));
+        report
+            .probes
+            .push((name.to_string(), ProbeOutcome::of(&result)));
+        match result {
+            Some(Ok(credential)) => return Ok(credential),
+            Some(Err(e)) => {
+                log::debug!("{}: {}", name, e);
+                failures.push((name.to_string(), e));
+            }
+            None => {}
+        }
+    }
+
+    Err(CIIDError::from_failures(failures))
+}
+
+/// Probes detector `name` with `detect`, recording the probe with the progress callback, the
+/// metrics hook and a tracing span. All blocking detection probes through this.
+fn record_probe(
+    name: &str,
+    detect: impl FnOnce() -> Result<String>,
+) -> Option<Result<DetectedCredential>> {
+    let _span = instrument::probe_span(name);
+    instrument::probe_started(name);
+    let result = detection_result(name, detect());
+    instrument::probe_outcome(name, &ProbeOutcome::of(&result));
+    result
+}
+
+/// An ordered list of detectors to probe.
+///
+/// The default registry contains the built-in detectors in the default order. Detectors can be
+/// removed, reordered or added, e.g. when multiple environment markers are present and the
+/// default order picks the wrong one. Detectors are referred to by name: operations on names
+/// that are not in the registry do nothing.
+///
+/// ```
+/// use ci_id::{CIEnvironment, DetectorRegistry};
+///
+/// // Try GitLab before GitHub
+/// let registry = DetectorRegistry::new().move_before(
+///     CIEnvironment::GitLab.name(),
+///     CIEnvironment::GitHubActions.name(),
+/// );
+///
+/// // Only probe Buildkite
+/// let registry = DetectorRegistry::new().only(&[CIEnvironment::Buildkite.name()]);
+/// match registry.detect_credentials(Some("my-audience")) {
+///     Ok(token) => println!("{}", token.expose()),
+///     Err(e) => eprintln!("{}", e)
+/// }
+/// ```
+pub struct DetectorRegistry {
+    detectors: Vec<Box<dyn Detector>>,
+}
+
+impl DetectorRegistry {
+    /// Returns a registry with the built-in detectors
+    pub fn new() -> Self {
+        let detectors = Provider::ALL
+            .iter()
+            .map(|provider| Box::new(*provider) as Box<dyn Detector>)
+            .collect();
+        DetectorRegistry { detectors }
+    }
+
+    /// Returns a registry without any detectors
+    pub fn empty() -> Self {
+        DetectorRegistry { detectors: vec![] }
+    }
+
+    /// Returns the detector names in probing order
+    pub fn names(&self) -> Vec<&str> {
+        self.detectors
+            .iter()
+            .map(|detector| detector.name())
+            .collect()
+    }
+
+    /// Adds a detector to the end of the registry
+    pub fn push(mut self, detector: impl Detector + 'static) -> Self {
+        self.detectors.push(Box::new(detector));
+        self
+    }
+
+    /// Adds a detector to the start of the registry
+    pub fn push_front(mut self, detector: impl Detector + 'static) -> Self {
+        self.detectors.insert(0, Box::new(detector));
+        self
+    }
+
+    /// Removes the named detector
+    pub fn remove(mut self, name: &str) -> Self {
+        self.detectors.retain(|detector| detector.name() != name);
+        self
+    }
+
+    /// Removes all detectors except the named ones. The order of the remaining detectors is
+    /// not changed.
+    pub fn only(mut self, names: &[&str]) -> Self {
+        self.detectors
+            .retain(|detector| names.contains(&detector.name()));
+        self
+    }
+
+    /// Moves the named detector to the start of the registry
+    pub fn move_to_front(mut self, name: &str) -> Self {
+        if let Some(index) = self.position(name) {
+            let detector = self.detectors.remove(index);
+            self.detectors.insert(0, detector);
+        }
+        self
+    }
+
+    /// Moves detector `name` so that it is probed right before detector `before`
+    pub fn move_before(mut self, name: &str, before: &str) -> Self {
+        if let (Some(index), Some(_)) = (self.position(name), self.position(before)) {
+            let detector = self.detectors.remove(index);
+            let before_index = self.position(before).unwrap();
+            self.detectors.insert(before_index, detector);
+        }
+        self
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.detectors
+            .iter()
+            .position(|detector| detector.name() == name)
+    }
+
+    /// Returns detected OIDC identity token, probing the detectors in registry order
+    pub fn detect_credentials(&self, audience: Option<&str>) -> Result<SecretToken> {
+        self.detect_credential(audience)
+            .map(|credential| credential.token)
+    }
+
+    /// Returns detected OIDC identity token along with the detector name and the unverified
+    /// token claims, probing the detectors in registry order
+    pub fn detect_credential(&self, audience: Option<&str>) -> Result<DetectedCredential> {
+        let (_, _context) = config_context(load_config()?);
+        probe(
+            self.detectors.iter().map(|detector| detector.as_ref()),
+            audience,
+        )
+    }
+}
+
+impl Default for DetectorRegistry {
+    fn default() -> Self {
+        DetectorRegistry::new()
+    }
+}
+
+impl fmt::Debug for DetectorRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Source of environment variables for the detectors.
+///
+/// Detection reads the process environment by default: [`with_env`] runs detection against
+/// another environment, e.g. a captured snapshot.
+pub trait EnvReader: Send + Sync {
+    /// Returns the value of variable `name` if it is set
+    fn var(&self, name: &str) -> Option<String>;
+
+    /// Returns all variables
+    fn vars(&self) -> Vec<(String, String)>;
+}
+
+/// The process environment: the default [`EnvReader`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessEnv;
+
+impl EnvReader for ProcessEnv {
+    fn var(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+
+    /// Variables that are not valid unicode are skipped
+    fn vars(&self) -> Vec<(String, String)> {
+        env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect()
+    }
+}
+
+impl EnvReader for HashMap<String, String> {
+    fn var(&self, name: &str) -> Option<String> {
+        self.get(name).cloned()
+    }
+
+    fn vars(&self) -> Vec<(String, String)> {
+        self.iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+}
+
+thread_local! {
+    static ENV_READER: RefCell<Option<Arc<dyn EnvReader>>> = RefCell::default();
+}
+
+/// Runs `f` so that detection on this thread reads variables from `env` instead of the process
+/// environment.
+///
+/// Helper programs (like `buildkite-agent`) still run in the process environment. Async
+/// detection is only affected for the parts that run within `f`.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let env = HashMap::from([("CI_ID_TOKEN".to_string(), "a.b.c".to_string())]);
+/// let token = ci_id::with_env(env, || ci_id::detect_credentials(None));
+/// assert_eq!(token.unwrap().expose(), "a.b.c");
+/// ```
+pub fn with_env<T>(env: impl EnvReader + 'static, f: impl FnOnce() -> T) -> T {
+    with_thread_local(&ENV_READER, Arc::new(env), f)
+}
+
+/// Runs `f` with `value` set in the thread local `key`, restores the previous value afterwards
+fn with_thread_local<V: 'static, T>(
+    key: &'static std::thread::LocalKey<RefCell<Option<V>>>,
+    value: V,
+    f: impl FnOnce() -> T,
+) -> T {
+    struct Restore<V: 'static> {
+        key: &'static std::thread::LocalKey<RefCell<Option<V>>>,
+        previous: Option<V>,
+    }
+    impl<V> Drop for Restore<V> {
+        fn drop(&mut self) {
+            self.key.set(self.previous.take());
+        }
+    }
+
+    let _restore = Restore {
+        key,
+        previous: key.replace(Some(value)),
+    };
+    f()
+}
+
+/// Returns the environment set with [`with_env`], if any
+fn current_env() -> Option<Arc<dyn EnvReader>> {
+    ENV_READER.with_borrow(|env| env.clone())
+}
+
+/// Returns the value of variable `name` in the detection environment
+fn env_var(name: &str) -> std::result::Result<String, env::VarError> {
+    match current_env() {
+        Some(env) => env.var(name).ok_or(env::VarError::NotPresent),
+        None => env::var(name),
+    }
+}
+
+/// Returns all variables in the detection environment
+#[cfg(feature = "gitlab")]
+fn env_vars() -> Vec<(String, String)> {
+    current_env().unwrap_or_else(|| Arc::new(ProcessEnv)).vars()
+}
+
+/// Returns true if the option variable is set to "1" or "true"
+fn env_flag(name: &str) -> bool {
+    matches!(env_var(name).as_deref(), Ok("1") | Ok("true"))
+}
+
+/// Returns true if user has opted in to legacy token formats with CI_ID_ALLOW_LEGACY_TOKENS
+fn legacy_tokens_allowed() -> bool {
+    env_flag("CI_ID_ALLOW_LEGACY_TOKENS")
+}
+
+/// A HTTP request made by a detector, see [`HttpTransport`]
+///
+/// The `Debug` output does not include the `Authorization` header value or the body.
+#[cfg(feature = "http")]
+#[derive(Clone, PartialEq)]
+#[non_exhaustive]
+pub struct HttpRequest {
+    /// HTTP method, e.g. "GET"
+    pub method: &'static str,
+    /// The request URL including the query
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// A HTTP response, see [`HttpTransport`]
+///
+/// The `Debug` output does not include the body: it typically contains the token.
+#[cfg(feature = "http")]
+#[derive(Clone, PartialEq)]
+#[non_exhaustive]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[cfg(feature = "http")]
+impl fmt::Debug for HttpRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let headers: Vec<_> = self
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                if name.eq_ignore_ascii_case("authorization") {
+                    (*name, "<redacted>")
+                } else {
+                    (*name, value.as_str())
+                }
+            })
+            .collect();
+        f.debug_struct("HttpRequest")
+            .field("method", &self.method)
+            .field("url", &self.url)
+            .field("headers", &headers)
+            .field("body", &self.body.as_ref().map(|body| body.len()))
+            .finish()
+    }
+}
+
+#[cfg(feature = "http")]
+impl fmt::Debug for HttpResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpResponse")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "http")]
+impl HttpResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    /// Adds a response header
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// Sends the token requests of the HTTP based detectors.
+///
+/// By default the requests are sent with a reqwest blocking client built by ci-id:
+/// [`with_transport`] allows using another client (e.g. a `reqwest::blocking::Client`
+/// configured with a proxy) or mocking the token endpoints in tests. With the
+/// `custom-transport` feature and no TLS feature, the built-in client is not compiled and the
+/// requests are only sent with the transport.
+///
+/// ```
+/// use ci_id::{BoxError, HttpRequest, HttpResponse, HttpTransport};
+///
+/// struct Mock;
+///
+/// impl HttpTransport for Mock {
+///     fn send(&self, request: &HttpRequest) -> Result<HttpResponse, BoxError> {
+///         println!("{} {}", request.method, request.url);
+///         Ok(HttpResponse::new(200, r#"{"value": "a.b.c"}"#))
+///     }
+/// }
+/// ```
+#[cfg(feature = "http")]
+pub trait HttpTransport: Send + Sync {
+    /// Sends the request. Only failures to get a response are errors: a response with an error
+    /// status is returned as a response.
+    fn send(&self, request: &HttpRequest) -> std::result::Result<HttpResponse, BoxError>;
+}
+
+#[cfg(all(feature = "http", feature = "reqwest"))]
+impl HttpTransport for reqwest::blocking::Client {
+    fn send(&self, request: &HttpRequest) -> std::result::Result<HttpResponse, BoxError> {
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+        let mut builder = self.request(method, &request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(*name, value);
+        }
+        if let Some(body) = &request.body {
+            builder = builder.body(body.clone());
+        }
+        let response = builder.send()?;
+        Ok(HttpResponse {
+            status: response.status().as_u16(),
+            headers: response_headers(response.headers()),
+            body: response.bytes()?.into(),
+        })
+    }
+}
+
+/// Returns the headers with valid string values
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn response_headers(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.into())))
+        .collect()
+}
+
+#[cfg(feature = "http")]
+thread_local! {
+    static HTTP_TRANSPORT: RefCell<Option<Arc<dyn HttpTransport>>> = RefCell::default();
+}
+
+/// Runs `f` so that token requests made by detection on this thread are sent with `transport`.
+///
+/// This applies to the blocking API: the async API uses the non-blocking reqwest client, unless
+/// the built-in client is not compiled (see [`HttpTransport`]).
+///
+/// ```no_run
+/// # #[cfg(feature = "reqwest")] {
+/// let client = reqwest::blocking::Client::builder()
+///     .proxy(reqwest::Proxy::https("http://proxy.example.com:8080").unwrap())
+///     .build()
+///     .unwrap();
+/// let token = ci_id::with_transport(client, || ci_id::detect_credentials(Some("my-audience")));
+/// # }
+/// ```
+#[cfg(feature = "http")]
+pub fn with_transport<T>(transport: impl HttpTransport + 'static, f: impl FnOnce() -> T) -> T {
+    with_thread_local(&HTTP_TRANSPORT, Arc::new(transport), f)
+}
+
+/// A HTTP token request. Detectors describe the request and the caller decides whether to send
+/// it with the blocking or the async client.
+#[cfg(feature = "http")]
+struct TokenRequest {
+    /// Error message prefix
+    name: &'static str,
+    method: &'static str,
+    url: String,
+    authorization: Option<String>,
+    query: Vec<(&'static str, String)>,
+    body: Option<serde_json::Value>,
+    /// Returns the error for a non-2xx response
+    status_error: fn(&str, u16, &str) -> CIIDError,
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(not(feature = "all-detectors"), allow(dead_code))]
+impl TokenRequest {
+    /// GET request that passes the audience as a query parameter
+    fn get(name: &'static str, url: String, authorization: String, audience: Option<&str>) -> Self {
+        TokenRequest {
+            name,
+            method: "GET",
+            url,
+            authorization: Some(authorization),
+            query: audience
+                .map(|aud| ("audience", aud.into()))
+                .into_iter()
+                .collect(),
+            body: None,
+            status_error: http_error,
+        }
+    }
+
+    /// POST request with a JSON body
+    fn post(
+        name: &'static str,
+        url: String,
+        authorization: String,
+        body: serde_json::Value,
+    ) -> Self {
+        TokenRequest {
+            name,
+            method: "POST",
+            url,
+            authorization: Some(authorization),
+            query: vec![],
+            body: Some(body),
+            status_error: http_error,
+        }
+    }
+
+    /// GET request without authorization, e.g. for an OIDC discovery document
+    #[cfg(feature = "verify")]
+    fn document(name: &'static str, url: String) -> Self {
+        TokenRequest {
+            name,
+            method: "GET",
+            url,
+            authorization: None,
+            query: vec![],
+            body: None,
+            status_error: http_error,
+        }
+    }
+
+    /// Sets the function that returns the error for a non-2xx response
+    fn status_error(mut self, status_error: fn(&str, u16, &str) -> CIIDError) -> Self {
+        self.status_error = status_error;
+        self
+    }
+
+    /// Returns the request for a HTTP client
+    fn http_request(&self) -> Result<HttpRequest> {
+        let mut url = url::Url::parse(&self.url).map_err(|e| request_error(self.name, e))?;
+        if !self.query.is_empty() {
+            url.query_pairs_mut().extend_pairs(&self.query);
+        }
+        let authorization = self
+            .authorization
+            .iter()
+            .map(|a| ("authorization", a.clone()));
+        let mut headers: Vec<_> = authorization.collect();
+        headers.push(("user-agent", REQUEST_CONTEXT.with_borrow(user_agent)));
+        if self.body.is_some() {
+            headers.push(("content-type", "application/json".into()));
+        }
+        Ok(HttpRequest {
+            method: self.method,
+            url: url.into(),
+            headers,
+            body: self.body.as_ref().map(|body| body.to_string().into_bytes()),
+        })
+    }
+
+    /// Sends the request with the blocking client (or the transport set with
+    /// [`with_transport`]) and parses the JSON response
+    fn send<T: DeserializeOwned>(self) -> Result<T> {
+        let request = self.http_request()?;
+        log::debug!("{}: Requesting token", self.name);
+        let transport = HTTP_TRANSPORT.with_borrow(|transport| transport.clone());
+        let mut retries = 0;
+        loop {
+            let start = Instant::now();
+            let response = match &transport {
+                Some(transport) => transport.send(&request),
+                #[cfg(feature = "reqwest")]
+                None => HttpTransport::send(&http_client(self.name)?, &request),
+                #[cfg(not(feature = "reqwest"))]
+                None => return Err(no_transport_error(self.name)),
+            };
+            let status = response.as_ref().ok().map(|response| response.status);
+            instrument::http_request(self.name, request.method, status, start.elapsed());
+            let response = response.map_err(|e| request_error(self.name, e))?;
+            match self.retry_delay(&response, retries) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return self.parse_response(response),
+            }
+            retries += 1;
+        }
+    }
+
+    /// Returns the delay before retrying a rate limited request, or None if the response should
+    /// not be retried
+    fn retry_delay(&self, response: &HttpResponse, retries: u32) -> Option<Duration> {
+        if response.status != 429 || retries >= RATE_LIMIT_RETRIES {
+            return None;
+        }
+        let delay = retry_after(response).filter(|delay| *delay <= MAX_RETRY_AFTER)?;
+        log::debug!(
+            "{}: Token request was rate limited, retrying in {} seconds",
+            self.name,
+            delay.as_secs()
+        );
+        Some(delay)
+    }
+
+    /// Returns the parsed JSON response for a successful response
+    fn parse_response<T: DeserializeOwned>(&self, response: HttpResponse) -> Result<T> {
+        if response.status == 429 {
+            let body = String::from_utf8_lossy(&response.body);
+            return Err(rate_limited_error(self.name, retry_after(&response), &body));
+        }
+        if !(200..300).contains(&response.status) {
+            let body = String::from_utf8_lossy(&response.body);
+            return Err((self.status_error)(self.name, response.status, &body));
+        }
+        serde_json::from_slice(&response.body).map_err(|e| parse_error(self.name, e))
+    }
+}
+
+#[cfg(all(feature = "http", not(feature = "reqwest")))]
+fn no_transport_error(name: &str) -> CIIDError {
+    CIIDError::EnvironmentError(format!(
+        "{}: No HTTP client for the token request: set a transport with with_transport",
+        name
+    ))
+}
+
+#[cfg(feature = "http")]
+fn request_error(name: &str, e: impl Into<BoxError>) -> CIIDError {
+    let e = e.into();
+    CIIDError::request_failed(format!("{}: Token request failed: {}", name, e), e)
+}
+
+#[cfg(feature = "http")]
+fn http_error(name: &str, status: u16, body: &str) -> CIIDError {
+    CIIDError::HttpError {
+        status,
+        message: format!(
+            "{}: Token request failed with status {}: {}",
+            name,
+            status,
+            body.trim()
+        ),
+    }
+}
+
+/// Rate limited token requests are retried this many times
+#[cfg(feature = "http")]
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Longest `Retry-After` delay that is waited for before retrying a rate limited token request
+#[cfg(feature = "http")]
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Returns the `Retry-After` delay of the response. Only delays in seconds are supported, not
+/// HTTP dates.
+#[cfg(feature = "http")]
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    let (_, value) = response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(feature = "http")]
+fn rate_limited_error(name: &str, retry_after: Option<Duration>, body: &str) -> CIIDError {
+    let retry = match retry_after {
+        Some(delay) => format!(", retry after {} seconds", delay.as_secs()),
+        None => String::new(),
+    };
+    CIIDError::RateLimited {
+        retry_after,
+        message: format!(
+            "{}: Token request was rate limited{}: {}",
+            name,
+            retry,
+            body.trim()
+        ),
+    }
+}
+
+#[cfg(feature = "http")]
+fn parse_error(name: &str, e: serde_json::Error) -> CIIDError {
+    CIIDError::request_failed(format!("{}: Failed to parse token reponse: {}", name, e), e)
+}
+
+/// Returns additional trusted root certificates: the certificates from the CA bundle file and
+/// the certificates set in the request options. `name` is used as the error message prefix.
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn root_certificates(name: &str, settings: &ClientSettings) -> Result<Vec<reqwest::Certificate>> {
+    let mut certs = vec![];
+    #[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+    if !settings.no_built_in_roots {
+        certs.extend(system_root_certificates().iter().cloned());
+    }
+    if let Some(path) = &settings.ca_bundle {
+        log::debug!("{}: Adding root certificates from {}", name, path);
+        let message =
+            |e: &dyn fmt::Display| format!("{}: Failed to read CA bundle {}: {}", name, path, e);
+        let pem = fs::read(path).map_err(|e| CIIDError::io_error(message(&e), e))?;
+        let bundle = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| CIIDError::request_failed(message(&e), e))?;
+        certs.extend(bundle);
+    }
+    for pem in &settings.root_certificates {
+        let bundle = reqwest::Certificate::from_pem_bundle(pem).map_err(|e| {
+            CIIDError::request_failed(format!("{}: Invalid root certificate: {}", name, e), e)
+        })?;
+        certs.extend(bundle);
+    }
+    Ok(certs)
+}
+
+/// Returns the root certificates from the system CA bundle (`SSL_CERT_FILE` or the bundle in a
+/// well-known location): rustls does not use the platform trust store by itself
+#[cfg(all(feature = "http", feature = "rustls-tls", not(feature = "native-tls")))]
+fn system_root_certificates() -> &'static [reqwest::Certificate] {
+    static CERTS: std::sync::OnceLock<Vec<reqwest::Certificate>> = std::sync::OnceLock::new();
+    CERTS.get_or_init(|| {
+        let Some(path) = openssl_probe::probe().cert_file else {
+            log::warn!("System CA bundle not found, set CI_ID_CA_BUNDLE");
+            return vec![];
+        };
+        let certs = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string()));
+        match certs {
+            Ok(certs) => certs,
+            Err(e) => {
+                log::warn!("Failed to read system CA bundle {}: {}", path.display(), e);
+                vec![]
+            }
+        }
+    })
+}
+
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn client_error(name: &str, e: reqwest::Error) -> CIIDError {
+    CIIDError::request_failed(format!("{}: Failed to build HTTP client: {}", name, e), e)
+}
+
+/// Token request options for the current detection call
+#[derive(Clone, Default)]
+#[cfg_attr(not(all(feature = "http", feature = "reqwest")), allow(dead_code))]
+struct RequestContext {
+    /// The configuration file, read once per detection call
+    config: Arc<Config>,
+    /// HTTP request timeout
+    timeout: Option<Duration>,
+    /// HTTP connect timeout
+    connect_timeout: Option<Duration>,
+    /// User-Agent of the token requests
+    user_agent: Option<String>,
+    /// Proxy URL for all token requests
+    proxy: Option<String>,
+    /// Additional trusted root certificates, as PEM bundles
+    root_certificates: Vec<Vec<u8>>,
+    /// Do not trust the built-in root certificates
+    no_built_in_roots: bool,
+    /// Custom claims for CircleCI token requests
+    #[cfg_attr(not(feature = "circleci"), allow(dead_code))]
+    circleci_claims: serde_json::Map<String, serde_json::Value>,
+    /// Buildkite token lifetime
+    #[cfg_attr(not(feature = "buildkite"), allow(dead_code))]
+    buildkite_lifetime: Option<Duration>,
+    /// Optional claims for Buildkite tokens
+    #[cfg_attr(not(feature = "buildkite"), allow(dead_code))]
+    buildkite_claims: Vec<String>,
+    /// Variable that contains the GitLab ID token
+    #[cfg_attr(not(feature = "gitlab"), allow(dead_code))]
+    gitlab_token_variable: Option<String>,
+}
+
+thread_local! {
+    static REQUEST_CONTEXT: RefCell<RequestContext> = RefCell::default();
+}
+
+/// Sets the request context on this thread until dropped
+struct RequestContextGuard {
+    previous: RequestContext,
+}
+
+impl RequestContextGuard {
+    fn new(context: RequestContext) -> Self {
+        let previous = REQUEST_CONTEXT.replace(context);
+        RequestContextGuard { previous }
+    }
+}
+
+impl Drop for RequestContextGuard {
+    fn drop(&mut self) {
+        REQUEST_CONTEXT.set(std::mem::take(&mut self.previous));
+    }
+}
+
+/// Sets `config` in the request context of this thread until the returned guard is dropped, so
+/// that the token requests of a detection call do not read the configuration file again
+fn config_context(config: Config) -> (Arc<Config>, RequestContextGuard) {
+    let config = Arc::new(config);
+    let context = RequestContext {
+        config: config.clone(),
+        ..REQUEST_CONTEXT.with_borrow(Clone::clone)
+    };
+    (config, RequestContextGuard::new(context))
+}
+
+/// Settings the HTTP clients are built with
+#[cfg(all(feature = "http", feature = "reqwest"))]
+#[derive(Debug, Clone, PartialEq)]
+struct ClientSettings {
+    timeouts: HttpTimeouts,
+    proxies: Vec<ProxySetting>,
+    /// PEM file with additional trusted root certificates
+    ca_bundle: Option<String>,
+    /// Additional trusted root certificates, as PEM bundles
+    root_certificates: Vec<Vec<u8>>,
+    no_built_in_roots: bool,
+}
+
+#[cfg(all(feature = "http", feature = "reqwest"))]
+impl ClientSettings {
+    /// Returns the settings for the request options and the detection environment
+    fn new(context: &RequestContext) -> Result<Self> {
+        let ca_bundle = match env_var("CI_ID_CA_BUNDLE") {
+            Ok(path) => Some(path),
+            Err(_) => context.config.ca_bundle.clone(),
+        };
+        Ok(ClientSettings {
+            timeouts: http_timeouts(context)?,
+            proxies: proxy_settings(context),
+            ca_bundle,
+            root_certificates: context.root_certificates.clone(),
+            no_built_in_roots: context.no_built_in_roots,
+        })
+    }
+}
+
+/// The last built blocking HTTP client, reused while the client settings do not change
+#[cfg(all(feature = "http", feature = "reqwest"))]
+static HTTP_CLIENT: Mutex<Option<(ClientSettings, reqwest::blocking::Client)>> = Mutex::new(None);
+
+/// Returns a blocking HTTP client for token requests: the client is reused by later requests
+/// with the same settings, so repeated detection does not set up TLS again. `name` is used as
+/// the error message prefix.
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn http_client(name: &str) -> Result<reqwest::blocking::Client> {
+    let settings = REQUEST_CONTEXT.with_borrow(ClientSettings::new)?;
+    let mut cached = HTTP_CLIENT.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((cached_settings, client)) = &*cached {
+        if *cached_settings == settings {
+            return Ok(client.clone());
+        }
+    }
+    let client = build_http_client(name, &settings)?;
+    *cached = Some((settings, client.clone()));
+    Ok(client)
+}
+
+/// User-Agent of the token requests unless set with `CI_ID_USER_AGENT` or
+/// [`CredentialRequest::user_agent`]
+#[cfg(feature = "http")]
+const DEFAULT_USER_AGENT: &str = concat!("ci-id/", env!("CARGO_PKG_VERSION"));
+
+/// Returns the User-Agent for the request options and the detection environment
+#[cfg(feature = "http")]
+fn user_agent(context: &RequestContext) -> String {
+    context
+        .user_agent
+        .clone()
+        .or_else(|| env_var("CI_ID_USER_AGENT").ok())
+        .filter(|user_agent| !user_agent.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.into())
+}
+
+/// Default HTTP request timeout
+#[cfg(any(all(feature = "http", feature = "reqwest"), feature = "fly"))]
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default HTTP connect timeout
+#[cfg(all(feature = "http", feature = "reqwest"))]
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP request and connect timeouts
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg(all(feature = "http", feature = "reqwest"))]
+struct HttpTimeouts {
+    request: Duration,
+    connect: Duration,
+}
+
+/// Returns the HTTP timeouts: the request options override `CI_ID_HTTP_TIMEOUT` and
+/// `CI_ID_CONNECT_TIMEOUT`, which override the configuration file
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn http_timeouts(context: &RequestContext) -> Result<HttpTimeouts> {
+    Ok(HttpTimeouts {
+        request: request_timeout(context)?,
+        connect: match context.connect_timeout {
+            Some(timeout) => timeout,
+            None => env_timeout("CI_ID_CONNECT_TIMEOUT")?
+                .or(context.config.connect_timeout)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        },
+    })
+}
+
+/// Returns the HTTP request timeout, see [`http_timeouts`]. Also used for requests that are not
+/// sent with the HTTP client.
+#[cfg(any(all(feature = "http", feature = "reqwest"), feature = "fly"))]
+fn request_timeout(context: &RequestContext) -> Result<Duration> {
+    Ok(match context.timeout {
+        Some(timeout) => timeout,
+        None => env_timeout("CI_ID_HTTP_TIMEOUT")?
+            .or(context.config.timeout)
+            .unwrap_or(DEFAULT_HTTP_TIMEOUT),
+    })
+}
+
+/// Returns the timeout in seconds from the environment variable
+fn env_timeout(var_name: &str) -> Result<Option<Duration>> {
+    let Ok(value) = env_var(var_name) else {
+        return Ok(None);
+    };
+    let timeout = value
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|timeout| !timeout.is_zero());
+    match timeout {
+        Some(timeout) => Ok(Some(timeout)),
+        None => Err(CIIDError::EnvironmentError(format!(
+            "{}: Invalid timeout '{}', expected a number of seconds",
+            var_name, value
+        ))),
+    }
+}
+
+/// Requests that a proxy is used for
+#[cfg(all(feature = "http", feature = "reqwest"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProxyScheme {
+    All,
+    Https,
+    Http,
+}
+
+/// A proxy for token requests
+#[cfg(all(feature = "http", feature = "reqwest"))]
+#[derive(Debug, Clone, PartialEq)]
+struct ProxySetting {
+    scheme: ProxyScheme,
+    url: String,
+    /// Hosts that are not proxied, in the `NO_PROXY` format
+    no_proxy: Option<String>,
+}
+
+#[cfg(all(feature = "http", feature = "reqwest"))]
+impl ProxySetting {
+    fn build(&self, name: &str) -> Result<reqwest::Proxy> {
+        let proxy = match self.scheme {
+            ProxyScheme::All => reqwest::Proxy::all(&self.url),
+            ProxyScheme::Https => reqwest::Proxy::https(&self.url),
+            ProxyScheme::Http => reqwest::Proxy::http(&self.url),
+        };
+        let proxy = proxy.map_err(|e| {
+            CIIDError::EnvironmentError(format!("{}: Invalid proxy '{}': {}", name, self.url, e))
+        })?;
+        let no_proxy = self
+            .no_proxy
+            .as_deref()
+            .and_then(reqwest::NoProxy::from_string);
+        Ok(proxy.no_proxy(no_proxy))
+    }
+}
+
+/// Returns the proxies for token requests: the proxy set in the request options, or the proxies
+/// set with `HTTPS_PROXY` and `HTTP_PROXY` (excluding the hosts in `NO_PROXY`)
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn proxy_settings(context: &RequestContext) -> Vec<ProxySetting> {
+    if let Some(url) = &context.proxy {
+        return vec![ProxySetting {
+            scheme: ProxyScheme::All,
+            url: url.clone(),
+            no_proxy: None,
+        }];
+    }
+
+    let var = |names: [&str; 2]| names.into_iter().find_map(|name| env_var(name).ok());
+    let no_proxy = var(["NO_PROXY", "no_proxy"]);
+    let mut proxies = vec![];
+    for (scheme, names) in [
+        (ProxyScheme::Https, ["HTTPS_PROXY", "https_proxy"]),
+        (ProxyScheme::Http, ["HTTP_PROXY", "http_proxy"]),
+    ] {
+        if let Some(url) = var(names).filter(|url| !url.is_empty()) {
+            proxies.push(ProxySetting {
+                scheme,
+                url,
+                no_proxy: no_proxy.clone(),
+            });
+        }
+    }
+    proxies
+}
+
+#[cfg(all(feature = "http", feature = "reqwest"))]
+fn build_http_client(name: &str, settings: &ClientSettings) -> Result<reqwest::blocking::Client> {
+    // Proxies come from the detection environment (see with_env), not the system settings
+    let mut builder = reqwest::blocking::Client::builder()
+        .timeout(settings.timeouts.request)
+        .connect_timeout(settings.timeouts.connect)
+        .no_proxy();
+    for proxy in &settings.proxies {
+        log::debug!("{}: Using proxy {}", name, proxy.url);
+        builder = builder.proxy(proxy.build(name)?);
+    }
+    // Without a TLS backend only the compile_error above is reported
+    #[cfg(any(feature = "rustls-tls", feature = "native-tls"))]
+    {
+        for cert in root_certificates(name, settings)? {
+            builder = builder.add_root_certificate(cert);
+        }
+        builder = builder.tls_built_in_root_certs(!settings.no_built_in_roots);
+    }
+    builder.build().map_err(|e| client_error(name, e))
+}
+
+/// Returns the path of `program` in PATH. On Windows the extensions in PATHEXT (e.g. `.exe` and
+/// `.cmd`) are tried as well.
+#[cfg(feature = "subprocess")]
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let paths = match current_env() {
+        Some(env) => env.var("PATH").map(Into::into),
+        None => env::var_os("PATH"),
+    }?;
+    let names = program_file_names(program);
+    env::split_paths(&paths).find_map(|dir| {
+        names
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+    })
+}
+
+/// Returns the file names that `program` may have in PATH
+#[cfg(all(feature = "subprocess", not(windows)))]
+fn program_file_names(program: &str) -> Vec<String> {
+    vec![program.into()]
+}
+
+/// Returns the file names that `program` may have in PATH
+#[cfg(all(feature = "subprocess", windows))]
+fn program_file_names(program: &str) -> Vec<String> {
+    let mut names = vec![];
+    if Path::new(program).extension().is_some() {
+        names.push(program.into());
+    }
+    let extensions = env_var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+    names.extend(
+        extensions
+            .split(';')
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| format!("{}{}", program, ext.to_ascii_lowercase())),
+    );
+    names
+}
+
+// Github implementation
+
+#[derive(Deserialize)]
+#[cfg(any(feature = "forgejo", feature = "gitea", feature = "github"))]
+struct GitHubTokenResponse {
+    value: String,
+}
+
+#[cfg(feature = "github")]
+fn detect_github(audience: Option<&str>) -> Result<String> {
+    let response: GitHubTokenResponse = github_request(audience)?.send()?;
+    check_github_issuer(response.value)
+}
+
+#[cfg(feature = "github")]
+fn github_request(audience: Option<&str>) -> Result<TokenRequest> {
+    if !CIEnvironment::GitHubActions.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    actions_token_request("GitHub Actions", audience)
+}
+
+/// Returns the issuer GitHub Actions tokens are expected to have: CI_ID_GITHUB_ISSUER overrides
+/// the default that is based on GITHUB_SERVER_URL
+fn github_issuer() -> String {
+    if let Ok(issuer) = env_var("CI_ID_GITHUB_ISSUER") {
+        return issuer;
+    }
+    let server_url = env_var("GITHUB_SERVER_URL").unwrap_or_else(|_| "https://github.com".into());
+    let server_url = server_url.trim_end_matches('/');
+    match server_url.strip_prefix("https://") {
+        None | Some("github.com") => "https://token.actions.githubusercontent.com".into(),
+        // GitHub Enterprise Cloud with data residency
+        Some(host) if host.ends_with(".ghe.com") => format!("https://token.actions.{}", host),
+        // GitHub Enterprise Server
+        Some(_) => format!("{}/_services/token", server_url),
+    }
+}
+
+/// Checks the token issuer: the expected issuer or the customized enterprise issuer
+/// `<issuer>/<enterprise-slug>`. A mismatch is only an error if the expected issuer is set with
+/// CI_ID_GITHUB_ISSUER, otherwise it is logged.
+#[cfg(feature = "github")]
+fn check_github_issuer(token: String) -> Result<String> {
+    let issuer = github_issuer();
+    let token_issuer =
+        token_claims(&token).and_then(|claims| claims.get("iss")?.as_str().map(String::from));
+    let message = match token_issuer {
+        Some(token_issuer) if is_github_issuer(&token_issuer, &issuer) => return Ok(token),
+        Some(token_issuer) => format!(
+            "GitHub Actions: Token issuer '{}' does not match expected issuer '{}'",
+            token_issuer, issuer
+        ),
+        None => "GitHub Actions: Failed to read token issuer".into(),
+    };
+    if env_var("CI_ID_GITHUB_ISSUER").is_ok() {
+        return Err(CIIDError::EnvironmentError(message));
+    }
+    log::warn!(
+        "{}. CI_ID_GITHUB_ISSUER can be used to require the expected issuer",
+        message
+    );
+    Ok(token)
+}
+
+/// Returns true if `token_issuer` is `issuer` or `issuer/<enterprise-slug>`
+#[cfg(feature = "github")]
+fn is_github_issuer(token_issuer: &str, issuer: &str) -> bool {
+    match token_issuer.strip_prefix(issuer.trim_end_matches('/')) {
+        Some("") => true,
+        Some(rest) => rest
+            .strip_prefix('/')
+            .is_some_and(|slug| !slug.is_empty() && !slug.contains('/')),
+        None => false,
+    }
+}
+
+#[cfg(feature = "gitea")]
+fn detect_gitea(audience: Option<&str>) -> Result<String> {
+    let response: GitHubTokenResponse = gitea_request(audience)?.send()?;
+    Ok(response.value)
+}
+
+#[cfg(feature = "gitea")]
+fn gitea_request(audience: Option<&str>) -> Result<TokenRequest> {
+    if !CIEnvironment::GiteaActions.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    actions_token_request("Gitea Actions", audience)
+}
+
+#[cfg(feature = "forgejo")]
+fn detect_forgejo(audience: Option<&str>) -> Result<String> {
+    let response: GitHubTokenResponse = forgejo_request(audience)?.send()?;
+    Ok(response.value)
+}
+
+#[cfg(feature = "forgejo")]
+fn forgejo_request(audience: Option<&str>) -> Result<TokenRequest> {
+    if !CIEnvironment::ForgejoActions.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    actions_token_request("Forgejo Actions", audience)
+}
+
+/// Returns a token request using the GitHub Actions compatible ACTIONS_ID_TOKEN_REQUEST_* variables
+#[cfg(any(feature = "forgejo", feature = "gitea", feature = "github"))]
+fn actions_token_request(name: &'static str, audience: Option<&str>) -> Result<TokenRequest> {
+    let Ok(token_token) = env_var("ACTIONS_ID_TOKEN_REQUEST_TOKEN") else {
+        return Err(CIIDError::MissingPermission {
+            message: format!(
+                "{}: ACTIONS_ID_TOKEN_REQUEST_TOKEN is not set. This could \
+                imply that the job does not have 'id-token: write' permission",
+                name
+            ),
+        });
+    };
+    let Ok(token_url) = env_var("ACTIONS_ID_TOKEN_REQUEST_URL") else {
+        return Err(CIIDError::missing_variable(
+            "ACTIONS_ID_TOKEN_REQUEST_URL",
+            format!("{}: ACTIONS_ID_TOKEN_REQUEST_URL is not set", name),
+        ));
+    };
+
+    Ok(
+        TokenRequest::get(name, token_url, format!("bearer {}", token_token), audience)
+            .status_error(actions_status_error),
+    )
+}
+
+/// Returns the error for a failed Actions token request, with advice for the common failures
+#[cfg(any(feature = "forgejo", feature = "gitea", feature = "github"))]
+fn actions_status_error(name: &str, status: u16, body: &str) -> CIIDError {
+    let advice = match status {
+        403 => {
+            return CIIDError::MissingPermission {
+                message: format!(
+                    "{}: Token request was denied with status 403: {}. Check that the job \
+                    has 'id-token: write' permission",
+                    name,
+                    body.trim()
+                ),
+            }
+        }
+        400 => "Check that the requested audience is valid",
+        500..=599 => "The token service may be temporarily unavailable: try again later",
+        _ => return http_error(name, status, body),
+    };
+    CIIDError::HttpError {
+        status,
+        message: format!(
+            "{}: Token request failed with status {}: {}. {}",
+            name,
+            status,
+            body.trim(),
+            advice
+        ),
+    }
+}
+
+// Codefresh implementation
+
+#[derive(Deserialize)]
+#[cfg(feature = "codefresh")]
+struct CodefreshTokenResponse {
+    id_token: String,
+}
+
+#[cfg(feature = "codefresh")]
+fn detect_codefresh(audience: Option<&str>) -> Result<String> {
+    let response: CodefreshTokenResponse = codefresh_request(audience)?.send()?;
+    Ok(response.id_token)
+}
+
+#[cfg(feature = "codefresh")]
+fn codefresh_request(audience: Option<&str>) -> Result<TokenRequest> {
+    if !CIEnvironment::Codefresh.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token_token) = env_var("CF_OIDC_REQUEST_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "CF_OIDC_REQUEST_TOKEN",
+            "Codefresh: CF_OIDC_REQUEST_TOKEN is not set",
+        ));
+    };
+    let Ok(token_url) = env_var("CF_OIDC_REQUEST_URL") else {
+        return Err(CIIDError::missing_variable(
+            "CF_OIDC_REQUEST_URL",
+            "Codefresh: CF_OIDC_REQUEST_URL is not set",
+        ));
+    };
+
+    // Codefresh expects the request token as is, without an authorization scheme
+    Ok(TokenRequest::get(
+        "Codefresh",
+        token_url,
+        token_token,
+        audience,
+    ))
+}
+
+/// Returns the audience sanitized for environment variable names: uppercased and all
+/// characters outside of ascii letters and digits replaced with "_"
+fn sanitize_audience(audience: &str) -> String {
+    audience
+        .to_uppercase()
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'A'..='Z' | '_' => c,
+            // a leading digit is replaced as well
+            '0'..='9' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Returns the variable name `<AUD>_ID_TOKEN` that the GitLab, Jenkins and TeamCity detectors
+/// read the token for `audience` from: `<AUD>` is the audience uppercased, with all characters
+/// outside of ascii letters and digits (and a leading digit) replaced with "_".
+///
+/// Tools that generate pipeline configuration can use this to name the ID tokens:
+///
+/// ```
+/// assert_eq!(ci_id::id_token_variable("sigstore"), "SIGSTORE_ID_TOKEN");
+/// assert_eq!(
+///     ci_id::id_token_variable("https://vault.example.com"),
+///     "HTTPS___VAULT_EXAMPLE_COM_ID_TOKEN"
+/// );
+/// ```
+///
+/// In GitLab, `CI_ID_GITLAB_TOKEN_VARIABLES` and [`CredentialRequest::gitlab_token_variable`]
+/// take precedence over this name.
+pub fn id_token_variable(audience: &str) -> String {
+    format!("{}_ID_TOKEN", sanitize_audience(audience))
+}
+
+#[cfg(feature = "gitlab")]
+fn detect_gitlab(audience: Option<&str>) -> Result<String> {
+    // gitlab tokens can be in any environment variable: we require the variable name to be
+    // * "<AUDIENCE>_ID_TOKEN" where <AUDIENCE> is the audience string.
+
+    if !CIEnvironment::GitLab.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let variable = REQUEST_CONTEXT.with_borrow(|context| context.gitlab_token_variable.clone());
+    if let Some(var_name) = variable {
+        log::debug!("GitLab Pipelines: Looking for token in {}", var_name);
+        return env_var(&var_name).map_err(|_| {
+            CIIDError::missing_variable(
+                &var_name,
+                format!("GitLab Pipelines: {} is not set", var_name),
+            )
+        });
+    }
+
+    let var_name = audience.map(gitlab_token_var_name);
+    if let Some(var_name) = &var_name {
+        log::debug!("GitLab Pipelines: Looking for token in {}", var_name);
+        if let Ok(token) = env_var(var_name) {
+            return Ok(token);
+        }
+    }
+
+    // Optionally look for any ID token with the requested audience
+    if let Some(audience) = audience {
+        if env_flag("CI_ID_GITLAB_DISCOVER_TOKENS") {
+            if let Some(token) = discover_gitlab_token(audience) {
+                return Ok(token);
+            }
+        }
+    }
+
+    // Older GitLab instances only offer the deprecated CI_JOB_JWT_V2
+    if legacy_tokens_allowed() {
+        if let Ok(token) = env_var("CI_JOB_JWT_V2") {
+            log::warn!("GitLab Pipelines: No ID token found, using legacy CI_JOB_JWT_V2");
+            return check_audience("GitLab Pipelines (legacy CI_JOB_JWT_V2)", token, audience);
+        }
+    }
+
+    match var_name {
+        None => Err(CIIDError::EnvironmentError(
+            "GitLab: audience must be set".into(),
+        )),
+        Some(var_name) => Err(CIIDError::missing_variable(
+            &var_name,
+            format!(
+                "GitLab Pipelines: {} is not set. This could imply that the \
+                pipeline does not define an id token with that name",
+                var_name
+            ),
+        )),
+    }
+}
+
+/// Returns the GitLab ID token variable name for audience: the name is looked up from the
+/// CI_ID_GITLAB_TOKEN_VARIABLES mapping ("<AUDIENCE>=<VARIABLE>,...") or derived from the
+/// audience
+#[cfg(feature = "gitlab")]
+fn gitlab_token_var_name(audience: &str) -> String {
+    if let Ok(mapping) = env_var("CI_ID_GITLAB_TOKEN_VARIABLES") {
+        let var_name = mapping.split(',').find_map(|entry| {
+            let (aud, var_name) = entry.trim().rsplit_once('=')?;
+            (aud.trim() == audience).then(|| var_name.trim().to_string())
+        });
+        if let Some(var_name) = var_name {
+            return var_name;
+        }
+    }
+    id_token_variable(audience)
+}
+
+/// Returns the value of the first (in name order) "*_ID_TOKEN" variable whose token audience
+/// matches `audience`
+#[cfg(feature = "gitlab")]
+fn discover_gitlab_token(audience: &str) -> Option<String> {
+    let mut vars: Vec<(String, String)> = env_vars()
+        .into_iter()
+        .filter(|(name, _)| name.ends_with("_ID_TOKEN"))
+        .collect();
+    vars.sort();
+    vars.into_iter().find_map(|(name, token)| {
+        let auds = token_audiences(&token)?;
+        if auds.iter().any(|aud| aud == audience) {
+            log::debug!(
+                "GitLab Pipelines: Found token with matching audience in {}",
+                name
+            );
+            Some(token)
+        } else {
+            None
+        }
+    })
+}
+
+/// A helper program call made by a detector, see [`CommandRunner`]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CommandCall {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Environment changes for the program: variables with value None are removed
+    pub env: Vec<(String, Option<String>)>,
+    /// The program should be killed if it does not finish in this time
+    pub timeout: Option<Duration>,
+}
+
+impl CommandCall {
+    fn new(program: &str, args: &[&str]) -> Self {
+        CommandCall {
+            program: program.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: vec![],
+            timeout: None,
+        }
+    }
+
+    /// Returns a call that runs `command` with the shell: `sh -c`, or `cmd /C` on Windows
+    fn shell(command: &str) -> Self {
+        if cfg!(windows) {
+            CommandCall::new("cmd", &["/C", command])
+        } else {
+            CommandCall::new("sh", &["-c", command])
+        }
+    }
+
+    fn env(mut self, name: &str, value: Option<&str>) -> Self {
+        self.env.push((name.into(), value.map(String::from)));
+        self
+    }
+}
+
+/// Output of a helper program, see [`CommandRunner`]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CommandOutput {
+    /// Exit code, None if the program was terminated by a signal
+    pub exit_code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl CommandOutput {
+    pub fn new(exit_code: Option<i32>, stdout: impl Into<Vec<u8>>) -> Self {
+        CommandOutput {
+            exit_code,
+            stdout: stdout.into(),
+            stderr: vec![],
+        }
+    }
+
+    /// Sets the captured stderr
+    pub fn stderr(mut self, stderr: impl Into<Vec<u8>>) -> Self {
+        self.stderr = stderr.into();
+        self
+    }
+}
+
+/// Runs the helper programs of the CLI based detectors (e.g. `buildkite-agent`, `circleci`).
+///
+/// By default the programs are looked up in PATH and run as subprocesses:
+/// [`with_command_runner`] allows intercepting, sandboxing or mocking the calls.
+pub trait CommandRunner: Send + Sync {
+    /// Returns true if `program` can be run
+    fn is_available(&self, program: &str) -> bool;
+
+    /// Runs the program and returns its output. A program that does not finish within
+    /// `call.timeout` should be killed and reported with [`std::io::ErrorKind::TimedOut`].
+    fn run(&self, call: &CommandCall) -> std::io::Result<CommandOutput>;
+}
+
+/// Runs helper programs as subprocesses: the default [`CommandRunner`]. Without the
+/// `subprocess` feature no programs are available.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessRunner;
+
+#[cfg(not(feature = "subprocess"))]
+impl CommandRunner for ProcessRunner {
+    fn is_available(&self, _program: &str) -> bool {
+        false
+    }
+
+    fn run(&self, _call: &CommandCall) -> std::io::Result<CommandOutput> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "running helper programs is disabled at build time, enable the ci-id 'subprocess' \
+            feature",
+        ))
+    }
+}
+
+#[cfg(feature = "subprocess")]
+impl CommandRunner for ProcessRunner {
+    fn is_available(&self, program: &str) -> bool {
+        find_in_path(program).is_some()
+    }
+
+    fn run(&self, call: &CommandCall) -> std::io::Result<CommandOutput> {
+        // Resolve the program here: the detection environment may have a different PATH than
+        // the process, and Windows only finds ".exe" programs on its own
+        let mut command = match find_in_path(&call.program) {
+            Some(path) => Command::new(path),
+            None => Command::new(&call.program),
+        };
+        command
+            .args(&call.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        for (name, value) in &call.env {
+            match value {
+                Some(value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+        let mut child = command.spawn()?;
+        // Read the pipes on separate threads so that a chatty program can not fill a pipe
+        // buffer and block while the status is polled
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+        let status = match call.timeout {
+            Some(timeout) => wait_timeout(&mut child, timeout)?,
+            None => child.wait()?,
+        };
+        Ok(CommandOutput {
+            exit_code: status.code(),
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+}
+
+/// Reads the pipe to the end on a new thread
+#[cfg(feature = "subprocess")]
+fn read_pipe(
+    pipe: Option<impl std::io::Read + Send + 'static>,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Waits for the child to exit, killing it if it does not exit within `timeout`.
+///
+/// On timeout the pipe reader threads are left to finish on their own: the pipes may be held
+/// open by programs the child started. A timeout too large to represent as a deadline means
+/// no timeout.
+#[cfg(feature = "subprocess")]
+fn wait_timeout(child: &mut Child, timeout: Duration) -> std::io::Result<ExitStatus> {
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("timed out after {} seconds", timeout.as_secs_f64()),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+thread_local! {
+    static COMMAND_RUNNER: RefCell<Option<Arc<dyn CommandRunner>>> = RefCell::default();
+}
+
+/// Runs `f` so that helper programs called by detection on this thread are run with `runner`
+///
+/// ```
+/// use ci_id::{CommandCall, CommandOutput, CommandRunner};
+///
+/// /// Pretends that no helper programs are installed
+/// struct NoPrograms;
+///
+/// impl CommandRunner for NoPrograms {
+///     fn is_available(&self, _program: &str) -> bool {
+///         false
+///     }
+///
+///     fn run(&self, call: &CommandCall) -> std::io::Result<CommandOutput> {
+///         Err(std::io::ErrorKind::NotFound.into())
+///     }
+/// }
+///
+/// let token = ci_id::with_command_runner(NoPrograms, || ci_id::detect_credentials(None));
+/// ```
+pub fn with_command_runner<T>(runner: impl CommandRunner + 'static, f: impl FnOnce() -> T) -> T {
+    with_thread_local(&COMMAND_RUNNER, Arc::new(runner), f)
+}
+
+/// Returns the runner set with [`with_command_runner`] or the default runner
+fn command_runner() -> Arc<dyn CommandRunner> {
+    COMMAND_RUNNER
+        .with_borrow(|runner| runner.clone())
+        .unwrap_or_else(|| Arc::new(ProcessRunner))
+}
+
+/// Returns true if the helper program can be run
+#[cfg(any(feature = "buildkite", feature = "circleci"))]
+fn program_available(program: &str) -> bool {
+    command_runner().is_available(program)
+}
+
+/// Default helper program timeout
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns the helper program timeout: `CI_ID_COMMAND_TIMEOUT` overrides the configuration file
+fn command_timeout() -> Result<Duration> {
+    Ok(match env_timeout("CI_ID_COMMAND_TIMEOUT")? {
+        Some(timeout) => timeout,
+        None => REQUEST_CONTEXT
+            .with_borrow(|context| context.config.command_timeout)
+            .unwrap_or(DEFAULT_COMMAND_TIMEOUT),
+    })
+}
+
+/// Runs the helper program and returns its output. `name` is used as the error message prefix.
+///
+/// If the program fails, its stderr is included in the error message.
+fn run_command(name: &str, mut call: CommandCall) -> Result<String> {
+    call.timeout = Some(command_timeout()?);
+    log::debug!("{}: Running {}", name, call.program);
+    match command_runner().run(&call) {
+        Ok(output) if output.exit_code != Some(0) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = match stderr.trim() {
+                "" => String::new(),
+                stderr => format!(": {}", stderr),
+            };
+            Err(CIIDError::SubprocessFailed {
+                exit_code: output.exit_code,
+                message: match output.exit_code {
+                    Some(code) => format!(
+                        "{}: {} failed with exit code {}{}",
+                        name, call.program, code, stderr
+                    ),
+                    None => format!(
+                        "{}: {} was terminated by a signal{}",
+                        name, call.program, stderr
+                    ),
+                },
+            })
+        }
+        Ok(output) => match String::from_utf8(output.stdout) {
+            Ok(stdout) => Ok(stdout),
+            Err(_) => Err(CIIDError::EnvironmentError(format!(
+                "{}: Failed to read token",
+                name
+            ))),
+        },
+        Err(e) => Err(CIIDError::io_error(
+            format!("{}: Call to {} failed: {}", name, call.program, e),
+            e,
+        )),
+    }
+}
+
+/// Returns the token from the output of a helper program: whitespace and lines that are not a
+/// token (e.g. warnings) are ignored. `name` is used as the error message prefix.
+#[cfg(any(feature = "buildkite", feature = "circleci", feature = "namespace"))]
+fn token_from_output(name: &str, program: &str, output: &str) -> Result<String> {
+    let mut token = None;
+    for line in output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        if is_jwt_like(line) {
+            token = Some(line);
+        } else {
+            log::debug!("{}: Ignoring {} output: {}", name, program, line);
+        }
+    }
+    match token {
+        Some(token) => Ok(token.into()),
+        None => Err(CIIDError::EnvironmentError(format!(
+            "{}: {} output does not contain a token",
+            name, program
+        ))),
+    }
+}
+
+#[cfg(feature = "circleci")]
+fn detect_circleci(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::CircleCI.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+    let mut claims = REQUEST_CONTEXT.with_borrow(|context| context.circleci_claims.clone());
+    match audience {
+        None if claims.is_empty() => return circleci_default_token(),
+        _ if !claims.is_empty() && !program_available("circleci") => {
+            return Err(CIIDError::EnvironmentError(
+                "CircleCI: circleci CLI is not in PATH: it is required to request tokens with \
+                custom claims"
+                    .into(),
+            ));
+        }
+        Some(audience) if !program_available("circleci") => {
+            // Without the CLI only the default token is available: it can be used if the
+            // requested audience is the default audience (the organization id)
+            log::debug!("CircleCI: circleci not found, using default token");
+            let token = circleci_default_token()?;
+            return match check_audience("CircleCI", token, Some(audience)) {
+                Ok(token) => Ok(token),
+                Err(_) => Err(CIIDError::EnvironmentError(format!(
+                    "CircleCI: circleci CLI is not in PATH: it is required to request \
+                    tokens for audience '{}'",
+                    audience
+                ))),
+            };
+        }
+        _ => {}
+    }
+
+    if let Some(audience) = audience {
+        claims.insert("aud".into(), audience.into());
+    }
+    let payload = serde_json::Value::Object(claims).to_string();
+    let args = ["run", "oidc", "get", "--claims", &payload];
+    let output = run_command("CircleCI", CommandCall::new("circleci", &args))?;
+    token_from_output("CircleCI", "circleci", &output)
+}
+
+#[cfg(feature = "circleci")]
+fn circleci_default_token() -> Result<String> {
+    if let Ok(token) = env_var("CIRCLE_OIDC_TOKEN_V2") {
+        return Ok(token);
+    }
+    if legacy_tokens_allowed() {
+        if let Ok(token) = env_var("CIRCLE_OIDC_TOKEN") {
+            log::warn!("CircleCI: CIRCLE_OIDC_TOKEN_V2 is not set, using legacy CIRCLE_OIDC_TOKEN");
+            return Ok(token);
+        }
+    }
+    Err(CIIDError::missing_variable(
+        "CIRCLE_OIDC_TOKEN_V2",
+        "CircleCI: CIRCLE_OIDC_TOKEN_V2 is not set.",
+    ))
+}
+
+#[cfg(feature = "buildkite")]
+fn detect_buildkite(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Buildkite.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    // buildkite-agent is often not available inside job containers: use the agent API directly
+    if !program_available("buildkite-agent") {
+        log::debug!("Buildkite: buildkite-agent not found, using agent API");
+        let response: BuildkiteTokenResponse = buildkite_api_request(audience)?.send()?;
+        return Ok(response.token);
+    }
+
+    let request = buildkite_token_request(audience)?;
+    let lifetime = request.lifetime.map(|lifetime| lifetime.to_string());
+    let claims = request.claims.join(",");
+    let mut args = vec!["oidc", "request-token"];
+    if let Some(audience) = request.audience {
+        args.extend(["--audience", audience]);
+    }
+    if let Some(lifetime) = &lifetime {
+        args.extend(["--lifetime", lifetime]);
+    }
+    if !claims.is_empty() {
+        args.extend(["--claim", &claims]);
+    }
+    let output = run_command("Buildkite", CommandCall::new("buildkite-agent", &args))?;
+    token_from_output("Buildkite", "buildkite-agent", &output)
+}
+
+#[derive(Serialize)]
+#[cfg(feature = "buildkite")]
+struct BuildkiteTokenRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audience: Option<&'a str>,
+    /// Token lifetime in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lifetime: Option<u64>,
+    /// Optional claims to include in the token
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    claims: Vec<String>,
+}
+
+/// Returns the token request options: the request options override `CI_ID_BUILDKITE_LIFETIME`
+/// and `CI_ID_BUILDKITE_CLAIMS`
+#[cfg(feature = "buildkite")]
+fn buildkite_token_request(audience: Option<&str>) -> Result<BuildkiteTokenRequest<'_>> {
+    let (lifetime, claims) = REQUEST_CONTEXT
+        .with_borrow(|context| (context.buildkite_lifetime, context.buildkite_claims.clone()));
+    let lifetime = match (lifetime, env_var("CI_ID_BUILDKITE_LIFETIME")) {
+        (Some(lifetime), _) => Some(lifetime.as_secs()),
+        (None, Ok(value)) => match value.trim().parse() {
+            Ok(lifetime) => Some(lifetime),
+            Err(_) => {
+                return Err(CIIDError::EnvironmentError(format!(
+                    "CI_ID_BUILDKITE_LIFETIME: Invalid lifetime '{}', expected a number of \
+                    seconds",
+                    value
+                )))
+            }
+        },
+        (None, Err(_)) => None,
+    };
+    let claims = match (claims.is_empty(), env_var("CI_ID_BUILDKITE_CLAIMS")) {
+        (true, Ok(value)) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|claim| !claim.is_empty())
+            .map(String::from)
+            .collect(),
+        _ => claims,
+    };
+    Ok(BuildkiteTokenRequest {
+        audience,
+        lifetime,
+        claims,
+    })
+}
+
+#[derive(Deserialize)]
+#[cfg(feature = "buildkite")]
+struct BuildkiteTokenResponse {
+    token: String,
+}
+
+/// Returns a token request for the agent API: used when buildkite-agent is not available
+#[cfg(feature = "buildkite")]
+fn buildkite_api_request(audience: Option<&str>) -> Result<TokenRequest> {
+    let Ok(access_token) = env_var("BUILDKITE_AGENT_ACCESS_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "BUILDKITE_AGENT_ACCESS_TOKEN",
+            "Buildkite: buildkite-agent is not in PATH and BUILDKITE_AGENT_ACCESS_TOKEN is \
+            not set",
+        ));
+    };
+    let Ok(job_id) = env_var("BUILDKITE_JOB_ID") else {
+        return Err(CIIDError::missing_variable(
+            "BUILDKITE_JOB_ID",
+            "Buildkite: BUILDKITE_JOB_ID is not set",
+        ));
+    };
+    let endpoint = env_var("BUILDKITE_AGENT_ENDPOINT")
+        .unwrap_or_else(|_| "https://agent.buildkite.com/v3".into());
+    let url = format!(
+        "{}/jobs/{}/oidc/tokens",
+        endpoint.trim_end_matches('/'),
+        job_id
+    );
+
+    Ok(TokenRequest::post(
+        "Buildkite",
+        url,
+        format!("Token {}", access_token),
+        serde_json::to_value(buildkite_token_request(audience)?).unwrap(),
+    ))
+}
+
+#[cfg(feature = "jenkins")]
+fn detect_jenkins(audience: Option<&str>) -> Result<String> {
+    // Jenkins OpenID Connect Provider plugin tokens are bound to a variable of the users
+    // choice: we require the variable name to be
+    // * "<AUDIENCE>_ID_TOKEN" where <AUDIENCE> is the audience string, or
+    // * "ID_TOKEN" if audience is not set
+    if !CIEnvironment::Jenkins.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let var_name = match audience {
+        None => "ID_TOKEN".into(),
+        Some(audience) => id_token_variable(audience),
+    };
+    log::debug!("Jenkins: Looking for token in {}", var_name);
+    match env_var(&var_name) {
+        Ok(token) => Ok(token),
+        Err(_) => Err(CIIDError::missing_variable(
+            &var_name,
+            format!(
+                "Jenkins: {} is not set. This could imply that the job does not \
+                bind an OpenID Connect id token credential to that variable",
+                var_name
+            ),
+        )),
+    }
+}
+
+#[cfg(feature = "teamcity")]
+fn detect_teamcity(audience: Option<&str>) -> Result<String> {
+    // TeamCity has no built-in id token support: tokens provided by a plugin or an earlier
+    // build step must be exposed as an environment variable (an "env." build parameter):
+    // * "<AUDIENCE>_ID_TOKEN" where <AUDIENCE> is the audience string, or
+    // * "ID_TOKEN" if audience is not set
+    if !CIEnvironment::TeamCity.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let var_name = match audience {
+        None => "ID_TOKEN".into(),
+        Some(audience) => id_token_variable(audience),
+    };
+    log::debug!("TeamCity: Looking for token in {}", var_name);
+    match env_var(&var_name) {
+        Ok(token) => Ok(token),
+        Err(_) => Err(CIIDError::missing_variable(
+            &var_name,
+            format!(
+                "TeamCity: {} is not set. The build configuration must define an \
+                'env.{}' parameter containing the id token",
+                var_name, var_name
+            ),
+        )),
+    }
+}
+
+#[cfg(feature = "harness")]
+fn detect_harness(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Harness.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("PLUGIN_OIDC_TOKEN_ID") else {
+        return Err(CIIDError::missing_variable(
+            "PLUGIN_OIDC_TOKEN_ID",
+            "Harness: PLUGIN_OIDC_TOKEN_ID is not set. This could imply that OIDC \
+            is not enabled for the stage",
+        ));
+    };
+    check_audience("Harness", token, audience)
+}
+
+#[cfg(feature = "bitrise")]
+fn detect_bitrise(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Bitrise.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("BITRISE_IDENTITY_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "BITRISE_IDENTITY_TOKEN",
+            "Bitrise: BITRISE_IDENTITY_TOKEN is not set. This could imply that the \
+            workflow does not run the 'Get OIDC Identity Token' step",
+        ));
+    };
+    check_audience("Bitrise", token, audience)
+}
+
+#[cfg(feature = "spacelift")]
+fn detect_spacelift(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Spacelift.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("SPACELIFT_OIDC_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "SPACELIFT_OIDC_TOKEN",
+            "Spacelift: SPACELIFT_OIDC_TOKEN is not set",
+        ));
+    };
+    check_audience("Spacelift", token, audience)
+}
+
+#[cfg(feature = "env0")]
+fn detect_env0(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Env0.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("ENV0_OIDC_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "ENV0_OIDC_TOKEN",
+            "env0: ENV0_OIDC_TOKEN is not set",
+        ));
+    };
+    check_audience("env0", token, audience)
+}
+
+#[cfg(feature = "terraform")]
+fn detect_terraform(audience: Option<&str>) -> Result<String> {
+    // The default token is in TFC_WORKLOAD_IDENTITY_TOKEN, additional tokens are in
+    // "TFC_WORKLOAD_IDENTITY_TOKEN_<TAG>": we require <TAG> to be the sanitized audience string
+    if !CIEnvironment::HcpTerraform.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    if let Some(audience) = audience {
+        let var_name = format!(
+            "TFC_WORKLOAD_IDENTITY_TOKEN_{}",
+            sanitize_audience(audience)
+        );
+        log::debug!("HCP Terraform: Looking for token in {}", var_name);
+        if let Ok(token) = env_var(&var_name) {
+            return Ok(token);
+        }
+        if env_var("TFC_WORKLOAD_IDENTITY_TOKEN").is_err() {
+            return Err(CIIDError::missing_variable(
+                &var_name,
+                format!(
+                    "HCP Terraform: {} is not set. This could imply that the workspace does \
+                    not define TFC_WORKLOAD_IDENTITY_AUDIENCE_{}",
+                    var_name,
+                    sanitize_audience(audience)
+                ),
+            ));
+        }
+    }
+
+    // Default token is used if it matches the audience
+    let Ok(token) = env_var("TFC_WORKLOAD_IDENTITY_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "TFC_WORKLOAD_IDENTITY_TOKEN",
+            "HCP Terraform: TFC_WORKLOAD_IDENTITY_TOKEN is not set. This could imply that the \
+            workspace does not define TFC_WORKLOAD_IDENTITY_AUDIENCE",
+        ));
+    };
+    check_audience("HCP Terraform", token, audience)
+}
+
+#[cfg(feature = "pulumi")]
+fn detect_pulumi(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::PulumiDeployments.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("PULUMI_OIDC_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "PULUMI_OIDC_TOKEN",
+            "Pulumi Deployments: PULUMI_OIDC_TOKEN is not set. This could imply that OIDC \
+            is not configured in the deployment settings",
+        ));
+    };
+    check_audience("Pulumi Deployments", token, audience)
+}
+
+#[cfg(feature = "vercel")]
+fn detect_vercel(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Vercel.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("VERCEL_OIDC_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "VERCEL_OIDC_TOKEN",
+            "Vercel: VERCEL_OIDC_TOKEN is not set. This could imply that OIDC federation is not \
+            enabled for the project",
+        ));
+    };
+    check_audience("Vercel", token, audience)
+}
+
+#[cfg(feature = "fly")]
+const FLY_API_SOCKET: &str = "/.fly/api";
+
+#[cfg(feature = "fly")]
+fn detect_fly(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::FlyIo.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    request_fly_token(Path::new(FLY_API_SOCKET), audience)
+}
+
+#[derive(Serialize)]
+#[cfg(feature = "fly")]
+struct FlyTokenRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+}
+
+#[cfg(all(unix, feature = "fly"))]
+fn request_fly_token(socket: &Path, audience: Option<&str>) -> Result<String> {
+    let body = serde_json::to_string(&FlyTokenRequest { aud: audience }).unwrap();
+
+    let timeout = REQUEST_CONTEXT.with_borrow(request_timeout)?;
+    log::debug!("Fly.io: Requesting token");
+    match unix_socket_request(socket, "POST", "/v1/tokens/oidc", &body, timeout) {
+        Ok((200, token)) => Ok(token.trim().to_string()),
+        Ok((status, body)) => Err(CIIDError::HttpError {
+            status,
+            message: format!(
+                "Fly.io: Token request failed with status {}: {}",
+                status,
+                body.trim()
+            ),
+        }),
+        Err(e) => Err(CIIDError::io_error(
+            format!("Fly.io: Token request failed: {}", e),
+            e,
+        )),
+    }
+}
+
+#[cfg(all(not(unix), feature = "fly"))]
+fn request_fly_token(_socket: &Path, _audience: Option<&str>) -> Result<String> {
+    Err(CIIDError::EnvironmentError(
+        "Fly.io: Token requests are only supported on unix".into(),
+    ))
+}
+
+/// Makes a HTTP/1.0 request over a unix socket, returns the status code and the response body.
+/// Writing the request and reading the response each fail after `timeout`.
+#[cfg(all(unix, feature = "fly"))]
+fn unix_socket_request(
+    socket: &Path,
+    method: &str,
+    path: &str,
+    body: &str,
+    timeout: Duration,
+) -> std::io::Result<(u16, String)> {
+    use std::io::{Error, ErrorKind, Read, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_read_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+        Content-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed HTTP response");
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(malformed)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(malformed)?;
+    Ok((status, body.into()))
+}
+
+#[cfg(feature = "namespace")]
+fn detect_namespace(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Namespace.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let args = match audience {
+        Some(audience) => vec!["auth", "issue-id-token", "--audience", audience],
+        None => vec!["auth", "issue-id-token"],
+    };
+    let output = run_command("Namespace", CommandCall::new("nsc", &args))?;
+    token_from_output("Namespace", "nsc", &output)
+}
+
+#[cfg(feature = "concourse")]
+fn detect_concourse(audience: Option<&str>) -> Result<String> {
+    // Concourse does not expose build metadata to tasks: the token from the idtoken credential
+    // manager must be passed to the task in CONCOURSE_ID_TOKEN
+    let Ok(token) = env_var("CONCOURSE_ID_TOKEN") else {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+    check_audience("Concourse", token, audience)
+}
+
+#[cfg(feature = "aws")]
+fn detect_aws(audience: Option<&str>) -> Result<String> {
+    let Ok(path) = env_var("AWS_WEB_IDENTITY_TOKEN_FILE") else {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    log::debug!("AWS: Reading token from {}", path);
+    let token = match fs::read_to_string(&path) {
+        Ok(token) => token.trim_end().to_string(),
+        Err(e) => {
+            return Err(CIIDError::io_error(
+                format!("AWS: Failed to read token from {}: {}", path, e),
+                e,
+            ))
+        }
+    };
+    // The variable is set automatically by EKS: a token for another audience (usually
+    // "sts.amazonaws.com") just means this is not the identity the caller is looking for
+    match check_audience("AWS", token, audience) {
+        Ok(token) => Ok(token),
+        Err(_) => {
+            log::debug!("AWS: Token audience does not match");
+            Err(CIIDError::EnvironmentNotDetected)
+        }
+    }
+}
+
+#[cfg(feature = "kubernetes")]
+const KUBERNETES_DEFAULT_TOKEN_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+#[cfg(feature = "kubernetes")]
+fn detect_kubernetes(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Kubernetes.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    // A projected token with a custom audience can be in any path: it must be pointed to with
+    // KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE
+    if let Ok(path) = env_var("KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE") {
+        log::debug!("Kubernetes: Reading token from {}", path);
+        return match fs::read_to_string(&path) {
+            Ok(token) => check_audience("Kubernetes", token.trim_end().into(), audience),
+            Err(e) => Err(CIIDError::io_error(
+                format!("Kubernetes: Failed to read token from {}: {}", path, e),
+                e,
+            )),
+        };
+    }
+
+    // The default service account token is a Kubernetes API credential: it is only used if an
+    // audience is requested and the token happens to match it. Many CI systems run in a pod so
+    // a missing or mismatching token is not an error
+    if audience.is_none() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    }
+    log::debug!(
+        "Kubernetes: Reading token from {}",
+        KUBERNETES_DEFAULT_TOKEN_FILE
+    );
+    let Ok(token) = fs::read_to_string(KUBERNETES_DEFAULT_TOKEN_FILE) else {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+    match check_audience("Kubernetes", token.trim_end().into(), audience) {
+        Ok(token) => Ok(token),
+        Err(_) => Err(CIIDError::EnvironmentNotDetected),
+    }
+}
+
+#[cfg(feature = "spiffe")]
+fn detect_spiffe(audience: Option<&str>) -> Result<String> {
+    let Ok(socket) = env_var("SPIFFE_ENDPOINT_SOCKET") else {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+    // The workload API is gRPC: use the SPIRE agent CLI to make the call. Workloads of other
+    // SPIFFE implementations often do not have it: leave the socket to other tools then
+    if !program_available("spire-agent") {
+        log::debug!(
+            "SPIFFE: spire-agent is not available, ignoring SPIFFE_ENDPOINT_SOCKET {}",
+            socket
+        );
+        return Err(CIIDError::EnvironmentNotDetected);
+    }
+    let Some(audience) = audience else {
+        return Err(CIIDError::EnvironmentError(
+            "SPIFFE: audience must be set".into(),
+        ));
+    };
+
+    let socket_path = socket.strip_prefix("unix://").unwrap_or(&socket);
+    let args = [
+        "api",
+        "fetch",
+        "jwt",
+        "-audience",
+        audience,
+        "-socketPath",
+        socket_path,
+    ];
+    let stdout = run_command("SPIFFE", CommandCall::new("spire-agent", &args))?;
+
+    // Output contains "token(<SPIFFE ID>):" line followed by the indented token
+    let mut lines = stdout.lines();
+    match lines.find(|line| line.starts_with("token(")) {
+        Some(_) => match lines.next() {
+            Some(token) => Ok(token.trim().to_string()),
+            None => Err(CIIDError::EnvironmentError(
+                "SPIFFE: Failed to read token".into(),
+            )),
+        },
+        None => Err(CIIDError::EnvironmentError(
+            "SPIFFE: Failed to read token".into(),
+        )),
+    }
+}
+
+fn detect_env_token(audience: Option<&str>) -> Result<String> {
+    // Generic fallback for environments ci-id does not support: token is read from
+    // * "CI_ID_TOKEN_<AUDIENCE>" where <AUDIENCE> is the sanitized audience string, or
+    // * "CI_ID_TOKEN": the token audience must then match the requested audience
+    if let Some(audience) = audience {
+        let var_name = format!("CI_ID_TOKEN_{}", sanitize_audience(audience));
+        log::debug!("Environment variable: Looking for token in {}", var_name);
+        if let Ok(token) = env_var(&var_name) {
+            return Ok(token);
+        }
+    }
+    log::debug!("Environment variable: Looking for token in CI_ID_TOKEN");
+    match env_var("CI_ID_TOKEN") {
+        Ok(token) => check_audience("Environment variable", token, audience),
+        Err(_) => Err(CIIDError::EnvironmentNotDetected),
+    }
+}
+
+fn detect_token_file(audience: Option<&str>) -> Result<String> {
+    // Generic fallback for environments that provide tokens as files: the path is read from
+    // * "CI_ID_TOKEN_FILE_<AUDIENCE>" where <AUDIENCE> is the sanitized audience string, or
+    // * "CI_ID_TOKEN_FILE": the token audience must then match the requested audience
+    let audience_var_name =
+        audience.map(|aud| format!("CI_ID_TOKEN_FILE_{}", sanitize_audience(aud)));
+    let (path, fallback) = match audience_var_name.and_then(|var_name| env_var(&var_name).ok()) {
+        Some(path) => (path, false),
+        None => match env_var("CI_ID_TOKEN_FILE") {
+            Ok(path) => (path, true),
+            Err(_) => return Err(CIIDError::EnvironmentNotDetected),
+        },
+    };
+    log::debug!("Token file: Reading token from {}", path);
+    let token = match fs::read_to_string(&path) {
+        Ok(token) => token.trim().to_string(),
+        Err(e) => {
+            return Err(CIIDError::io_error(
+                format!("Token file: Failed to read token from {}: {}", path, e),
+                e,
+            ))
+        }
+    };
+    match fallback {
+        true => check_audience("Token file", token, audience),
+        false => Ok(token),
+    }
+}
+
+fn detect_token_command(audience: Option<&str>) -> Result<String> {
+    // Generic fallback that runs a user specified shell command: the audience is available to
+    // the command in CI_ID_AUDIENCE
+    let Ok(token_command) = env_var("CI_ID_TOKEN_COMMAND") else {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let call = CommandCall::shell(&token_command).env("CI_ID_AUDIENCE", audience);
+    let output = run_command("Token command", call)?;
+    Ok(output.trim().to_string())
+}
+
+#[cfg(feature = "codespaces")]
+fn detect_codespaces(_audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::GitHubCodespaces.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    // Codespaces does not offer an id token for the workload (GITHUB_TOKEN in a codespace
+    // is not an OIDC token): fail with a clear error instead of not detecting anything
+    Err(CIIDError::EnvironmentError(
+        "GitHub Codespaces: Codespaces does not provide ambient OIDC credentials. \
+        Use GitHub Actions to get a GitHub workflow identity"
+            .into(),
+    ))
+}
+
+#[cfg(feature = "depot")]
+fn detect_depot(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Depot.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("DEPOT_OIDC_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "DEPOT_OIDC_TOKEN",
+            "Depot: DEPOT_OIDC_TOKEN is not set",
+        ));
+    };
+    check_audience("Depot", token, audience)
+}
+
+#[cfg(feature = "garnix")]
+fn detect_garnix(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Garnix.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("GARNIX_ID_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "GARNIX_ID_TOKEN",
+            "Garnix: GARNIX_ID_TOKEN is not set. This could imply that the action does not \
+            have identity tokens enabled",
+        ));
+    };
+    check_audience("Garnix", token, audience)
+}
+
+#[cfg(feature = "railway")]
+fn detect_railway(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Railway.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("RAILWAY_OIDC_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "RAILWAY_OIDC_TOKEN",
+            "Railway: RAILWAY_OIDC_TOKEN is not set. This could imply that OIDC tokens are not \
+            enabled for the service",
+        ));
+    };
+    check_audience("Railway", token, audience)
+}
+
+#[cfg(feature = "scaleway")]
+fn detect_scaleway(audience: Option<&str>) -> Result<String> {
+    if !CIEnvironment::Scaleway.is_present() {
+        return Err(CIIDError::EnvironmentNotDetected);
+    };
+
+    let Ok(token) = env_var("SCW_ID_TOKEN") else {
+        return Err(CIIDError::missing_variable(
+            "SCW_ID_TOKEN",
+            "Scaleway Serverless Jobs: SCW_ID_TOKEN is not set. This could imply that workload \
+            identity is not enabled for the job definition",
+        ));
+    };
+    check_audience("Scaleway Serverless Jobs", token, audience)
+}
+
+// The tests cover all detectors
+#[cfg(all(test, feature = "all-detectors"))]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use std::{
+        fs::File,
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    const TOKEN: &str = "eyJhbGciOiJSUzI1NiIsImtpZCI6IjMxNjA2OGMzM2ZhMjg2OTZhZmI5YzM5YWI2OTMxMjY1ZDk0Y2I3NTUifQ.eyJpc3MiOiJodHRwczovL29hdXRoMi5zaWdzdG9yZS5kZXYvYXV0aCIsInN1YiI6IkNnVXpNVGc0T1JJbWFIUjBjSE02SlRKR0pUSkdaMmwwYUhWaUxtTnZiU1V5Um14dloybHVKVEpHYjJGMWRHZyIsImF1ZCI6InNpZ3N0b3JlIiwiZXhwIjoxNzI5NTEyOTMwLCJpYXQiOjE3Mjk1MTI4NzAsIm5vbmNlIjoiNTI3NjM3Y2UtN2Q2MS00MDA5LThkM2EtNGNjZGM3OGJiZDg1IiwiYXRfaGFzaCI6IktmMUNPTXB5TVJDTkdzWWp1QXczclEiLCJlbWFpbCI6ImprdUBnb3RvLmZpIiwiZW1haWxfdmVyaWZpZWQiOnRydWUsImZlZGVyYXRlZF9jbGFpbXMiOnsiY29ubmVjdG9yX2lkIjoiaHR0cHM6Ly9naXRodWIuY29tL2xvZ2luL29hdXRoIiwidXNlcl9pZCI6IjMxODg5In19.s27uZ3vpIzRS4eWdC3pM0FSsYkHNvScQoii_TcSRVZhtrcPAbA4D95Pw_R_UB-qRquMK1BHepKmeN1b1-CQ00jiFZgUOf9sDLC3Hy3oQejGJsYKb-7oeHs7amLz3SBzPwDwVd09e-7Yu1x9YV5k6aezqruLLt42C_kyOTsHeCIWWMEVmGp32105Jkj8YT5uEYXS-aOEvQFvAYsDfKgGuiJtGybUycVcJEfqyWI3cami7fkjU5PcCx8oFyP2E7YNRw4UeNWCTn7WFtL2onrgDm0oa2AqF3gtH4Q-9ByksVq3y6xQdoLj1ydzWcoCzsF43oZ6O6DkLmWk5fu3FxNyewg";
+
+    #[cfg(unix)]
+    fn create_fake_executable(name: &str, script: &str) -> PathBuf {
+        // Returns a temporary directory containing an executable `name` that runs `script`
+        let dir_path = tempfile::tempdir().unwrap().into_path();
+        let path = dir_path.join(name);
+        let mut f = File::create(&path).unwrap();
+        f.write_all(script.as_bytes()).unwrap();
+        let mut permissions = f.metadata().unwrap().permissions();
+        drop(f);
+        permissions.set_mode(0o744);
+        fs::set_permissions(path, permissions).unwrap();
+        dir_path
+    }
+
+    /// Returns an environment snapshot containing `vars`
+    fn snapshot<const N: usize>(vars: [(&str, &str); N]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn buildkite_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_buildkite(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn buildkite_env_failure() {
+        with_env(
+            // empty the path so that this does not accidentally succeed on buildkite
+            snapshot([("BUILDKITE", "1"), ("PATH", "")]),
+            || {
+                assert!(matches!(
+                    detect_buildkite("my-audience".into()).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        // agent API request fails
+        #[cfg(feature = "reqwest")]
+        with_env(
+            snapshot([
+                ("BUILDKITE", "1"),
+                ("PATH", ""),
+                ("BUILDKITE_AGENT_ACCESS_TOKEN", "token"),
+                ("BUILDKITE_AGENT_ENDPOINT", "http://invalid/v3"),
+                ("BUILDKITE_JOB_ID", "01234567-89ab-cdef-0123-456789abcdef"),
+            ]),
+            || {
+                let err = detect_buildkite("my-audience".into()).unwrap_err();
+                assert!(matches!(err, CIIDError::RequestFailed { .. }));
+                assert_eq!(
+                    err.to_string(),
+                    "credential detection failed: Buildkite: Token request failed: error sending \
+                    request for url \
+                    (http://invalid/v3/jobs/01234567-89ab-cdef-0123-456789abcdef/oidc/tokens)"
+                );
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn buildkite_success() {
+        // create a fake 'buildkite-agent' executable
+        let dir_path = create_fake_executable(
+            "buildkite-agent",
+            &format!("#!/bin/sh\necho -n {}\n", TOKEN),
+        );
+
+        // Make sure the fake executable is in PATH, then test non-default audience
+        with_env(
+            snapshot([("BUILDKITE", "1"), ("PATH", dir_path.to_str().unwrap())]),
+            || {
+                assert_eq!(detect_buildkite("my-audience".into()), Ok(TOKEN.into()));
+            },
+        );
+
+        // Make sure the fake executable is in PATH, then test default audience
+        with_env(
+            snapshot([("BUILDKITE", "1"), ("PATH", dir_path.to_str().unwrap())]),
+            || {
+                assert_eq!(detect_buildkite(None), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    /// Runner that pretends every program is available and returns a canned output
+    struct MockRunner(fn(&CommandCall) -> CommandOutput);
+
+    impl CommandRunner for MockRunner {
+        fn is_available(&self, _program: &str) -> bool {
+            true
+        }
+
+        fn run(&self, call: &CommandCall) -> std::io::Result<CommandOutput> {
+            Ok((self.0)(call))
+        }
+    }
+
+    #[test]
+    fn buildkite_token_options() {
+        with_env(
+            snapshot([
+                ("BUILDKITE", "1"),
+                ("CI_ID_BUILDKITE_LIFETIME", "300"),
+                ("CI_ID_BUILDKITE_CLAIMS", "organization_id, pipeline_id"),
+            ]),
+            || {
+                let runner = MockRunner(|call| {
+                    assert_eq!(
+                        call.args,
+                        [
+                            "oidc",
+                            "request-token",
+                            "--audience",
+                            "my-audience",
+                            "--lifetime",
+                            "300",
+                            "--claim",
+                            "organization_id,pipeline_id"
+                        ]
+                    );
+                    CommandOutput::new(Some(0), TOKEN)
+                });
+                assert_eq!(
+                    with_command_runner(runner, || detect_buildkite(Some("my-audience"))),
+                    Ok(TOKEN.into())
+                );
+
+                // request options override the environment
+                let runner = MockRunner(|call| {
+                    assert_eq!(
+                        call.args,
+                        [
+                            "oidc",
+                            "request-token",
+                            "--lifetime",
+                            "60",
+                            "--claim",
+                            "pipeline_id"
+                        ]
+                    );
+                    CommandOutput::new(Some(0), TOKEN)
+                });
+                let credential = with_command_runner(runner, || {
+                    CredentialRequest::new()
+                        .providers([CIEnvironment::Buildkite])
+                        .buildkite_lifetime(Duration::from_secs(60))
+                        .buildkite_claim("pipeline_id")
+                        .detect()
+                });
+                assert_eq!(credential.unwrap().token.expose(), TOKEN);
+            },
+        );
+
+        // the agent API request includes the options
+        with_env(
+            snapshot([
+                ("BUILDKITE", "1"),
+                ("PATH", ""),
+                ("BUILDKITE_AGENT_ACCESS_TOKEN", "token"),
+                ("BUILDKITE_JOB_ID", "job"),
+                ("CI_ID_BUILDKITE_LIFETIME", "300"),
+                ("CI_ID_BUILDKITE_CLAIMS", "organization_id"),
+            ]),
+            || {
+                let transport = MockTransport(|request| {
+                    let body: serde_json::Value =
+                        serde_json::from_slice(request.body.as_ref().unwrap()).unwrap();
+                    assert_eq!(
+                        body,
+                        serde_json::json!({
+                            "audience": "my-audience",
+                            "lifetime": 300,
+                            "claims": ["organization_id"]
+                        })
+                    );
+                    HttpResponse::new(200, format!("{{\"token\": \"{}\"}}", TOKEN))
+                });
+                assert_eq!(
+                    with_transport(transport, || detect_buildkite(Some("my-audience"))),
+                    Ok(TOKEN.into())
+                );
+            },
+        );
+
+        with_env(
+            snapshot([("BUILDKITE", "1"), ("CI_ID_BUILDKITE_LIFETIME", "forever")]),
+            || {
+                let runner = MockRunner(|_| CommandOutput::new(Some(0), TOKEN));
+                assert!(matches!(
+                    with_command_runner(runner, || detect_buildkite(None)),
+                    Err(CIIDError::EnvironmentError(_))
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn buildkite_command_runner() {
+        with_env(snapshot([("BUILDKITE", "1")]), || {
+            let runner = MockRunner(|call| {
+                assert_eq!(call.program, "buildkite-agent");
+                assert_eq!(
+                    call.args,
+                    ["oidc", "request-token", "--audience", "my-audience"]
+                );
+                CommandOutput::new(Some(0), format!("{}\n", TOKEN))
+            });
+            assert_eq!(
+                with_command_runner(runner, || detect_buildkite(Some("my-audience"))),
+                Ok(TOKEN.into())
+            );
+
+            // warnings around the token are ignored
+            let runner = MockRunner(|_| {
+                CommandOutput::new(
+                    Some(0),
+                    format!("WARN: agent is outdated\r\n  {}  \r\n\n", TOKEN),
+                )
+            });
+            assert_eq!(
+                with_command_runner(runner, || detect_buildkite(None)),
+                Ok(TOKEN.into())
+            );
+
+            let runner = MockRunner(|_| CommandOutput::new(Some(0), "WARN: no token for you\n"));
+            assert_eq!(
+                with_command_runner(runner, || detect_buildkite(None)),
+                Err(CIIDError::EnvironmentError(
+                    "Buildkite: buildkite-agent output does not contain a token".into()
+                ))
+            );
+
+            let runner = MockRunner(|_| CommandOutput::new(Some(1), "").stderr("not allowed"));
+            assert_eq!(
+                with_command_runner(runner, || detect_buildkite(None)),
+                Err(CIIDError::SubprocessFailed {
+                    exit_code: Some(1),
+                    message: "Buildkite: buildkite-agent failed with exit code 1: not allowed"
+                        .into()
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn circleci_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_circleci(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn circleci_env_failure() {
+        with_env(
+            // empty the path so that this does not accidentally succeed on CircleCI
+            snapshot([("CIRCLECI", "1"), ("PATH", "")]),
+            || {
+                assert!(matches!(
+                    detect_circleci("my-audience".into()).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        with_env(
+            // default audience uses specific env var
+            snapshot([("CIRCLECI", "1")]),
+            || {
+                assert!(matches!(
+                    detect_circleci(None).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn circleci_without_cli() {
+        // TOKEN audience is "sigstore": pretend that is the organization id
+        with_env(
+            snapshot([
+                ("CIRCLECI", "1"),
+                ("CIRCLE_OIDC_TOKEN_V2", TOKEN),
+                ("PATH", ""),
+            ]),
+            || {
+                assert_eq!(detect_circleci("sigstore".into()), Ok(TOKEN.into()));
+                assert!(matches!(
+                    detect_circleci("my-audience".into()).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn circleci_claims() {
+        with_env(snapshot([("CIRCLECI", "1")]), || {
+            // the runner fails with the requested claims in stderr
+            let claims = |request: CredentialRequest| {
+                let runner = MockRunner(|call| {
+                    assert_eq!(call.program, "circleci");
+                    assert_eq!(call.args[..4], ["run", "oidc", "get", "--claims"]);
+                    CommandOutput::new(Some(1), "").stderr(call.args[4].as_str())
+                });
+                let credential = with_command_runner(runner, || {
+                    request.providers([CIEnvironment::CircleCI]).detect()
+                });
+                match credential {
+                    Err(CIIDError::SubprocessFailed { message, .. }) => {
+                        let (_, claims) = message
+                            .split_once(": circleci failed with exit code 1: ")
+                            .unwrap();
+                        serde_json::from_str::<serde_json::Value>(claims).unwrap()
+                    }
+                    result => panic!("unexpected result {:?}", result),
+                }
+            };
+
+            // the audience is JSON encoded
+            assert_eq!(
+                claims(CredentialRequest::new().audience("my \"quoted\" audience")),
+                serde_json::json!({"aud": "my \"quoted\" audience"})
+            );
+            assert_eq!(
+                claims(
+                    CredentialRequest::new()
+                        .audience("my-audience")
+                        .circleci_claim("deployment", "production")
+                        .circleci_claim("aud", "overridden")
+                ),
+                serde_json::json!({"aud": "my-audience", "deployment": "production"})
+            );
+            // custom claims without audience use the CLI as well
+            assert_eq!(
+                claims(CredentialRequest::new().circleci_claim("retries", 2)),
+                serde_json::json!({"retries": 2})
+            );
+        });
+
+        with_env(
+            snapshot([
+                ("CIRCLECI", "1"),
+                ("CIRCLE_OIDC_TOKEN_V2", TOKEN),
+                ("PATH", ""),
+            ]),
+            || {
+                let result = CredentialRequest::new()
+                    .providers([CIEnvironment::CircleCI])
+                    .circleci_claim("deployment", "production")
+                    .detect();
+                assert!(matches!(result, Err(CIIDError::EnvironmentError(_))));
+            },
+        );
+    }
+
+    #[test]
+    fn circleci_legacy_token() {
+        // legacy token is not used by default
+        with_env(
+            snapshot([("CIRCLECI", "1"), ("CIRCLE_OIDC_TOKEN", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_circleci(None).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        with_env(
+            snapshot([
+                ("CIRCLECI", "1"),
+                ("CIRCLE_OIDC_TOKEN", TOKEN),
+                ("CI_ID_ALLOW_LEGACY_TOKENS", "1"),
+            ]),
+            || {
+                assert_eq!(detect_circleci(None), Ok(TOKEN.into()));
+                let credential = detect_credential(None).unwrap();
+                assert_eq!(credential.provider, "CircleCI");
+                assert_eq!(
+                    credential.legacy_source.as_deref(),
+                    Some("CIRCLE_OIDC_TOKEN")
+                );
+            },
+        );
+
+        // the v2 token is not legacy
+        with_env(
+            snapshot([
+                ("CIRCLECI", "1"),
+                ("CIRCLE_OIDC_TOKEN_V2", TOKEN),
+                ("CIRCLE_OIDC_TOKEN", "a.b.c"),
+                ("CI_ID_ALLOW_LEGACY_TOKENS", "1"),
+            ]),
+            || {
+                assert_eq!(detect_credential(None).unwrap().legacy_source, None);
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn circleci_success() {
+        // create a fake 'circleci' executable
+        let dir_path =
+            create_fake_executable("circleci", &format!("#!/bin/sh\necho -n {}\n", TOKEN));
+
+        // Make sure the fake executable is in PATH, then test non-default audience
+        with_env(
+            snapshot([("CIRCLECI", "1"), ("PATH", dir_path.to_str().unwrap())]),
+            || {
+                assert_eq!(detect_circleci("my-audience".into()), Ok(TOKEN.into()));
+            },
+        );
+
+        with_env(
+            snapshot([("CIRCLECI", "1"), ("CIRCLE_OIDC_TOKEN_V2", TOKEN)]),
+            || {
+                assert_eq!(detect_circleci(None), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn github_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_github(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn github_env_failure() {
+        // Missing env variables
+        with_env(snapshot([("GITHUB_ACTIONS", "1")]), || {
+            assert!(matches!(
+                detect_github(None).unwrap_err(),
+                CIIDError::MissingPermission { .. }
+            ));
+        });
+        with_env(
+            snapshot([
+                ("GITHUB_ACTIONS", "1"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_github(None).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        // request fails
+        #[cfg(feature = "reqwest")]
+        with_env(
+            snapshot([
+                ("GITHUB_ACTIONS", "1"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+                ("ACTIONS_ID_TOKEN_REQUEST_URL", "http://invalid"),
+            ]),
+            || {
+                let err = detect_github(None).unwrap_err();
+                assert!(matches!(err, CIIDError::RequestFailed { .. }));
+                assert_eq!(
+                    err.to_string(),
+                    "credential detection failed: GitHub Actions: Token request failed: error \
+                    sending request for url (http://invalid/)"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn github_not_detected_on_gitea_and_forgejo() {
+        with_env(
+            snapshot([("GITHUB_ACTIONS", "true"), ("GITEA_ACTIONS", "true")]),
+            || {
+                assert_eq!(detect_github(None), Err(CIIDError::EnvironmentNotDetected));
+            },
+        );
+        with_env(
+            snapshot([("GITHUB_ACTIONS", "true"), ("FORGEJO_ACTIONS", "true")]),
+            || {
+                assert_eq!(detect_github(None), Err(CIIDError::EnvironmentNotDetected));
+            },
+        );
+    }
+
+    #[test]
+    fn gitea_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_gitea(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+
+        // Forgejo is handled by its own detector
+        with_env(
+            snapshot([("GITEA_ACTIONS", "true"), ("FORGEJO_ACTIONS", "true")]),
+            || {
+                assert_eq!(detect_gitea(None), Err(CIIDError::EnvironmentNotDetected));
+            },
+        );
+    }
+
+    #[test]
+    fn gitea_env_failure() {
+        with_env(
+            snapshot([("GITHUB_ACTIONS", "true"), ("GITEA_ACTIONS", "true")]),
+            || {
+                assert_eq!(
+                    detect_gitea(None),
+                    Err(CIIDError::MissingPermission {
+                        message: "Gitea Actions: ACTIONS_ID_TOKEN_REQUEST_TOKEN is not set. \
+                        This could imply that the job does not have 'id-token: write' permission"
+                            .into()
+                    })
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn forgejo_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_forgejo(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn forgejo_env_failure() {
+        #[cfg(feature = "reqwest")]
+        with_env(
+            snapshot([
+                ("FORGEJO_ACTIONS", "true"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+                ("ACTIONS_ID_TOKEN_REQUEST_URL", "http://invalid"),
+            ]),
+            || {
+                let err = detect_forgejo(None).unwrap_err();
+                assert!(matches!(err, CIIDError::RequestFailed { .. }));
+                assert_eq!(
+                    err.to_string(),
+                    "credential detection failed: Forgejo Actions: Token request failed: error \
+                    sending request for url (http://invalid/)"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn github_expected_issuer() {
+        for (server_url, issuer_override, expected) in [
+            (None, None, "https://token.actions.githubusercontent.com"),
+            (
+                Some("https://github.com"),
+                None,
+                "https://token.actions.githubusercontent.com",
+            ),
+            (
+                Some("https://octocorp.ghe.com"),
+                None,
+                "https://token.actions.octocorp.ghe.com",
+            ),
+            (
+                Some("https://github.example.com/"),
+                None,
+                "https://github.example.com/_services/token",
+            ),
+            (
+                Some("https://github.example.com"),
+                Some("https://issuer.example.com"),
+                "https://issuer.example.com",
+            ),
+        ] {
+            let mut env = snapshot([]);
+            if let Some(server_url) = server_url {
+                env.insert("GITHUB_SERVER_URL".into(), server_url.into());
+            }
+            if let Some(issuer) = issuer_override {
+                env.insert("CI_ID_GITHUB_ISSUER".into(), issuer.into());
+            }
+            with_env(env, || {
+                assert_eq!(github_issuer(), expected);
+            });
+        }
+    }
+
+    #[test]
+    fn github_issuer_mismatch() {
+        let token_with_issuer = |iss: &str| {
+            let payload = URL_SAFE_NO_PAD.encode(format!(r#"{{"iss":"{}"}}"#, iss));
+            format!("eyJhbGciOiJub25lIn0.{}.sig", payload)
+        };
+        let enterprise = token_with_issuer("https://token.actions.githubusercontent.com/octocorp");
+        let nested = token_with_issuer("https://token.actions.githubusercontent.com/a/b");
+
+        // TOKEN issuer is "https://oauth2.sigstore.dev/auth": a mismatch is only logged by default
+        with_env(snapshot([]), || {
+            assert_eq!(check_github_issuer(TOKEN.into()), Ok(TOKEN.into()));
+            assert_eq!(check_github_issuer("a.b.c".into()), Ok("a.b.c".into()));
+            assert_eq!(
+                check_github_issuer(enterprise.clone()),
+                Ok(enterprise.clone())
+            );
+        });
+
+        // the issuer is enforced when set explicitly
+        with_env(
+            snapshot([(
+                "CI_ID_GITHUB_ISSUER",
+                "https://token.actions.githubusercontent.com",
+            )]),
+            || {
+                assert_eq!(
+                    check_github_issuer(TOKEN.into()),
+                    Err(CIIDError::EnvironmentError(
+                        "GitHub Actions: Token issuer 'https://oauth2.sigstore.dev/auth' does \
+                        not match expected issuer 'https://token.actions.githubusercontent.com'"
+                            .into()
+                    ))
+                );
+                assert_eq!(
+                    check_github_issuer("a.b.c".into()),
+                    Err(CIIDError::EnvironmentError(
+                        "GitHub Actions: Failed to read token issuer".into()
+                    ))
+                );
+                assert_eq!(
+                    check_github_issuer(enterprise.clone()),
+                    Ok(enterprise.clone())
+                );
+                assert!(check_github_issuer(nested.clone()).is_err());
+            },
+        );
+        with_env(
+            snapshot([("CI_ID_GITHUB_ISSUER", "https://oauth2.sigstore.dev/auth")]),
+            || {
+                assert_eq!(check_github_issuer(TOKEN.into()), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn error_source() {
+        with_env(
+            snapshot([("CI_ID_TOKEN_FILE", "/nonexistent/token")]),
+            || {
+                let err = detect_token_file(None).unwrap_err();
+                let source = std::error::Error::source(&err).unwrap();
+                assert!(source.downcast_ref::<std::io::Error>().is_some());
+
+                let boxed: Box<dyn std::error::Error + Send + Sync> = err.into();
+                assert!(boxed
+                    .to_string()
+                    .starts_with("credential detection failed: Token file"));
+            },
+        );
+        assert!(std::error::Error::source(&CIIDError::EnvironmentNotDetected).is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    fn http_client_ca_bundle() {
+        with_env(
+            snapshot([("CI_ID_CA_BUNDLE", "/nonexistent/ca.pem")]),
+            || {
+                assert!(matches!(
+                    http_client("Test").unwrap_err(),
+                    CIIDError::IoError { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    fn root_certificate_settings() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config_path = tmpdir.path().join("ci-id.toml");
+        fs::write(&config_path, "ca_bundle = \"/nonexistent/config-ca.pem\"\n").unwrap();
+        let env = snapshot([("CI_ID_CONFIG", config_path.to_str().unwrap())]);
+        with_env(env, || {
+            let context = RequestContext {
+                config: Arc::new(load_config().unwrap()),
+                ..Default::default()
+            };
+            let settings = ClientSettings::new(&context).unwrap();
+            assert_eq!(
+                settings.ca_bundle.as_deref(),
+                Some("/nonexistent/config-ca.pem")
+            );
+            let err = root_certificates("Test", &settings).unwrap_err();
+            assert!(matches!(err, CIIDError::IoError { .. }));
+            assert!(err
+                .to_string()
+                .contains("Test: Failed to read CA bundle /nonexistent/config-ca.pem"));
+        });
+
+        let context = RequestContext {
+            root_certificates: vec![b"-----BEGIN CERTIFICATE-----\nnot base64\n".to_vec()],
+            ..Default::default()
+        };
+        with_env(snapshot([]), || {
+            let settings = ClientSettings::new(&context).unwrap();
+            let err = root_certificates("Test", &settings).unwrap_err();
+            assert!(matches!(err, CIIDError::RequestFailed { .. }));
+            assert!(err.to_string().contains("Test: Invalid root certificate"));
+        });
+    }
+
+    /// Transport that returns a canned response
+    struct MockTransport(fn(&HttpRequest) -> HttpResponse);
+
+    impl HttpTransport for MockTransport {
+        fn send(&self, request: &HttpRequest) -> std::result::Result<HttpResponse, BoxError> {
+            Ok((self.0)(request))
+        }
+    }
+
+    #[test]
+    fn github_success() {
+        with_env(
+            snapshot([
+                ("GITHUB_ACTIONS", "1"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+                (
+                    "ACTIONS_ID_TOKEN_REQUEST_URL",
+                    "https://example.com/token?api-version=2.0",
+                ),
+                ("CI_ID_GITHUB_ISSUER", "https://oauth2.sigstore.dev/auth"),
+            ]),
+            || {
+                let transport = MockTransport(|request| {
+                    assert_eq!(request.method, "GET");
+                    assert_eq!(
+                        request.url,
+                        "https://example.com/token?api-version=2.0&audience=my-audience"
+                    );
+                    assert_eq!(
+                        request.headers,
+                        [
+                            ("authorization", "bearer token".to_string()),
+                            ("user-agent", DEFAULT_USER_AGENT.to_string())
+                        ]
+                    );
+                    HttpResponse::new(200, format!("{{\"value\": \"{}\"}}", TOKEN))
+                });
+                assert_eq!(
+                    with_transport(transport, || detect_github(Some("my-audience"))),
+                    Ok(TOKEN.into())
+                );
+
+                let transport = MockTransport(|_| HttpResponse::new(403, "forbidden\n"));
+                assert_eq!(
+                    with_transport(transport, || detect_github(None)),
+                    Err(CIIDError::MissingPermission {
+                        message: "GitHub Actions: Token request was denied with status 403: \
+                            forbidden. Check that the job has 'id-token: write' permission"
+                            .into()
+                    })
+                );
+
+                let transport = MockTransport(|_| HttpResponse::new(400, "bad audience"));
+                assert_eq!(
+                    with_transport(transport, || detect_github(Some("my-audience"))),
+                    Err(CIIDError::HttpError {
+                        status: 400,
+                        message: "GitHub Actions: Token request failed with status 400: \
+                            bad audience. Check that the requested audience is valid"
+                            .into()
+                    })
+                );
+
+                let transport = MockTransport(|_| HttpResponse::new(503, ""));
+                assert!(matches!(
+                    with_transport(transport, || detect_github(None)),
+                    Err(CIIDError::HttpError { status: 503, message })
+                        if message.ends_with("temporarily unavailable: try again later")
+                ));
+
+                let transport = MockTransport(|_| HttpResponse::new(404, "not found"));
+                assert_eq!(
+                    with_transport(transport, || detect_github(None)),
+                    Err(http_error("GitHub Actions", 404, "not found"))
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn gitlab_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_gitlab(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn gitlab_env_failure() {
+        // GitLab does not support default audience
+        with_env(snapshot([("GITLAB_CI", "1")]), || {
+            assert!(matches!(
+                detect_gitlab(None).unwrap_err(),
+                CIIDError::EnvironmentError(_)
+            ));
+        });
+
+        // Missing token variable for non-default audience
+        with_env(snapshot([("GITLAB_CI", "1")]), || {
+            assert!(matches!(
+                detect_gitlab(Some("my-aud")).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+    }
+
+    #[test]
+    fn gitlab_legacy_token() {
+        // legacy token is not used by default
+        with_env(
+            snapshot([("GITLAB_CI", "1"), ("CI_JOB_JWT_V2", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_gitlab(Some("sigstore")).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        with_env(
+            snapshot([
+                ("GITLAB_CI", "1"),
+                ("CI_JOB_JWT_V2", TOKEN),
+                ("CI_ID_ALLOW_LEGACY_TOKENS", "1"),
+            ]),
+            || {
+                assert_eq!(detect_gitlab(None), Ok(TOKEN.into()));
+                assert_eq!(detect_gitlab(Some("sigstore")), Ok(TOKEN.into()));
+                let credential = detect_credential(Some("sigstore")).unwrap();
+                assert_eq!(credential.provider, "GitLab Pipelines");
+                assert_eq!(credential.legacy_source.as_deref(), Some("CI_JOB_JWT_V2"));
+                // legacy token audience does not match
+                assert!(matches!(
+                    detect_gitlab(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn gitlab_discover_token() {
+        // TOKEN audience is "sigstore"
+        with_env(
+            snapshot([
+                ("GITLAB_CI", "1"),
+                ("MY_SIGNING_ID_TOKEN", TOKEN),
+                ("OTHER_ID_TOKEN", "not.a.token"),
+            ]),
+            || {
+                // discovery is not used by default
+                assert!(matches!(
+                    detect_gitlab(Some("sigstore")).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        with_env(
+            snapshot([
+                ("GITLAB_CI", "1"),
+                ("MY_SIGNING_ID_TOKEN", TOKEN),
+                ("OTHER_ID_TOKEN", "not.a.token"),
+                ("CI_ID_GITLAB_DISCOVER_TOKENS", "1"),
+            ]),
+            || {
+                assert_eq!(detect_gitlab(Some("sigstore")), Ok(TOKEN.into()));
+                assert!(matches!(
+                    detect_gitlab(Some("my-aud")).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn gitlab_token_variable_mapping() {
+        with_env(
+            snapshot([
+                ("GITLAB_CI", "1"),
+                (
+                    "CI_ID_GITLAB_TOKEN_VARIABLES",
+                    "https://example.com=EXAMPLE_TOKEN, sigstore = SIGNING_TOKEN",
+                ),
+                ("SIGNING_TOKEN", TOKEN),
+                ("MY_AUD_ID_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_gitlab(Some("sigstore")), Ok(TOKEN.into()));
+                // audiences that are not mapped use the default name
+                assert_eq!(detect_gitlab(Some("my-aud")), Ok(TOKEN.into()));
+                assert_eq!(
+                    detect_gitlab(Some("https://example.com")),
+                    Err(CIIDError::missing_variable(
+                        "EXAMPLE_TOKEN",
+                        "GitLab Pipelines: EXAMPLE_TOKEN is not set. This could imply that the \
+                        pipeline does not define an id token with that name"
+                    ))
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn gitlab_token_variable_option() {
+        with_env(
+            snapshot([
+                ("GITLAB_CI", "1"),
+                ("SIGSTORE_ID_TOKEN", "other.sigstore.token"),
+                ("HOUSE_STYLE_OIDC", TOKEN),
+            ]),
+            || {
+                let request = CredentialRequest::new()
+                    .providers([CIEnvironment::GitLab])
+                    .gitlab_token_variable("HOUSE_STYLE_OIDC");
+                // the variable is used with and without audience
+                assert_eq!(request.detect().unwrap().token.expose(), TOKEN);
+                let credential = request.clone().audience("sigstore").detect().unwrap();
+                assert_eq!(credential.token.expose(), TOKEN);
+
+                let request = CredentialRequest::new()
+                    .providers([CIEnvironment::GitLab])
+                    .audience("sigstore")
+                    .gitlab_token_variable("MISSING_OIDC");
+                assert!(matches!(
+                    request.detect().unwrap_err(),
+                    CIIDError::MissingVariable { name, .. } if name == "MISSING_OIDC"
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn gitlab_success() {
+        with_env(
+            snapshot([("GITLAB_CI", "1"), ("MY_AUD_ID_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(detect_gitlab(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn jenkins_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_jenkins(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn jenkins_env_failure() {
+        with_env(
+            snapshot([("JENKINS_URL", "https://jenkins.example.com/")]),
+            || {
+                assert!(matches!(
+                    detect_jenkins(None).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        with_env(
+            snapshot([("JENKINS_URL", "https://jenkins.example.com/")]),
+            || {
+                assert!(matches!(
+                    detect_jenkins(Some("my-aud")).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn jenkins_success() {
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "https://jenkins.example.com/"),
+                ("ID_TOKEN", TOKEN),
+                ("MY_AUD_ID_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_jenkins(None), Ok(TOKEN.into()));
+                assert_eq!(detect_jenkins(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn teamcity_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_teamcity(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn teamcity_env_failure() {
+        with_env(
+            snapshot([("TEAMCITY_VERSION", "2024.12 (build 174331)")]),
+            || {
+                assert_eq!(
+                    detect_teamcity(Some("my-aud")),
+                    Err(CIIDError::missing_variable(
+                        "MY_AUD_ID_TOKEN",
+                        "TeamCity: MY_AUD_ID_TOKEN is not set. The build configuration must \
+                        define an 'env.MY_AUD_ID_TOKEN' parameter containing the id token"
+                    ))
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn teamcity_success() {
+        with_env(
+            snapshot([
+                ("TEAMCITY_VERSION", "2024.12 (build 174331)"),
+                ("ID_TOKEN", TOKEN),
+                ("MY_AUD_ID_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_teamcity(None), Ok(TOKEN.into()));
+                assert_eq!(detect_teamcity(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn codefresh_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_codefresh(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn codefresh_env_failure() {
+        // Missing env variables
+        with_env(snapshot([("CF_BUILD_ID", "1")]), || {
+            assert!(matches!(
+                detect_codefresh(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+        with_env(
+            snapshot([("CF_BUILD_ID", "1"), ("CF_OIDC_REQUEST_TOKEN", "token")]),
+            || {
+                assert!(matches!(
+                    detect_codefresh(None).unwrap_err(),
+                    CIIDError::MissingVariable { .. }
+                ));
+            },
+        );
+
+        // request fails
+        #[cfg(feature = "reqwest")]
+        with_env(
+            snapshot([
+                ("CF_BUILD_ID", "1"),
+                ("CF_OIDC_REQUEST_TOKEN", "token"),
+                ("CF_OIDC_REQUEST_URL", "http://invalid"),
+            ]),
+            || {
+                let err = detect_codefresh(None).unwrap_err();
+                assert!(matches!(err, CIIDError::RequestFailed { .. }));
+                assert_eq!(
+                    err.to_string(),
+                    "credential detection failed: Codefresh: Token request failed: error sending \
+                    request for url (http://invalid/)"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn check_audience_mismatch() {
+        // TOKEN has audience "sigstore"
+        assert_eq!(check_audience("Test", TOKEN.into(), None), Ok(TOKEN.into()));
+        assert_eq!(
+            check_audience("Test", TOKEN.into(), Some("sigstore")),
+            Ok(TOKEN.into())
+        );
+        assert!(matches!(
+            check_audience("Test", TOKEN.into(), Some("my-aud")).unwrap_err(),
+            CIIDError::EnvironmentError(_)
+        ));
+        assert!(matches!(
+            check_audience("Test", "a.b.c".into(), Some("sigstore")).unwrap_err(),
+            CIIDError::EnvironmentError(_)
+        ));
+    }
+
+    #[test]
+    fn harness_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_harness(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn harness_env_failure() {
+        with_env(snapshot([("HARNESS_BUILD_ID", "1")]), || {
+            assert!(matches!(
+                detect_harness(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+    }
+
+    #[test]
+    fn harness_success() {
+        with_env(
+            snapshot([("HARNESS_BUILD_ID", "1"), ("PLUGIN_OIDC_TOKEN_ID", TOKEN)]),
+            || {
+                assert_eq!(detect_harness(None), Ok(TOKEN.into()));
+                assert_eq!(detect_harness(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn bitrise_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_bitrise(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn bitrise_env_failure() {
+        with_env(snapshot([("BITRISE_IO", "true")]), || {
+            assert!(matches!(
+                detect_bitrise(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([("BITRISE_IO", "true"), ("BITRISE_IDENTITY_TOKEN", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_bitrise(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn bitrise_success() {
+        with_env(
+            snapshot([("BITRISE_IO", "true"), ("BITRISE_IDENTITY_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(detect_bitrise(None), Ok(TOKEN.into()));
+                assert_eq!(detect_bitrise(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn spacelift_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_spacelift(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn spacelift_env_failure() {
+        with_env(snapshot([("TF_VAR_spacelift_run_id", "01HXYZ")]), || {
+            assert!(matches!(
+                detect_spacelift(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([
+                ("TF_VAR_spacelift_run_id", "01HXYZ"),
+                ("SPACELIFT_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_spacelift(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn spacelift_success() {
+        with_env(
+            snapshot([
+                ("TF_VAR_spacelift_run_id", "01HXYZ"),
+                ("SPACELIFT_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_spacelift(None), Ok(TOKEN.into()));
+                assert_eq!(detect_spacelift(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn env0_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_env0(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn env0_env_failure() {
+        with_env(snapshot([("ENV0_ENVIRONMENT_ID", "5e8b3fa2")]), || {
+            assert!(matches!(
+                detect_env0(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([
+                ("ENV0_ENVIRONMENT_ID", "5e8b3fa2"),
+                ("ENV0_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_env0(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn env0_success() {
+        with_env(
+            snapshot([
+                ("ENV0_ENVIRONMENT_ID", "5e8b3fa2"),
+                ("ENV0_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_env0(None), Ok(TOKEN.into()));
+                assert_eq!(detect_env0(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn terraform_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_terraform(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn terraform_env_failure() {
+        with_env(snapshot([("TFC_RUN_ID", "run-CZcmD7eagjhyX0vN")]), || {
+            assert!(matches!(
+                detect_terraform(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+            assert!(matches!(
+                detect_terraform(Some("my-aud")).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // default token audience does not match
+        with_env(
+            snapshot([
+                ("TFC_RUN_ID", "run-CZcmD7eagjhyX0vN"),
+                ("TFC_WORKLOAD_IDENTITY_TOKEN", TOKEN),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_terraform(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn terraform_success() {
+        // default token
+        with_env(
+            snapshot([
+                ("TFC_RUN_ID", "run-CZcmD7eagjhyX0vN"),
+                ("TFC_WORKLOAD_IDENTITY_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_terraform(None), Ok(TOKEN.into()));
+                assert_eq!(detect_terraform(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+
+        // tagged token
+        with_env(
+            snapshot([
+                ("TFC_RUN_ID", "run-CZcmD7eagjhyX0vN"),
+                ("TFC_WORKLOAD_IDENTITY_TOKEN_MY_AUD", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_terraform(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn pulumi_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_pulumi(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn pulumi_env_failure() {
+        with_env(snapshot([("PULUMI_DEPLOYMENT_ID", "1")]), || {
+            assert!(matches!(
+                detect_pulumi(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([("PULUMI_DEPLOYMENT_ID", "1"), ("PULUMI_OIDC_TOKEN", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_pulumi(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn pulumi_success() {
+        with_env(
+            snapshot([("PULUMI_DEPLOYMENT_ID", "1"), ("PULUMI_OIDC_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(detect_pulumi(None), Ok(TOKEN.into()));
+                assert_eq!(detect_pulumi(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn vercel_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_vercel(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn vercel_env_failure() {
+        with_env(snapshot([("VERCEL", "1")]), || {
+            assert!(matches!(
+                detect_vercel(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([("VERCEL", "1"), ("VERCEL_OIDC_TOKEN", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_vercel(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn vercel_success() {
+        with_env(
+            snapshot([("VERCEL", "1"), ("VERCEL_OIDC_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(detect_vercel(None), Ok(TOKEN.into()));
+                assert_eq!(detect_vercel(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn fly_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_fly(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[cfg(unix)]
+    fn serve_unix_socket_once(response: String) -> (tempfile::TempDir, PathBuf) {
+        // Serves a single request: returns the canned response after reading the JSON body
+        let tmpdir = tempfile::tempdir().unwrap();
+        let socket = tmpdir.path().join("api");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        std::thread::spawn(move || {
+            use std::io::Read;
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            assert!(request.starts_with(b"POST /v1/tokens/oidc HTTP/1.0\r\n"));
+            assert!(request.ends_with(b"{\"aud\":\"my-audience\"}"));
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        (tmpdir, socket)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fly_env_failure() {
+        assert!(matches!(
+            request_fly_token(Path::new("/nonexistent/api"), None).unwrap_err(),
+            CIIDError::IoError { .. }
+        ));
+
+        let (_tmpdir, socket) = serve_unix_socket_once(
+            "HTTP/1.0 403 Forbidden\r\nContent-Length: 9\r\n\r\nforbidden".into(),
+        );
+        assert_eq!(
+            request_fly_token(&socket, Some("my-audience")),
+            Err(CIIDError::HttpError {
+                status: 403,
+                message: "Fly.io: Token request failed with status 403: forbidden".into()
+            })
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fly_timeout() {
+        // the socket accepts the connection but never responds
+        let tmpdir = tempfile::tempdir().unwrap();
+        let socket = tmpdir.path().join("api");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        std::thread::spawn(move || {
+            use std::io::Read;
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+
+        with_env(snapshot([("CI_ID_HTTP_TIMEOUT", "0.2")]), || {
+            let start = Instant::now();
+            let err = request_fly_token(&socket, Some("my-audience")).unwrap_err();
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert!(matches!(err, CIIDError::IoError { .. }));
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fly_success() {
+        let (_tmpdir, socket) = serve_unix_socket_once(format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n{}",
+            TOKEN
+        ));
+        assert_eq!(
+            request_fly_token(&socket, Some("my-audience")),
+            Ok(TOKEN.into())
+        );
+    }
+
+    #[test]
+    fn namespace_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_namespace(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn namespace_env_failure() {
+        with_env(
+            // empty the path so that nsc is not found
+            snapshot([("NSC_INSTANCE_ID", "ab1cd2ef"), ("PATH", "")]),
+            || {
+                assert!(matches!(
+                    detect_namespace(Some("my-audience")).unwrap_err(),
+                    CIIDError::IoError { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn namespace_success() {
+        // create a fake 'nsc' executable that checks expected arguments
+        let script = format!(
+            "#!/bin/sh\n\
+            [ \"$*\" = \"auth issue-id-token --audience my-audience\" ] || exit 1\n\
+            echo {}\n",
+            TOKEN
+        );
+        let dir_path = create_fake_executable("nsc", &script);
+
+        with_env(
+            snapshot([
+                ("NSC_INSTANCE_ID", "ab1cd2ef"),
+                ("PATH", dir_path.to_str().unwrap()),
+            ]),
+            || {
+                assert_eq!(detect_namespace(Some("my-audience")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn concourse_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_concourse(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn concourse_env_failure() {
+        // token audience does not match
+        with_env(snapshot([("CONCOURSE_ID_TOKEN", TOKEN)]), || {
+            assert!(matches!(
+                detect_concourse(Some("my-aud")).unwrap_err(),
+                CIIDError::EnvironmentError(_)
+            ));
+        });
+    }
+
+    #[test]
+    fn concourse_success() {
+        with_env(snapshot([("CONCOURSE_ID_TOKEN", TOKEN)]), || {
+            assert_eq!(detect_concourse(None), Ok(TOKEN.into()));
+            assert_eq!(detect_concourse(Some("sigstore")), Ok(TOKEN.into()));
+        });
+    }
+
+    #[test]
+    fn aws_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_aws(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+
+        // token for another audience is not an error
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("token");
+        fs::write(&path, TOKEN).unwrap();
+        with_env(
+            snapshot([("AWS_WEB_IDENTITY_TOKEN_FILE", path.to_str().unwrap())]),
+            || {
+                assert_eq!(
+                    detect_aws(Some("sts.amazonaws.com")),
+                    Err(CIIDError::EnvironmentNotDetected)
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn aws_env_failure() {
+        with_env(
+            snapshot([("AWS_WEB_IDENTITY_TOKEN_FILE", "/nonexistent/token")]),
+            || {
+                assert!(matches!(
+                    detect_aws(None).unwrap_err(),
+                    CIIDError::IoError { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn aws_success() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("token");
+        fs::write(&path, format!("{}\n", TOKEN)).unwrap();
+
+        with_env(
+            snapshot([("AWS_WEB_IDENTITY_TOKEN_FILE", path.to_str().unwrap())]),
+            || {
+                assert_eq!(detect_aws(None), Ok(TOKEN.into()));
+                assert_eq!(detect_aws(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn kubernetes_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_kubernetes(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+
+        // the default service account token is not used without an audience
+        with_env(snapshot([("KUBERNETES_SERVICE_HOST", "10.0.0.1")]), || {
+            assert_eq!(
+                detect_kubernetes(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn kubernetes_env_failure() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("token");
+        fs::write(&path, format!("{}\n", TOKEN)).unwrap();
+
+        // token file does not exist
+        with_env(
+            snapshot([
+                ("KUBERNETES_SERVICE_HOST", "10.0.0.1"),
+                (
+                    "KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE",
+                    "/nonexistent/token",
+                ),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_kubernetes(None).unwrap_err(),
+                    CIIDError::IoError { .. }
+                ));
+            },
+        );
+
+        // token audience does not match
+        with_env(
+            snapshot([
+                ("KUBERNETES_SERVICE_HOST", "10.0.0.1"),
+                (
+                    "KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE",
+                    path.to_str().unwrap(),
+                ),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_kubernetes(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn kubernetes_success() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("token");
+        fs::write(&path, format!("{}\n", TOKEN)).unwrap();
+
+        with_env(
+            snapshot([
+                ("KUBERNETES_SERVICE_HOST", "10.0.0.1"),
+                (
+                    "KUBERNETES_SERVICE_ACCOUNT_TOKEN_FILE",
+                    path.to_str().unwrap(),
+                ),
+            ]),
+            || {
+                assert_eq!(detect_kubernetes(None), Ok(TOKEN.into()));
+                assert_eq!(detect_kubernetes(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn spiffe_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_spiffe(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn spiffe_env_failure() {
+        let socket = "unix:///tmp/spire-agent/public/api.sock";
+
+        // SPIFFE does not support default audience
+        #[cfg(unix)]
+        {
+            let dir_path = create_fake_executable("spire-agent", "#!/bin/sh\nexit 1\n");
+            with_env(
+                snapshot([
+                    ("SPIFFE_ENDPOINT_SOCKET", socket),
+                    ("PATH", dir_path.to_str().unwrap()),
+                ]),
+                || {
+                    assert!(matches!(
+                        detect_spiffe(None).unwrap_err(),
+                        CIIDError::EnvironmentError(_)
+                    ));
+                },
+            );
+        }
+
+        // empty the path so that spire-agent is not found: the socket is left to other tools
+        with_env(
+            snapshot([("SPIFFE_ENDPOINT_SOCKET", socket), ("PATH", "")]),
+            || {
+                assert_eq!(
+                    detect_spiffe(Some("my-audience")),
+                    Err(CIIDError::EnvironmentNotDetected)
+                );
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn spiffe_success() {
+        // create a fake 'spire-agent' executable that checks expected arguments
+        let script = format!(
+            "#!/bin/sh\n\
+            [ \"$*\" = \"api fetch jwt -audience my-audience -socketPath /tmp/api.sock\" ] || exit 1\n\
+            printf 'token(spiffe://example.org/ci):\\n\\t{}\\n'\n\
+            printf 'bundle(spiffe://example.org):\\n\\t{{}}\\n'\n",
+            TOKEN
+        );
+        let dir_path = create_fake_executable("spire-agent", &script);
+
+        with_env(
+            snapshot([
+                ("SPIFFE_ENDPOINT_SOCKET", "unix:///tmp/api.sock"),
+                ("PATH", dir_path.to_str().unwrap()),
+            ]),
+            || {
+                assert_eq!(detect_spiffe(Some("my-audience")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn env_token_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_env_token(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+            assert_eq!(
+                detect_env_token(Some("my-aud")),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn env_token_success() {
+        with_env(snapshot([("CI_ID_TOKEN", TOKEN)]), || {
+            assert_eq!(detect_env_token(None), Ok(TOKEN.into()));
+            assert_eq!(detect_env_token(Some("sigstore")), Ok(TOKEN.into()));
+            // the default token must match the requested audience
+            assert!(matches!(
+                detect_env_token(Some("my-aud")),
+                Err(CIIDError::EnvironmentError(_))
+            ));
+        });
+
+        // audience specific token is preferred
+        with_env(
+            snapshot([
+                ("CI_ID_TOKEN", "default.token.value"),
+                ("CI_ID_TOKEN_MY_AUD", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_env_token(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn token_file_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_token_file(Some("my-aud")),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn token_file_env_failure() {
+        with_env(
+            snapshot([("CI_ID_TOKEN_FILE", "/nonexistent/token")]),
+            || {
+                assert!(matches!(
+                    detect_token_file(None).unwrap_err(),
+                    CIIDError::IoError { .. }
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn token_file_success() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("token");
+        fs::write(&path, format!("{}\n", TOKEN)).unwrap();
+
+        with_env(
+            snapshot([
+                ("CI_ID_TOKEN_FILE", "/nonexistent/token"),
+                ("CI_ID_TOKEN_FILE_MY_AUD", path.to_str().unwrap()),
+            ]),
+            || {
+                assert_eq!(detect_token_file(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+        with_env(
+            snapshot([("CI_ID_TOKEN_FILE", path.to_str().unwrap())]),
+            || {
+                assert_eq!(detect_token_file(None), Ok(TOKEN.into()));
+                assert_eq!(detect_token_file(Some("sigstore")), Ok(TOKEN.into()));
+                // the default token must match the requested audience
+                assert!(matches!(
+                    detect_token_file(Some("my-aud")),
+                    Err(CIIDError::EnvironmentError(_))
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn find_program_in_path() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let file_name = if cfg!(windows) {
+            "ci-id-helper.exe"
+        } else {
+            "ci-id-helper"
+        };
+        let path = tmpdir.path().join(file_name);
+        File::create(&path).unwrap();
+        let paths = env::join_paths([Path::new("/nonexistent"), tmpdir.path()]).unwrap();
+        let env = snapshot([("PATH", paths.to_str().unwrap())]);
+        with_env(env, || {
+            assert_eq!(find_in_path("ci-id-helper"), Some(path));
+            assert_eq!(find_in_path("ci-id-other"), None);
+        });
+    }
+
+    #[test]
+    fn token_command_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_token_command(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn token_command_exit_code() {
+        with_env(snapshot([("CI_ID_TOKEN_COMMAND", "exit 3")]), || {
+            assert!(matches!(
+                detect_token_command(None).unwrap_err(),
+                CIIDError::SubprocessFailed {
+                    exit_code: Some(3),
+                    ..
+                }
+            ));
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn token_command_stderr() {
+        with_env(
+            snapshot([("CI_ID_TOKEN_COMMAND", "echo 'agent says no' >&2; exit 3")]),
+            || {
+                let err = detect_token_command(None).unwrap_err();
+                assert!(err
+                    .to_string()
+                    .ends_with("Token command: sh failed with exit code 3: agent says no"));
+            },
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn token_command_timeout() {
+        with_env(
+            snapshot([
+                ("CI_ID_TOKEN_COMMAND", "sleep 5"),
+                ("CI_ID_COMMAND_TIMEOUT", "0.2"),
+            ]),
+            || {
+                let start = Instant::now();
+                let err = detect_token_command(None).unwrap_err();
+                assert!(start.elapsed() < Duration::from_secs(4));
+                assert!(matches!(err, CIIDError::IoError { .. }));
+                assert!(err.to_string().contains("timed out after 0.2 seconds"));
+            },
+        );
+        // a timeout beyond any representable deadline does not overflow
+        with_env(
+            snapshot([
+                ("CI_ID_TOKEN_COMMAND", "echo a.b.c"),
+                ("CI_ID_COMMAND_TIMEOUT", "1e19"),
+            ]),
+            || {
+                assert_eq!(detect_token_command(None), Ok("a.b.c".into()));
+            },
+        );
+        with_env(
+            snapshot([
+                ("CI_ID_TOKEN_COMMAND", "echo token"),
+                ("CI_ID_COMMAND_TIMEOUT", "soon"),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_token_command(None).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn token_command_env_failure() {
+        /// Pretends that the shell is not installed
+        struct NoShell;
+
+        impl CommandRunner for NoShell {
+            fn is_available(&self, _program: &str) -> bool {
+                false
+            }
+
+            fn run(&self, _call: &CommandCall) -> std::io::Result<CommandOutput> {
+                Err(std::io::ErrorKind::NotFound.into())
+            }
+        }
+
+        with_env(snapshot([("CI_ID_TOKEN_COMMAND", "echo token")]), || {
+            assert!(matches!(
+                with_command_runner(NoShell, || detect_token_command(None)).unwrap_err(),
+                CIIDError::IoError { .. }
+            ));
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn token_command_success() {
+        let dir_path = create_fake_executable(
+            "token-tool",
+            &format!(
+                "#!/bin/sh\n[ \"$1\" = \"my-aud\" ] || exit 1\necho {}\n",
+                TOKEN
+            ),
+        );
+        let tool = dir_path.join("token-tool");
+        let command = format!("{} \"$CI_ID_AUDIENCE\"", tool.to_str().unwrap());
+
+        with_env(
+            snapshot([("CI_ID_TOKEN_COMMAND", command.as_str())]),
+            || {
+                assert_eq!(detect_token_command(Some("my-aud")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn codespaces_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_codespaces(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn codespaces_env_failure() {
+        with_env(snapshot([("CODESPACES", "true")]), || {
+            assert!(matches!(
+                detect_codespaces(Some("my-aud")).unwrap_err(),
+                CIIDError::EnvironmentError(_)
+            ));
+        });
+    }
+
+    #[test]
+    fn depot_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_depot(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn depot_env_failure() {
+        with_env(snapshot([("DEPOT_PROJECT_ID", "abc123xyz")]), || {
+            assert!(matches!(
+                detect_depot(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([
+                ("DEPOT_PROJECT_ID", "abc123xyz"),
+                ("DEPOT_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_depot(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn depot_success() {
+        with_env(
+            snapshot([
+                ("DEPOT_PROJECT_ID", "abc123xyz"),
+                ("DEPOT_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_depot(None), Ok(TOKEN.into()));
+                assert_eq!(detect_depot(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn garnix_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_garnix(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn garnix_env_failure() {
+        with_env(snapshot([("GARNIX_CI", "1")]), || {
+            assert!(matches!(
+                detect_garnix(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([("GARNIX_CI", "1"), ("GARNIX_ID_TOKEN", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_garnix(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn garnix_success() {
+        with_env(
+            snapshot([("GARNIX_CI", "1"), ("GARNIX_ID_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(detect_garnix(None), Ok(TOKEN.into()));
+                assert_eq!(detect_garnix(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn railway_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(detect_railway(None), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn railway_env_failure() {
+        with_env(snapshot([("RAILWAY_ENVIRONMENT_ID", "1")]), || {
+            assert!(matches!(
+                detect_railway(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([
+                ("RAILWAY_ENVIRONMENT_ID", "1"),
+                ("RAILWAY_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert!(matches!(
+                    detect_railway(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn railway_success() {
+        with_env(
+            snapshot([
+                ("RAILWAY_ENVIRONMENT_ID", "1"),
+                ("RAILWAY_OIDC_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(detect_railway(None), Ok(TOKEN.into()));
+                assert_eq!(detect_railway(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn scaleway_not_detected() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_scaleway(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    fn scaleway_env_failure() {
+        with_env(snapshot([("SCW_JOB_RUN_ID", "1")]), || {
+            assert!(matches!(
+                detect_scaleway(None).unwrap_err(),
+                CIIDError::MissingVariable { .. }
+            ));
+        });
+
+        // token audience does not match
+        with_env(
+            snapshot([("SCW_JOB_RUN_ID", "1"), ("SCW_ID_TOKEN", TOKEN)]),
+            || {
+                assert!(matches!(
+                    detect_scaleway(Some("my-aud")).unwrap_err(),
+                    CIIDError::EnvironmentError(_)
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn scaleway_success() {
+        with_env(
+            snapshot([("SCW_JOB_RUN_ID", "1"), ("SCW_ID_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(detect_scaleway(None), Ok(TOKEN.into()));
+                assert_eq!(detect_scaleway(Some("sigstore")), Ok(TOKEN.into()));
+            },
+        );
+    }
+
+    #[test]
+    fn detect_credentials_no_environments() {
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_credentials(None),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+            assert_eq!(detect_environment(), None);
+            assert!(!is_ci());
+            assert_eq!(environment_name(), None);
+        });
+    }
+
+    #[test]
+    fn detection_retry() {
+        let interval = Duration::from_millis(10);
+        let timeout = Duration::from_secs(10);
+
+        // succeeds once the environment is ready
+        let mut attempts = 0;
+        let result = retry_until(timeout, interval, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(CIIDError::EnvironmentNotDetected),
+                2 => Err(CIIDError::missing_variable("TOKEN", "TOKEN is not set")),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        // Duration::MAX waits without a deadline
+        let mut attempts = 0;
+        let result = retry_until(Duration::MAX, interval, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(CIIDError::EnvironmentNotDetected),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result, Ok(2));
+
+        // permanent errors are not retried
+        let mut attempts = 0;
+        let result: Result<()> = retry_until(timeout, interval, || {
+            attempts += 1;
+            Err(CIIDError::MissingPermission {
+                message: "denied".into(),
+            })
+        });
+        assert!(matches!(result, Err(CIIDError::MissingPermission { .. })));
+        assert_eq!(attempts, 1);
+
+        // the last error is returned after the timeout
+        let mut attempts = 0;
+        let start = Instant::now();
+        let result: Result<()> = retry_until(Duration::from_millis(50), interval, || {
+            attempts += 1;
+            Err(CIIDError::EnvironmentError(format!("attempt {}", attempts)))
+        });
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(attempts > 1);
+        assert_eq!(
+            result,
+            Err(CIIDError::EnvironmentError(format!("attempt {}", attempts)))
+        );
+
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("MY_AUDIENCE_ID_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(
+                    detect_credentials_or_wait(Some("my-audience"), Duration::ZERO),
+                    Ok(TOKEN.into())
+                );
+            },
+        );
+    }
+
+    #[cfg(feature = "async")]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn detect_credentials_async_request_failure() {
+        with_env(
+            snapshot([
+                ("GITHUB_ACTIONS", "true"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+                ("ACTIONS_ID_TOKEN_REQUEST_URL", "http://invalid"),
+            ]),
+            || {
+                let err = block_on(detect_credentials_async(Some("my-audience"))).unwrap_err();
+                assert!(matches!(err, CIIDError::RequestFailed { .. }));
+                assert_eq!(
+                    err.to_string(),
+                    "credential detection failed: GitHub Actions: Token request failed: error \
+                    sending request for url (http://invalid/?audience=my-audience)"
+                );
+            },
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn detect_credentials_async_success() {
+        // Detectors without HTTP requests work as in the blocking API
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("MY_AUDIENCE_ID_TOKEN", TOKEN),
+            ]),
+            || {
+                assert_eq!(
+                    block_on(detect_credentials_async(Some("my-audience"))),
+                    Ok(TOKEN.into())
+                );
+            },
+        );
+    }
+
+    #[cfg(feature = "async")]
+    struct StaticDetector(Result<String>);
+
+    #[cfg(feature = "async")]
+    impl AsyncDetector for StaticDetector {
+        fn name(&self) -> &str {
+            "Static"
+        }
+
+        fn detect<'a>(&'a self, _audience: Option<&'a str>) -> DetectFuture<'a> {
+            Box::pin(async move { self.0.clone() })
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn detect_credentials_async_with_custom_detectors() {
+        let not_detected = StaticDetector(Err(CIIDError::EnvironmentNotDetected));
+        let found = StaticDetector(Ok(TOKEN.into()));
+        let failed = StaticDetector(Err(CIIDError::EnvironmentError("Static: failed".into())));
+
+        assert_eq!(
+            block_on(detect_credentials_async_with(
+                &[&not_detected, &found],
+                None
+            )),
+            Ok(TOKEN.into())
+        );
+        // probing continues after a failure
+        assert_eq!(
+            block_on(detect_credentials_async_with(&[&failed, &found], None)),
+            Ok(TOKEN.into())
+        );
+        assert_eq!(
+            block_on(detect_credentials_async_with(
+                &[&failed, &not_detected],
+                None
+            )),
+            Err(CIIDError::EnvironmentError("Static: failed".into()))
+        );
+        assert_eq!(
+            block_on(detect_credentials_async_with(
+                &[&StaticDetector(Ok("not a token".into()))],
+                None
+            )),
+            Err(CIIDError::MalformedToken)
+        );
+    }
+
+    #[test]
+    fn detect_environment_actions() {
+        with_env(snapshot([("GITHUB_ACTIONS", "true")]), || {
+            assert_eq!(detect_environment(), Some(CIEnvironment::GitHubActions));
+            assert!(is_ci());
+            assert_eq!(environment_name(), Some("GitHub Actions"));
+        });
+        with_env(
+            snapshot([("GITEA_ACTIONS", "true"), ("GITHUB_ACTIONS", "true")]),
+            || {
+                assert_eq!(detect_environment(), Some(CIEnvironment::GiteaActions));
+                assert!(!CIEnvironment::GitHubActions.is_present());
+            },
+        );
+        with_env(
+            snapshot([
+                ("FORGEJO_ACTIONS", "true"),
+                ("GITEA_ACTIONS", "true"),
+                ("GITHUB_ACTIONS", "true"),
+            ]),
+            || {
+                assert_eq!(detect_environment(), Some(CIEnvironment::ForgejoActions));
+                assert!(!CIEnvironment::GiteaActions.is_present());
+            },
+        );
+    }
+
+    #[test]
+    fn is_ci_codespaces() {
+        with_env(snapshot([("CODESPACES", "true")]), || {
+            assert_eq!(environment_name(), Some("GitHub Codespaces"));
+            assert!(!is_ci());
+        });
+    }
+
+    #[test]
+    fn ci_environment_names() {
+        assert_eq!(CIEnvironment::GitLab.to_string(), "GitLab Pipelines");
+        assert_eq!(
+            Provider::from(CIEnvironment::GitLab).to_string(),
+            "GitLab Pipelines"
+        );
+        assert_eq!(Provider::TokenFile.to_string(), "Token file");
+    }
+
+    #[test]
+    fn provider_order() {
+        // Environments are probed in the same order as detect_environment() checks them
+        let envs: Vec<CIEnvironment> = Provider::ALL
+            .iter()
+            .filter_map(|provider| match provider {
+                Provider::Environment(env) => Some(*env),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(envs, CIEnvironment::ALL);
+    }
+
+    struct EnvDetector;
+
+    impl Detector for EnvDetector {
+        fn name(&self) -> &str {
+            "Custom"
+        }
+
+        fn detect(&self, audience: Option<&str>) -> Result<String> {
+            match audience {
+                Some("custom") => env_var("CUSTOM_ID_TOKEN")
+                    .map_err(|_| CIIDError::EnvironmentError("Custom: no token".into())),
+                _ => Err(CIIDError::EnvironmentNotDetected),
+            }
+        }
+    }
+
+    #[test]
+    fn detect_credentials_from_provider() {
+        with_env(
+            snapshot([("GITHUB_ACTIONS", "true"), ("CI_ID_TOKEN", TOKEN)]),
+            || {
+                assert_eq!(
+                    detect_credentials_from(CIEnvironment::GitLab, None),
+                    Err(CIIDError::EnvironmentError(
+                        "GitLab Pipelines: Environment not detected".into()
+                    ))
+                );
+                let credential =
+                    detect_credential_from(Provider::EnvironmentVariable, None).unwrap();
+                assert_eq!(credential.token.expose(), TOKEN);
+                assert_eq!(credential.provider, "Environment variable");
+            },
+        );
+        with_env(snapshot([("CI_ID_TOKEN", "not a token")]), || {
+            assert_eq!(
+                detect_credentials_from(Provider::EnvironmentVariable, None),
+                Err(CIIDError::MalformedToken)
+            );
+        });
+    }
+
+    #[test]
+    fn detect_all_credentials_multiple_providers() {
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("CI_ID_TOKEN", TOKEN),
+                ("CONCOURSE_ID_TOKEN", TOKEN),
+            ]),
+            || {
+                let results = detect_all_credentials(None).unwrap();
+                let detectors: Vec<&str> = results.iter().map(|(d, _)| d.as_str()).collect();
+                assert_eq!(
+                    detectors,
+                    [
+                        CIEnvironment::Jenkins.name(),
+                        CIEnvironment::Concourse.name(),
+                        Provider::EnvironmentVariable.name(),
+                    ]
+                );
+                assert!(matches!(
+                    results[0].1,
+                    Err(CIIDError::MissingVariable { .. })
+                ));
+                assert_eq!(results[1].1.as_ref().unwrap().token.expose(), TOKEN);
+                assert_eq!(
+                    results[2].1.as_ref().unwrap().provider,
+                    "Environment variable"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn detect_credentials_multi_audiences() {
+        assert_eq!(detect_credentials_multi(&[]), Ok(vec![]));
+
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("MY_AUDIENCE_ID_TOKEN", TOKEN),
+                ("SIGSTORE_ID_TOKEN", "a.b.c"),
+            ]),
+            || {
+                assert_eq!(
+                    detect_credentials_multi(&["my-audience", "sigstore"]),
+                    Ok(vec![TOKEN.into(), "a.b.c".into()])
+                );
+                assert_eq!(
+                    detect_credentials_multi(&["my-audience", "other"]),
+                    Err(CIIDError::missing_variable(
+                        "OTHER_ID_TOKEN",
+                        "Jenkins: OTHER_ID_TOKEN is not set. This could imply that the \
+                        job does not bind an OpenID Connect id token credential to that variable"
+                    ))
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn credential_request_providers() {
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("MY_AUDIENCE_ID_TOKEN", "a.b.c"),
+                ("CI_ID_TOKEN_MY_AUDIENCE", TOKEN),
+            ]),
+            || {
+                let credential = CredentialRequest::new()
+                    .audience("my-audience")
+                    .providers([Provider::EnvironmentVariable, CIEnvironment::Jenkins.into()])
+                    .detect()
+                    .unwrap();
+                assert_eq!(credential.token.expose(), TOKEN);
+                assert_eq!(credential.provider, "Environment variable");
+
+                assert_eq!(
+                    CredentialRequest::new()
+                        .audience("my-audience")
+                        .providers([CIEnvironment::GitLab])
+                        .detect(),
+                    Err(CIIDError::EnvironmentNotDetected)
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn credential_request_failed_provider() {
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("CI_ID_TOKEN_MY_AUDIENCE", TOKEN),
+            ]),
+            || {
+                // probing continues after the failing Jenkins detector, like detect_credential
+                for parallel in [false, true] {
+                    let request = CredentialRequest::new()
+                        .audience("my-audience")
+                        .parallel(parallel);
+                    let credential = request
+                        .clone()
+                        .providers([CIEnvironment::Jenkins.into(), Provider::EnvironmentVariable])
+                        .detect()
+                        .unwrap();
+                    assert_eq!(credential.provider, "Environment variable");
+                    assert!(matches!(
+                        request.providers([CIEnvironment::Jenkins]).detect(),
+                        Err(CIIDError::MissingVariable { .. })
+                    ));
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn credential_request_parallel() {
+        let env = snapshot([
+            ("GITHUB_ACTIONS", "1"),
+            ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+            ("ACTIONS_ID_TOKEN_REQUEST_URL", "https://example.com/token"),
+            ("CI_ID_GITHUB_ISSUER", "https://oauth2.sigstore.dev/auth"),
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("MY_AUDIENCE_ID_TOKEN", "jenkins.token.sig"),
+        ]);
+        // the GitHub token request is slower than the Jenkins detection
+        let transport = MockTransport(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            HttpResponse::new(200, format!("{{\"value\": \"{}\"}}", TOKEN))
+        });
+        let detect = |providers: [CIEnvironment; 3]| {
+            CredentialRequest::new()
+                .audience("my-audience")
+                .providers(providers)
+                .parallel(true)
+                .detect()
+                .unwrap()
+        };
+        with_env(env, || {
+            with_transport(transport, || {
+                let credential = detect([
+                    CIEnvironment::GitLab,
+                    CIEnvironment::GitHubActions,
+                    CIEnvironment::Jenkins,
+                ]);
+                assert_eq!(credential.provider, "GitHub Actions");
+                assert_eq!(credential.token.expose(), TOKEN);
+
+                let credential = detect([
+                    CIEnvironment::GitLab,
+                    CIEnvironment::Jenkins,
+                    CIEnvironment::GitHubActions,
+                ]);
+                assert_eq!(credential.provider, "Jenkins");
+                assert_eq!(credential.token.expose(), "jenkins.token.sig");
+            });
+            assert_eq!(
+                CredentialRequest::new()
+                    .providers([CIEnvironment::GitLab, CIEnvironment::CircleCI])
+                    .parallel(true)
+                    .detect(),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    fn http_timeout_settings() {
+        let context = RequestContext::default();
+        with_env(snapshot([]), || {
+            assert_eq!(
+                http_timeouts(&context),
+                Ok(HttpTimeouts {
+                    request: DEFAULT_HTTP_TIMEOUT,
+                    connect: DEFAULT_CONNECT_TIMEOUT,
+                })
+            );
+        });
+        let env = snapshot([
+            ("CI_ID_HTTP_TIMEOUT", "5"),
+            ("CI_ID_CONNECT_TIMEOUT", " 0.5 "),
+        ]);
+        with_env(env, || {
+            assert_eq!(
+                http_timeouts(&context),
+                Ok(HttpTimeouts {
+                    request: Duration::from_secs(5),
+                    connect: Duration::from_millis(500),
+                })
+            );
+            // request options override the environment
+            let context = RequestContext {
+                connect_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            };
+            assert_eq!(
+                http_timeouts(&context).unwrap().connect,
+                Duration::from_secs(1)
+            );
+        });
+        for value in ["0", "-1", "soon", "1e30"] {
+            with_env(snapshot([("CI_ID_HTTP_TIMEOUT", value)]), || {
+                assert_eq!(
+                    http_timeouts(&context),
+                    Err(CIIDError::EnvironmentError(format!(
+                        "CI_ID_HTTP_TIMEOUT: Invalid timeout '{}', expected a number of seconds",
+                        value
+                    )))
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn audience_sanitization() {
+        assert_eq!(sanitize_audience("sigstore"), "SIGSTORE");
+        assert_eq!(
+            sanitize_audience("my-aud.example.com"),
+            "MY_AUD_EXAMPLE_COM"
+        );
+        assert_eq!(sanitize_audience("https://a/b"), "HTTPS___A_B");
+        assert_eq!(sanitize_audience("2fa_v2"), "_FA_V2");
+        assert_eq!(sanitize_audience("größe"), "GR_SSE");
+        assert_eq!(sanitize_audience(""), "");
+        assert_eq!(
+            id_token_variable("my-aud.example.com"),
+            "MY_AUD_EXAMPLE_COM_ID_TOKEN"
+        );
+    }
+
+    #[test]
+    fn provider_table() {
+        let environments: Vec<CIEnvironment> = PROVIDERS.iter().map(|p| p.environment).collect();
+        assert_eq!(environments, CIEnvironment::ALL);
+        for provider in PROVIDERS {
+            assert_eq!(provider.id, provider.environment.id());
+            assert!(provider
+                .docs_url
+                .starts_with("https://docs.rs/ci-id/latest/ci_id/#"));
+        }
+
+        let github = CIEnvironment::GitHubActions.info();
+        assert_eq!(github.marker, "GITHUB_ACTIONS");
+        assert!(github.network && !github.subprocess);
+        assert_eq!(github.audience, AudienceSupport::Requested);
+        assert_eq!(
+            serde_json::to_value(CIEnvironment::GitLab.info()).unwrap(),
+            serde_json::json!({
+                "environment": "gitlab",
+                "id": "gitlab",
+                "name": "GitLab Pipelines",
+                "marker": "GITLAB_CI",
+                "network": false,
+                "subprocess": false,
+                "docs_url": "https://docs.rs/ci-id/latest/ci_id/#gitlab-pipelines",
+                "audience": "configured",
+            })
+        );
+        assert_eq!(
+            CIEnvironment::GitHubCodespaces.info().audience,
+            AudienceSupport::Unsupported
+        );
+    }
+
+    #[test]
+    fn detector_features() {
+        assert!(CIEnvironment::ALL.iter().all(|env| env.is_enabled()));
+        assert!(Provider::ALL
+            .iter()
+            .all(|provider| provider.detect_fn().is_some()));
+    }
+
+    #[test]
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    fn http_proxy_settings() {
+        let context = RequestContext::default();
+        with_env(snapshot([("NO_PROXY", "localhost")]), || {
+            assert_eq!(proxy_settings(&context), vec![]);
+        });
+        let env = snapshot([
+            ("https_proxy", "http://proxy.example.com:3128"),
+            ("HTTP_PROXY", "http://proxy.example.com:8080"),
+            ("NO_PROXY", "localhost,.internal"),
+        ]);
+        with_env(env, || {
+            let no_proxy = Some("localhost,.internal".to_string());
+            assert_eq!(
+                proxy_settings(&context),
+                vec![
+                    ProxySetting {
+                        scheme: ProxyScheme::Https,
+                        url: "http://proxy.example.com:3128".into(),
+                        no_proxy: no_proxy.clone(),
+                    },
+                    ProxySetting {
+                        scheme: ProxyScheme::Http,
+                        url: "http://proxy.example.com:8080".into(),
+                        no_proxy,
+                    },
+                ]
+            );
+            assert!(http_client("Test").is_ok());
+            // request options override the environment
+            let context = RequestContext {
+                proxy: Some("http://override.example.com".into()),
+                ..Default::default()
+            };
+            assert_eq!(
+                proxy_settings(&context),
+                vec![ProxySetting {
+                    scheme: ProxyScheme::All,
+                    url: "http://override.example.com".into(),
+                    no_proxy: None,
+                }]
+            );
+        });
+        with_env(snapshot([("HTTPS_PROXY", "http://[::1")]), || {
+            let Err(CIIDError::EnvironmentError(msg)) = http_client("Test") else {
+                panic!("expected an invalid proxy error");
+            };
+            assert!(
+                msg.starts_with("Test: Invalid proxy 'http://[::1'"),
+                "{}",
+                msg
+            );
+        });
+    }
+
+    /// Transport that rate limits the first `limited` requests
+    struct RateLimitTransport {
+        limited: usize,
+        retry_after: Option<&'static str>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl HttpTransport for RateLimitTransport {
+        fn send(&self, _: &HttpRequest) -> std::result::Result<HttpResponse, BoxError> {
+            if self.requests.fetch_add(1, Ordering::SeqCst) < self.limited {
+                let response = HttpResponse::new(429, "slow down");
+                return Ok(match self.retry_after {
+                    Some(retry_after) => response.header("Retry-After", retry_after),
+                    None => response,
+                });
+            }
+            Ok(HttpResponse::new(
+                200,
+                format!("{{\"value\": \"{}\"}}", TOKEN),
+            ))
+        }
+    }
+
+    #[test]
+    fn rate_limited_token_request() {
+        let detect = |limited, retry_after| {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let transport = RateLimitTransport {
+                limited,
+                retry_after,
+                requests: requests.clone(),
+            };
+            let result = with_transport(transport, || detect_github(None));
+            (result, requests.load(Ordering::SeqCst))
+        };
+        with_env(
+            snapshot([
+                ("GITHUB_ACTIONS", "1"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+                ("ACTIONS_ID_TOKEN_REQUEST_URL", "https://example.com/token"),
+                ("CI_ID_GITHUB_ISSUER", "https://oauth2.sigstore.dev/auth"),
+            ]),
+            || {
+                // Retry-After is honored
+                assert_eq!(detect(2, Some("0")), (Ok(TOKEN.into()), 3));
+
+                // retries are limited
+                assert_eq!(
+                    detect(10, Some("0")),
+                    (
+                        Err(CIIDError::RateLimited {
+                            retry_after: Some(Duration::ZERO),
+                            message: "GitHub Actions: Token request was rate limited, retry \
+                                after 0 seconds: slow down"
+                                .into()
+                        }),
+                        4
+                    )
+                );
+
+                // long delays and responses without a delay are not retried
+                assert_eq!(
+                    detect(1, Some("3600")),
+                    (
+                        Err(CIIDError::RateLimited {
+                            retry_after: Some(Duration::from_secs(3600)),
+                            message: "GitHub Actions: Token request was rate limited, retry \
+                                after 3600 seconds: slow down"
+                                .into()
+                        }),
+                        1
+                    )
+                );
+                assert_eq!(
+                    detect(1, None),
+                    (
+                        Err(CIIDError::RateLimited {
+                            retry_after: None,
+                            message: "GitHub Actions: Token request was rate limited: slow down"
+                                .into()
+                        }),
+                        1
+                    )
+                );
+                assert_eq!(detect(1, Some("Wed, 21 Oct 2015 07:28:00 GMT")).1, 1);
+            },
+        );
+    }
+
+    #[test]
+    fn user_agent_header() {
+        let env = snapshot([
+            ("GITHUB_ACTIONS", "1"),
+            ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+            ("ACTIONS_ID_TOKEN_REQUEST_URL", "https://example.com/token"),
+        ]);
+        let user_agent = |request: CredentialRequest| {
+            let transport = MockTransport(|request| {
+                let (_, user_agent) = request
+                    .headers
+                    .iter()
+                    .find(|(name, _)| *name == "user-agent")
+                    .unwrap();
+                // echo the header back in the error message
+                HttpResponse::new(400, user_agent.clone())
+            });
+            let request = request.providers([CIEnvironment::GitHubActions]);
+            with_transport(transport, || request.detect())
+                .unwrap_err()
+                .to_string()
+        };
+        let expected = |user_agent: &str| {
+            format!(
+                "credential detection failed: GitHub Actions: Token request failed with status \
+                 400: {}. Check that the requested audience is valid",
+                user_agent
+            )
+        };
+
+        assert!(DEFAULT_USER_AGENT.starts_with("ci-id/"));
+        with_env(env.clone(), || {
+            assert_eq!(
+                user_agent(CredentialRequest::new()),
+                expected(DEFAULT_USER_AGENT)
+            );
+            assert_eq!(
+                user_agent(CredentialRequest::new().user_agent("release-tool/1.0")),
+                expected("release-tool/1.0")
+            );
+        });
+        let mut env = env;
+        env.insert("CI_ID_USER_AGENT".into(), "ci-id-cli/2.0".into());
+        with_env(env, || {
+            assert_eq!(
+                user_agent(CredentialRequest::new()),
+                expected("ci-id-cli/2.0")
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(all(feature = "http", feature = "reqwest"))]
+    fn http_client_settings() {
+        // the cached client is reused while the settings are equal
+        let context = RequestContext::default();
+        let settings = |vars: HashMap<String, String>| {
+            with_env(vars, || ClientSettings::new(&context).unwrap())
+        };
+        assert_eq!(settings(snapshot([])), settings(snapshot([])));
+        assert_ne!(
+            settings(snapshot([])),
+            settings(snapshot([("CI_ID_HTTP_TIMEOUT", "5")]))
+        );
+        assert_ne!(
+            settings(snapshot([])),
+            settings(snapshot([("HTTPS_PROXY", "http://proxy.example.com")]))
+        );
+        let context = RequestContext {
+            root_certificates: vec![b"pem".to_vec()],
+            ..Default::default()
+        };
+        with_env(snapshot([]), || {
+            assert_ne!(
+                ClientSettings::new(&context).unwrap(),
+                ClientSettings::new(&RequestContext::default()).unwrap()
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "reqwest")]
+    fn credential_request_timeout() {
+        // The listener never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        with_env(
+            snapshot([
+                ("GITHUB_ACTIONS", "true"),
+                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+                ("ACTIONS_ID_TOKEN_REQUEST_URL", &url),
+            ]),
+            || {
+                let start = Instant::now();
+                let result = CredentialRequest::new()
+                    .providers([CIEnvironment::GitHubActions])
+                    .timeout(Duration::from_millis(100))
+                    .detect();
+                assert!(matches!(result, Err(CIIDError::RequestFailed { .. })));
+                assert!(start.elapsed() < Duration::from_secs(5));
+                // The timeout only applies to the request
+                REQUEST_CONTEXT.with_borrow(|context| assert!(context.timeout.is_none()));
+            },
+        );
+    }
+
+    /// Returns a token with the given expiry time
+    fn token_with_expiry(expiry: SystemTime) -> String {
+        let exp = expiry
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let payload = URL_SAFE_NO_PAD.encode(format!(r#"{{"aud":"my-audience","exp":{}}}"#, exp));
+        format!("eyJhbGciOiJub25lIn0.{}.sig", payload)
+    }
+
+    #[test]
+    fn credential_expiry() {
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(1729512930);
+        let credential = DetectedCredential::new("GitHub Actions", TOKEN.into());
+        assert_eq!(credential.expires_at(), Some(expiry));
+        assert_eq!(credential.time_to_expiry(), Some(Duration::ZERO));
+        assert_eq!(credential.token.expires_at(), Some(expiry));
+        assert_eq!(credential.token.time_to_expiry(), Some(Duration::ZERO));
+
+        let token = token_with_expiry(SystemTime::now() + Duration::from_secs(600));
+        let credential = DetectedCredential::new("Test", token);
+        let remaining = credential.time_to_expiry().unwrap();
+        assert!(remaining > Duration::from_secs(590) && remaining <= Duration::from_secs(600));
+        let remaining = credential.token.time_to_expiry().unwrap();
+        assert!(remaining > Duration::from_secs(590) && remaining <= Duration::from_secs(600));
+
+        let credential = DetectedCredential::new("Test", "not a token".into());
+        assert_eq!(credential.expires_at(), None);
+        assert_eq!(credential.time_to_expiry(), None);
+        assert_eq!(credential.token.expires_at(), None);
+        assert_eq!(credential.token.time_to_expiry(), None);
+    }
+
+    #[test]
+    fn credential_request_check_audience() {
+        with_env(
+            snapshot([
+                ("CI_ID_TOKEN_SIGSTORE", TOKEN),
+                ("CI_ID_TOKEN_OTHER", TOKEN),
+            ]),
+            || {
+                let request = CredentialRequest::new()
+                    .providers([Provider::EnvironmentVariable])
+                    .check_audience(true);
+                assert_eq!(
+                    request
+                        .clone()
+                        .audience("sigstore")
+                        .detect()
+                        .unwrap()
+                        .token
+                        .expose(),
+                    TOKEN
+                );
+
+                let result = request.clone().audience("other").detect();
+                assert_eq!(
+                    result,
+                    Err(CIIDError::AudienceMismatch {
+                        expected: "other".into(),
+                        found: vec!["sigstore".into()]
+                    })
+                );
+                assert_eq!(
+                    result.unwrap_err().to_string(),
+                    "token audience [\"sigstore\"] does not match requested audience 'other'"
+                );
+
+                // Not checked by default
+                assert!(request
+                    .check_audience(false)
+                    .audience("other")
+                    .detect()
+                    .is_ok());
+            },
+        );
+
+        // the audience from the configuration file is checked too
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config_path = tmpdir.path().join("ci-id.toml");
+        fs::write(&config_path, "audience = \"other\"\n").unwrap();
+        let env = snapshot([
+            ("CI_ID_CONFIG", config_path.to_str().unwrap()),
+            ("CI_ID_TOKEN_OTHER", TOKEN),
+        ]);
+        with_env(env, || {
+            let request = CredentialRequest::new().providers([Provider::EnvironmentVariable]);
+            assert!(request.clone().detect().is_ok());
+            assert_eq!(
+                request.check_audience(true).detect(),
+                Err(CIIDError::AudienceMismatch {
+                    expected: "other".into(),
+                    found: vec!["sigstore".into()]
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn credential_request_min_validity() {
+        let valid = token_with_expiry(SystemTime::now() + Duration::from_secs(600));
+        let request = CredentialRequest::new()
+            .audience("my-audience")
+            .providers([Provider::EnvironmentVariable]);
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", valid.as_str())]),
+            || {
+                let minutes = |m: u64| Duration::from_secs(m * 60);
+                assert!(request.clone().min_validity(minutes(5)).detect().is_ok());
+                match request.clone().min_validity(minutes(15)).detect() {
+                    Err(CIIDError::TokenTooShortLived { expires_in }) => {
+                        assert!(expires_in <= minutes(10) && expires_in > minutes(9))
+                    }
+                    result => panic!("unexpected result {:?}", result),
+                }
+            },
+        );
+        with_env(snapshot([("CI_ID_TOKEN_MY_AUDIENCE", TOKEN)]), || {
+            assert_eq!(
+                request.clone().min_validity(Duration::ZERO).detect(),
+                Err(CIIDError::TokenExpired)
+            );
+            // Expiry is not checked by default
+            assert!(request.detect().is_ok());
+        });
+        with_env(snapshot([("CI_ID_TOKEN_MY_AUDIENCE", "a.b.c")]), || {
+            // Tokens without expiry are accepted
+            assert!(request.clone().min_validity(Duration::MAX).detect().is_ok());
+        });
+    }
+
+    #[test]
+    fn credential_request_cache() {
+        let valid = token_with_expiry(SystemTime::now() + Duration::from_secs(600));
+        let expiring = token_with_expiry(SystemTime::now() + Duration::from_secs(10));
+        let request = CredentialRequest::new()
+            .audience("my-audience")
+            .providers([Provider::EnvironmentVariable])
+            .cache(true);
+
+        clear_token_cache();
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", valid.as_str())]),
+            || {
+                assert_eq!(request.detect().unwrap().token.expose(), valid);
+            },
+        );
+        with_env(snapshot([("CI_ID_TOKEN_MY_AUDIENCE", TOKEN)]), || {
+            // cached token is returned
+            assert_eq!(request.detect().unwrap().token.expose(), valid);
+            // unless the cache is bypassed or the audience differs
+            assert_eq!(
+                request
+                    .clone()
+                    .cache(false)
+                    .detect()
+                    .unwrap()
+                    .token
+                    .expose(),
+                TOKEN
+            );
+            // or an option changes the token
+            let lifetime = request.clone().buildkite_lifetime(Duration::from_secs(300));
+            assert_eq!(lifetime.detect().unwrap().token.expose(), TOKEN);
+            assert_eq!(
+                request.clone().audience("other").detect(),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+        });
+
+        clear_token_cache();
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", expiring.as_str())]),
+            || {
+                assert_eq!(request.detect().unwrap().token.expose(), expiring);
+            },
+        );
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", valid.as_str())]),
+            || {
+                // token close to expiry is refetched
+                assert_eq!(request.detect().unwrap().token.expose(), valid);
+            },
+        );
+        clear_token_cache();
+    }
+
+    #[test]
+    fn credential_provider_refresh() {
+        let valid = token_with_expiry(SystemTime::now() + Duration::from_secs(600));
+        let provider = CredentialProvider::new(
+            CredentialRequest::new()
+                .audience("my-audience")
+                .providers([Provider::EnvironmentVariable]),
+        );
+
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", valid.as_str())]),
+            || {
+                assert_eq!(provider.token().unwrap().expose(), valid);
+            },
+        );
+        with_env(snapshot([("CI_ID_TOKEN_MY_AUDIENCE", TOKEN)]), || {
+            // Current token is still valid
+            assert_eq!(provider.token().unwrap().expose(), valid);
+        });
+
+        // With a larger margin the token is refreshed: TOKEN has expired so it is refreshed on
+        // every call
+        let provider = provider.refresh_margin(Duration::from_secs(3600));
+        with_env(snapshot([("CI_ID_TOKEN_MY_AUDIENCE", TOKEN)]), || {
+            assert_eq!(provider.token().unwrap().expose(), TOKEN);
+        });
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", valid.as_str())]),
+            || {
+                assert_eq!(provider.token().unwrap().expose(), valid);
+            },
+        );
+        with_env(snapshot([]), || {
+            assert_eq!(provider.token(), Err(CIIDError::EnvironmentNotDetected));
+        });
+    }
+
+    #[test]
+    fn detector_registry_ordering() {
+        let registry = DetectorRegistry::new();
+        assert_eq!(registry.names().len(), Provider::ALL.len());
+        assert_eq!(registry.names()[2], "GitHub Actions");
+
+        let registry = registry.move_before("GitLab Pipelines", "GitHub Actions");
+        assert_eq!(
+            registry.names()[..4],
+            [
+                "Forgejo Actions",
+                "Gitea Actions",
+                "GitLab Pipelines",
+                "GitHub Actions"
+            ]
+        );
+
+        let registry = registry
+            .move_to_front("Kubernetes")
+            .remove("Forgejo Actions")
+            .remove("Unknown")
+            .move_before("Unknown", "Kubernetes");
+        assert_eq!(
+            registry.names()[..3],
+            ["Kubernetes", "Gitea Actions", "GitLab Pipelines"]
+        );
+
+        let registry = registry
+            .only(&["Buildkite", "Kubernetes"])
+            .push(EnvDetector)
+            .push_front(Provider::TokenFile);
+        assert_eq!(
+            registry.names(),
+            ["Token file", "Kubernetes", "Buildkite", "Custom"]
+        );
+    }
+
+    #[test]
+    fn detector_registry_detect() {
+        assert_eq!(
+            DetectorRegistry::empty().detect_credentials(None),
+            Err(CIIDError::EnvironmentNotDetected)
+        );
+
+        with_env(
+            snapshot([
+                ("CUSTOM_ID_TOKEN", TOKEN),
+                ("CI_ID_TOKEN", "builtin"),
+                ("BUILDKITE", "true"),
+            ]),
+            || {
+                // Buildkite is not probed
+                let registry = DetectorRegistry::new()
+                    .only(&["Environment variable"])
+                    .push_front(EnvDetector);
+                let credential = registry.detect_credential(Some("custom")).unwrap();
+                assert_eq!(credential.provider, "Custom");
+                assert_eq!(
+                    registry.detect_credentials(None),
+                    Err(CIIDError::MalformedToken)
+                );
+
+                let registry = registry.remove("Environment variable");
+                assert_eq!(
+                    registry.detect_credentials(Some("other")),
+                    Err(CIIDError::EnvironmentNotDetected)
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn detect_credentials_with_custom_detector() {
+        with_env(
+            snapshot([("CUSTOM_ID_TOKEN", TOKEN), ("CI_ID_TOKEN", "builtin")]),
+            || {
+                let credential = detect_credential_with(&[&EnvDetector], Some("custom")).unwrap();
+                assert_eq!(credential.token.expose(), TOKEN);
+                assert_eq!(credential.provider, "Custom");
+            },
+        );
+        with_env(snapshot([]), || {
+            assert_eq!(
+                detect_credentials_with(&[&EnvDetector], Some("custom")),
+                Err(CIIDError::EnvironmentError("Custom: no token".into()))
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn testing_helpers() {
+        use testing::{fake_token, FakeDetector, ScopedEnv};
+
+        let token = fake_token(serde_json::json!({"aud": "my-audience", "exp": 2000000000}));
+        assert_eq!(validate_token(token.clone()), Ok(token.clone()));
+        assert_eq!(token_audiences(&token), Some(vec!["my-audience".into()]));
+
+        {
+            let _env = ScopedEnv::new([("CI_ID_TOKEN", token.as_str())]);
+            {
+                // nested guards see only their own variables
+                let _inner = ScopedEnv::new([("UNRELATED", "1")]);
+                assert_eq!(env_var("CI_ID_TOKEN"), Err(env::VarError::NotPresent));
+            }
+            let credential = detect_credential(Some("my-audience")).unwrap();
+            assert_eq!(credential.token.expose(), token);
+            assert_eq!(credential.provider, "Environment variable");
+        }
+        assert!(current_env().is_none());
+
+        let failing = FakeDetector::failing("Failing", CIIDError::EnvironmentError("no".into()));
+        let fake = FakeDetector::new("Fake", &token);
+        let detectors: [&dyn Detector; 3] =
+            [&FakeDetector::not_detected("Absent"), &fake, &failing];
+        let _env = ScopedEnv::new::<&str, &str>([]);
+        assert_eq!(
+            detect_credentials_with(&detectors, None).unwrap().expose(),
+            token
+        );
+        assert_eq!(
+            detect_credentials_with(&[&failing], None),
+            Err(CIIDError::EnvironmentError("no".into()))
+        );
+    }
+
+    #[test]
+    fn detect_with_env() {
+        let env = snapshot([("GITHUB_ACTIONS", "true")]);
+        assert!(matches!(
+            with_env(env, || detect_github(None)),
+            Err(CIIDError::MissingPermission { .. })
+        ));
+
+        let env = snapshot([
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("ID_TOKEN", TOKEN),
+        ]);
+        with_env(env, || {
+            assert_eq!(detect_environment(), Some(CIEnvironment::Jenkins));
+            let credential = detect_credential(None).unwrap();
+            assert_eq!(credential.provider, "Jenkins");
+            assert_eq!(credential.token.expose(), TOKEN);
+        });
+
+        // the process environment is restored afterwards
+        with_env(snapshot([("CI_ID_TOKEN", TOKEN)]), || {
+            assert_eq!(
+                with_env(snapshot([]), || detect_env_token(None)),
+                Err(CIIDError::EnvironmentNotDetected)
+            );
+            assert_eq!(detect_env_token(None), Ok(TOKEN.into()));
+        });
+    }
+
+    #[test]
+    fn provider_ids() {
+        for provider in Provider::ALL {
+            assert_eq!(provider.id().parse::<Provider>(), Ok(*provider));
+        }
+        assert_eq!("GitLab".parse(), Ok(Provider::from(CIEnvironment::GitLab)));
+        assert!(matches!(
+            "gitlabci".parse::<Provider>(),
+            Err(CIIDError::EnvironmentError(_))
+        ));
+    }
+
+    #[test]
+    fn detect_with_provider_config() {
+        let vars = [
+            ("GITHUB_ACTIONS", "true"),
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("ID_TOKEN", TOKEN),
+        ];
+
+        // GitHub Actions is probed first and fails
+        let failed = |report: &ProbeReport| {
+            report.probes.iter().any(|(name, outcome)| {
+                name == "GitHub Actions" && matches!(outcome, ProbeOutcome::Failed(_))
+            })
+        };
+        let (_, report) = with_env(snapshot(vars), || detect_credentials_with_report(None));
+        assert!(failed(&report));
+
+        let mut env = snapshot(vars);
+        env.insert("CI_ID_PROVIDER".into(), "jenkins".into());
+        with_env(env, || {
+            let (result, report) = detect_credentials_with_report(None);
+            assert_eq!(result, Ok(TOKEN.into()));
+            assert_eq!(report.probes, [("Jenkins".into(), ProbeOutcome::Succeeded)]);
+        });
+
+        let mut env = snapshot(vars);
+        env.insert("CI_ID_DISABLE_GITHUB".into(), "1".into());
+        with_env(env, || {
+            let (result, report) = detect_credentials_with_report(None);
+            assert_eq!(result, Ok(TOKEN.into()));
+            assert!(!failed(&report));
+        });
+
+        let mut env = snapshot(vars);
+        env.insert("CI_ID_PROVIDER".into(), "unknown".into());
+        with_env(env, || {
+            assert_eq!(
+                detect_credentials(None),
+                Err(CIIDError::EnvironmentError(
+                    "CI_ID_PROVIDER: Unknown provider 'unknown'".into()
+                ))
+            );
+            assert!(matches!(
+                detect_all_credentials(None),
+                Err(CIIDError::EnvironmentError(_))
+            ));
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn detect_with_config_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let config_path = tmpdir.path().join("ci-id.toml");
+        let with_config = |config: &str, vars: &[(&str, &str)], f: &dyn Fn()| {
+            fs::write(&config_path, config).unwrap();
+            let mut env: HashMap<String, String> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            env.insert("CI_ID_CONFIG".into(), config_path.to_str().unwrap().into());
+            with_env(env, f);
+        };
+
+        // config order and disabled providers
+        let config = r#"
+            providers = ["github", "jenkins", "env"] # jenkins before env
+            disable = ["github"]
+        "#;
+        let vars = [
+            ("GITHUB_ACTIONS", "true"),
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("ID_TOKEN", TOKEN),
+            ("CI_ID_TOKEN", "env.token.sig"),
+        ];
+        with_config(config, &vars, &|| {
+            let (result, report) = detect_credentials_with_report(None);
+            assert_eq!(result, Ok(TOKEN.into()));
+            assert_eq!(report.probes, [("Jenkins".into(), ProbeOutcome::Succeeded)]);
+        });
+        // environment variables take precedence
+        let vars = [
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("ID_TOKEN", TOKEN),
+            ("CI_ID_TOKEN", "env.token.sig"),
+            ("CI_ID_PROVIDER", "env"),
+        ];
+        with_config(config, &vars, &|| {
+            assert_eq!(detect_credentials(None), Ok("env.token.sig".into()))
+        });
+
+        // default audience and custom detectors
+        let config = r#"
+            audience = 'config-audience'
+
+            [[detector]]
+            name = "Custom env"
+            env = "MY_TOKEN"
+
+            [[detector]]
+            name = "Custom command"
+            command = "echo \"token.$CI_ID_AUDIENCE.sig\""
+        "#;
+        with_config(config, &[("MY_TOKEN", " my.token.sig\n")], &|| {
+            let credential = detect_credential(None).unwrap();
+            assert_eq!(credential.token.expose(), "my.token.sig");
+            assert_eq!(credential.provider, "Custom env");
+        });
+        with_config(config, &[], &|| {
+            assert_eq!(
+                detect_credentials(None),
+                Ok("token.config-audience.sig".into())
+            );
+            assert_eq!(
+                detect_credentials(Some("other")),
+                Ok("token.other.sig".into())
+            );
+        });
+
+        // invalid configurations
+        let invalid = [
+            ("unknown = 1", "Unknown setting 'unknown'"),
+            (
+                "audience = \"a\"\naudience = \"b\"",
+                "line 2: duplicate key",
+            ),
+            ("audience = 1", "audience must be a string"),
+            (
+                "providers = [\"github\", \"nope\"]",
+                "Unknown provider 'nope' in providers",
+            ),
+            ("timeout = \"30\"", "timeout must be a number of seconds"),
+            ("[tool]", "line 1: only [[detector]] tables are supported"),
+            (
+                "audience = \"a\" b",
+                "line 1: unexpected characters after value",
+            ),
+            ("[[detector]]\nenv = \"A\"", "detector must have a name"),
+            (
+                "[[detector]]\nname = \"x\"\nenv = \"A\"\nfile = \"B\"",
+                "detector 'x' must have exactly one of 'env', 'file' or 'command'",
+            ),
+        ];
+        for (config, message) in invalid {
+            with_config(config, &[], &|| match detect_credentials(None) {
+                Err(CIIDError::EnvironmentError(e)) => {
+                    assert!(e.starts_with("Invalid configuration"), "{}", e);
+                    assert!(e.ends_with(message), "{}: {}", config, e);
+                }
+                result => panic!("{}: unexpected {:?}", config, result),
+            });
+        }
+
+        // without CI_ID_CONFIG no file is read, not even ci-id.toml in the current directory
+        with_env(snapshot([]), || {
+            let config = load_config().unwrap();
+            assert!(config.detectors.is_empty() && config.providers.is_none());
+        });
+
+        // CI_ID_CONFIG must point to an existing file
+        let env = snapshot([("CI_ID_CONFIG", "/nonexistent/ci-id.toml")]);
+        with_env(env, || {
+            assert!(matches!(
+                detect_credentials(None),
+                Err(CIIDError::IoError { .. })
+            ));
+            assert!(matches!(
+                detect_all_credentials(None),
+                Err(CIIDError::IoError { .. })
+            ));
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn detect_with_plugins() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let (dir1, dir2) = (tmpdir.path().join("1"), tmpdir.path().join("2"));
+        let write_plugin = |dir: &Path, name: &str, script: &str| {
+            fs::create_dir_all(dir).unwrap();
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        write_plugin(&dir1, "ci-id-provider-b", "echo token.$CI_ID_AUDIENCE.b");
+        write_plugin(&dir1, "ci-id-provider-a", "true");
+        write_plugin(&dir2, "ci-id-provider-b", "echo token.shadowed.b");
+        write_plugin(
+            &dir2,
+            "ci-id-provider-c",
+            "echo '{\"token\": \"token.c.c\"}'",
+        );
+        write_plugin(&dir2, "ci-id-provider-", "echo token.empty.name");
+        // not executable
+        fs::write(dir2.join("ci-id-provider-d"), "echo token.d.d").unwrap();
+        let path = env::join_paths([&dir1, &dir2]).unwrap();
+        let path = path.to_str().unwrap();
+        let enabled = ("CI_ID_ENABLE_PLUGINS", "1");
+
+        with_env(snapshot([("PATH", path), enabled]), || {
+            let (result, report) = detect_credentials_with_report(Some("aud"));
+            assert_eq!(result, Ok("token.aud.b".into()));
+            assert_eq!(
+                report.probes,
+                [
+                    ("ci-id-provider-a".into(), ProbeOutcome::NotDetected),
+                    ("ci-id-provider-b".into(), ProbeOutcome::Succeeded),
+                ]
+            );
+            let names: Vec<_> = discover_plugins()
+                .iter()
+                .map(|plugin| Detector::name(plugin).to_string())
+                .collect();
+            assert_eq!(
+                names,
+                ["ci-id-provider-a", "ci-id-provider-b", "ci-id-provider-c"]
+            );
+        });
+
+        // JSON responses and failures
+        write_plugin(
+            &dir1,
+            "ci-id-provider-b",
+            "echo '{\"error\": \"no token\"}'",
+        );
+        write_plugin(&dir1, "ci-id-provider-a", "exit 2");
+        with_env(snapshot([("PATH", path), enabled]), || {
+            let (result, report) = detect_credentials_with_report(None);
+            assert_eq!(result, Ok("token.c.c".into()));
+            let outcomes: Vec<_> = report.probes.iter().map(|(_, outcome)| outcome).collect();
+            assert_eq!(
+                outcomes,
+                [
+                    &ProbeOutcome::Failed(CIIDError::SubprocessFailed {
+                        exit_code: Some(2),
+                        message: format!(
+                            "ci-id-provider-a: {} failed with exit code 2",
+                            dir1.join("ci-id-provider-a").display()
+                        )
+                    }),
+                    &ProbeOutcome::Failed(CIIDError::EnvironmentError(
+                        "ci-id-provider-b: no token".into()
+                    )),
+                    &ProbeOutcome::Succeeded,
+                ]
+            );
+        });
+
+        // plugins are opt-in, and not used with CI_ID_PROVIDER
+        let envs = [
+            snapshot([("PATH", path)]),
+            snapshot([("PATH", path), enabled, ("CI_ID_PROVIDER", "env")]),
+        ];
+        for env in envs {
+            with_env(env, || {
+                assert_eq!(
+                    detect_credentials(None),
+                    Err(CIIDError::EnvironmentNotDetected)
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        let digests = [
+            (
+                "",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                "abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (data, digest) in digests {
+            let hex: String = sha256::sha256(data.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            assert_eq!(hex, digest);
+            assert_eq!(token_fingerprint(data), digest[..12]);
+        }
+        assert_eq!(SecretToken::from("abc").fingerprint(), "ba7816bf8f01");
 
-    struct SavedEnv<'a> {
-        old_env: HashMap<&'a str, Option<String>>,
-        _guard: MutexGuard<'a, ()>,
+        // tokens and credentials are not included in debug output
+        let request = HttpRequest {
+            method: "GET",
+            url: "https://example.com".into(),
+            headers: vec![("Authorization", "Bearer secret".into())],
+            body: None,
+        };
+        let response = HttpResponse::new(200, TOKEN);
+        let credential = DetectedCredential::new("test", TOKEN.into());
+        for debug in [
+            format!("{:?}", request),
+            format!("{:?}", response),
+            format!("{:?}", credential),
+        ] {
+            assert!(
+                !debug.contains("secret") && !debug.contains(TOKEN),
+                "{}",
+                debug
+            );
+        }
     }
 
-    impl<'a> SavedEnv<'a> {
-        fn new<T>(test_env: T) -> Self
-        where
-            T: IntoIterator<Item = (&'a str, Option<&'a str>)>,
-        {
-            // Tests can panic: assume our lock is still fine
-            let guard = match ENV_MUTEX.lock() {
-                Ok(guard) => guard,
-                Err(poison) => poison.into_inner(),
-            };
+    #[test]
+    fn serialization() {
+        let credential = DetectedCredential::new("GitHub Actions", TOKEN.into());
+        let json = serde_json::to_value(&credential).unwrap();
+        assert_eq!(json["token"], TOKEN);
+        assert_eq!(json["provider"], "GitHub Actions");
+        assert_eq!(json["issuer"], "https://oauth2.sigstore.dev/auth");
+        assert_eq!(json["expiry"], 1729512930);
+        assert_eq!(json["legacy_source"], serde_json::Value::Null);
+        assert_eq!(json["claims"]["aud"], "sigstore");
+        let deserialized: DetectedCredential = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized, credential);
 
-            // Store current env values, set the test values as the environment
-            let mut old_env = HashMap::new();
-            for (key, val) in test_env {
-                let old_val = env::var(key).ok();
-                old_env.insert(key, old_val);
-                match val {
-                    Some(val) => env::set_var(key, val),
-                    None => env::remove_var(key),
-                }
-            }
+        let provider = Provider::Environment(CIEnvironment::GitLab);
+        assert_eq!(serde_json::to_string(&provider).unwrap(), "\"gitlab\"");
+        assert_eq!(
+            serde_json::from_str::<Vec<Provider>>("[\"GitLab\", \"env\"]").unwrap(),
+            [provider, Provider::EnvironmentVariable]
+        );
+        assert_eq!(
+            serde_json::from_str::<CIEnvironment>("\"circleci\"").unwrap(),
+            CIEnvironment::CircleCI
+        );
+        assert!(serde_json::from_str::<CIEnvironment>("\"env\"").is_err());
+        assert!(serde_json::from_str::<Provider>("\"unknown\"").is_err());
 
-            Self {
-                old_env,
-                _guard: guard,
-            }
-        }
+        let report = ProbeReport {
+            probes: vec![
+                ("GitHub Actions".into(), ProbeOutcome::NotDetected),
+                (
+                    "GitLab Pipelines".into(),
+                    ProbeOutcome::Failed(CIIDError::MalformedToken),
+                ),
+                ("Environment variable".into(), ProbeOutcome::Succeeded),
+            ],
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!([
+                {"detector": "GitHub Actions", "outcome": "not_detected"},
+                {
+                    "detector": "GitLab Pipelines",
+                    "outcome": "failed",
+                    "error": CIIDError::MalformedToken.to_string()
+                },
+                {"detector": "Environment variable", "outcome": "succeeded"},
+            ])
+        );
     }
 
-    impl<'a> Drop for SavedEnv<'a> {
-        fn drop(&mut self) {
-            for (key, val) in self.old_env.drain() {
-                match val {
-                    Some(val) => env::set_var(key, val),
-                    None => env::remove_var(key),
-                }
+    #[test]
+    fn issuers() {
+        for env in CIEnvironment::ALL {
+            for issuer in env.known_issuers() {
+                assert!(issuer.starts_with("https://") && !issuer.ends_with('/'));
             }
         }
-    }
-
-    fn run_with_env<'a, T, F>(test_env: T, f: F)
-    where
-        F: Fn(),
-        T: IntoIterator<Item = (&'a str, Option<&'a str>)>,
-    {
-        // Prepares env variables according to `env`, runs the function, then returns environment
-        // to old values
-        let saved_env = SavedEnv::new(test_env);
-        f();
-        drop(saved_env);
-    }
 
-    #[test]
-    fn buildkite_not_detected() {
-        run_with_env([("BUILDKITE", None)], || {
+        let env = snapshot([("CI_SERVER_URL", "https://gitlab.example.com/")]);
+        with_env(env, || {
             assert_eq!(
-                detect_buildkite(None),
-                Err(CIIDError::EnvironmentNotDetected)
+                CIEnvironment::GitHubActions.expected_issuer().as_deref(),
+                Some("https://token.actions.githubusercontent.com")
+            );
+            assert_eq!(
+                CIEnvironment::GitLab.expected_issuer().as_deref(),
+                Some("https://gitlab.example.com")
+            );
+            assert_eq!(
+                CIEnvironment::Buildkite.expected_issuer().as_deref(),
+                Some("https://agent.buildkite.com")
+            );
+            assert_eq!(CIEnvironment::Jenkins.expected_issuer(), None);
+        });
+        with_env(snapshot([]), || {
+            assert_eq!(
+                CIEnvironment::GitLab.expected_issuer().as_deref(),
+                Some("https://gitlab.com")
             );
         });
     }
 
     #[test]
-    fn buildkite_env_failure() {
-        run_with_env(
-            // empty the path so that this does not accidentally succeed on buildkite
-            [("BUILDKITE", Some("1")), ("PATH", Some(""))],
+    fn expected_identity() {
+        let credential = |provider: &str, claims: serde_json::Value| {
+            let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+            DetectedCredential::new(provider, format!("e30.{}.sig", payload))
+        };
+        let expected = |identity: &str| {
+            Some(ExpectedIdentity {
+                issuer: "https://issuer.example.com".into(),
+                identity: identity.into(),
+            })
+        };
+
+        let github = credential(
+            "GitHub Actions",
+            serde_json::json!({
+                "iss": "https://issuer.example.com",
+                "sub": "repo:octo/repo:ref:refs/heads/main",
+                "job_workflow_ref": "octo/repo/.github/workflows/release.yml@refs/heads/main",
+            }),
+        );
+        with_env(snapshot([]), || {
+            assert_eq!(
+                github.expected_identity(),
+                expected(
+                    "https://github.com/octo/repo/.github/workflows/release.yml@refs/heads/main"
+                )
+            );
+        });
+        with_env(
+            snapshot([("GITHUB_SERVER_URL", "https://github.example.com/")]),
             || {
-                assert!(matches!(
-                    detect_buildkite("my-audience".into()).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
+                assert_eq!(
+                    github.expected_identity(),
+                    expected(
+                        "https://github.example.com/octo/repo/.github/workflows/release.yml@refs/heads/main"
+                    )
+                );
             },
         );
-    }
 
-    #[test]
-    fn buildkite_success() {
-        // create a fake 'buildkite-agent' executable
-        let tmpdir = tempfile::tempdir().unwrap();
-        let dir_path = tmpdir.into_path();
-        let path = dir_path.join("buildkite-agent");
-        let mut f = File::create(&path).unwrap();
-        let script = format!("#!/bin/sh\necho -n {}\n", TOKEN);
-        f.write_all(script.as_bytes()).unwrap();
-        let mut permissions = f.metadata().unwrap().permissions();
-        drop(f);
-        permissions.set_mode(0o744);
-        fs::set_permissions(path, permissions).unwrap();
+        let gitlab = credential(
+            "GitLab Pipelines",
+            serde_json::json!({
+                "iss": "https://issuer.example.com",
+                "ci_config_ref_uri": "gitlab.com/group/project//.gitlab-ci.yml@refs/heads/main",
+            }),
+        );
+        assert_eq!(
+            gitlab.expected_identity(),
+            expected("https://gitlab.com/group/project//.gitlab-ci.yml@refs/heads/main")
+        );
 
-        // TODO: actually make the fake binary check that args are correct?
+        let buildkite = credential(
+            "Buildkite",
+            serde_json::json!({
+                "iss": "https://issuer.example.com",
+                "organization_slug": "org",
+                "pipeline_slug": "pipeline",
+            }),
+        );
+        assert_eq!(
+            buildkite.expected_identity(),
+            expected("https://buildkite.com/org/pipeline")
+        );
 
-        // Make sure the fake executable is in PATH, then test non-default audience
-        run_with_env(
-            [
-                ("BUILDKITE", Some("1")),
-                ("PATH", Some(dir_path.to_str().unwrap())),
-            ],
-            || {
-                assert_eq!(detect_buildkite("my-audience".into()), Ok(TOKEN.into()));
-            },
+        let other = credential(
+            "Jenkins",
+            serde_json::json!({"iss": "https://issuer.example.com", "sub": "job/release"}),
         );
+        assert_eq!(other.expected_identity(), expected("job/release"));
 
-        // Make sure the fake executable is in PATH, then test default audience
-        run_with_env(
-            [
-                ("BUILDKITE", Some("1")),
-                ("PATH", Some(dir_path.to_str().unwrap())),
-            ],
-            || {
-                assert_eq!(detect_buildkite(None), Ok(TOKEN.into()));
-            },
+        // missing claims
+        let missing = credential(
+            "GitLab Pipelines",
+            serde_json::json!({"iss": "https://issuer.example.com", "sub": "project_path:a/b"}),
         );
+        assert_eq!(missing.expected_identity(), None);
+        let no_issuer = credential("Jenkins", serde_json::json!({"sub": "job/release"}));
+        assert_eq!(no_issuer.expected_identity(), None);
     }
 
     #[test]
-    fn circleci_not_detected() {
-        run_with_env([("CIRCLECI", None)], || {
+    fn run_metadata_from_env() {
+        let env = snapshot([
+            ("GITLAB_CI", "true"),
+            ("CI_PROJECT_PATH", "group/project"),
+            ("CI_COMMIT_REF_NAME", "main"),
+            ("CI_COMMIT_SHA", "abc123"),
+            ("CI_PIPELINE_ID", "42"),
+            ("GITLAB_USER_LOGIN", "octo"),
+            ("CI_JOB_NAME", "release"),
+        ]);
+        with_env(env, || {
             assert_eq!(
-                detect_circleci(None),
-                Err(CIIDError::EnvironmentNotDetected)
+                run_metadata(),
+                Some(RunMetadata {
+                    environment: Some(CIEnvironment::GitLab),
+                    repository: Some("group/project".into()),
+                    git_ref: Some("main".into()),
+                    sha: Some("abc123".into()),
+                    run_id: Some("42".into()),
+                    actor: Some("octo".into()),
+                    job: Some("release".into()),
+                })
             );
         });
+
+        let env = snapshot([
+            ("CIRCLECI", "true"),
+            ("CIRCLE_PROJECT_USERNAME", "octo"),
+            ("CIRCLE_PROJECT_REPONAME", "repo"),
+            ("CIRCLE_BRANCH", "main"),
+            ("CIRCLE_TAG", "v1.0"),
+        ]);
+        with_env(env, || {
+            let metadata = run_metadata().unwrap();
+            assert_eq!(metadata.repository.as_deref(), Some("octo/repo"));
+            assert_eq!(metadata.git_ref.as_deref(), Some("v1.0"));
+            assert_eq!(metadata.sha, None);
+        });
+
+        with_env(snapshot([]), || assert_eq!(run_metadata(), None));
+
+        // claims fill in the values missing from the environment
+        let claims = serde_json::json!({
+            "repository": "octo/repo",
+            "ref": "refs/heads/main",
+            "sha": "def456",
+            "run_id": "7",
+        });
+        let token = format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims.to_string()));
+        let credential = DetectedCredential::new("GitHub Actions", token);
+        with_env(snapshot([("GITHUB_SHA", "abc123")]), || {
+            let metadata = credential.run_metadata();
+            assert_eq!(metadata.environment, Some(CIEnvironment::GitHubActions));
+            assert_eq!(metadata.repository.as_deref(), Some("octo/repo"));
+            assert_eq!(metadata.git_ref.as_deref(), Some("refs/heads/main"));
+            assert_eq!(metadata.sha.as_deref(), Some("abc123"));
+            assert_eq!(metadata.run_id.as_deref(), Some("7"));
+            assert_eq!(metadata.actor, None);
+        });
     }
 
     #[test]
-    fn circleci_env_failure() {
-        run_with_env(
-            // empty the path so that this does not accidentally succeed on CircleCI
-            [("CIRCLECI", Some("1")), ("PATH", Some(""))],
-            || {
-                assert!(matches!(
-                    detect_circleci("my-audience".into()).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
-            },
-        );
+    fn metrics_hook() {
+        #[derive(Default)]
+        struct Recorder {
+            probes: Mutex<Vec<(String, ProbeOutcome)>>,
+            requests: Mutex<Vec<(String, Option<u16>)>>,
+        }
+        impl MetricsHook for Arc<Recorder> {
+            fn probe(&self, detector: &str, outcome: &ProbeOutcome) {
+                let probe = (detector.to_string(), outcome.clone());
+                self.probes.lock().unwrap().push(probe);
+            }
+            fn token_request(&self, detector: &str, status: Option<u16>, _elapsed: Duration) {
+                let request = (detector.to_string(), status);
+                self.requests.lock().unwrap().push(request);
+            }
+        }
 
-        run_with_env(
-            // default audience uses specific env var
-            [("CIRCLECI", Some("1")), ("CIRCLE_OIDC_TOKEN_V2", None)],
-            || {
-                assert!(matches!(
-                    detect_circleci(None).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
-            },
-        );
+        let recorder = Arc::new(Recorder::default());
+        set_metrics_hook(recorder.clone());
+        let env = snapshot([
+            ("GITHUB_ACTIONS", "true"),
+            ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "token"),
+            ("ACTIONS_ID_TOKEN_REQUEST_URL", "https://example.com/token"),
+            ("CI_ID_TOKEN", TOKEN),
+        ]);
+        let transport = MockTransport(|_| HttpResponse::new(500, "error"));
+        let result = with_env(env, || {
+            with_transport(transport, || detect_credentials(None))
+        });
+        clear_metrics_hook();
+
+        assert_eq!(result, Ok(TOKEN.into()));
+        let probes = recorder.probes.lock().unwrap();
+        assert!(probes.contains(&(
+            "GitHub Actions".into(),
+            ProbeOutcome::Failed(actions_status_error("GitHub Actions", 500, "error"))
+        )));
+        assert!(probes.contains(&("Environment variable".into(), ProbeOutcome::Succeeded)));
+        let requests = recorder.requests.lock().unwrap();
+        assert!(requests.contains(&("GitHub Actions".into(), Some(500))));
     }
 
     #[test]
-    fn circleci_success() {
-        // create a fake 'circleci' executable
-        let tmpdir = tempfile::tempdir().unwrap();
-        let dir_path = tmpdir.into_path();
-        let path = dir_path.join("circleci");
-        let mut f = File::create(&path).unwrap();
-        let script = format!("#!/bin/sh\necho -n {}\n", TOKEN);
-        f.write_all(script.as_bytes()).unwrap();
-        let mut permissions = f.metadata().unwrap().permissions();
-        drop(f);
-        permissions.set_mode(0o744);
-        fs::set_permissions(path, permissions).unwrap();
+    fn detect_with_progress() {
+        let events = std::rc::Rc::new(RefCell::new(vec![]));
+        let recorded = events.clone();
+        let callback = move |event: ProgressEvent| {
+            let event = match event {
+                ProgressEvent::Probing { detector } => format!("probing {}", detector),
+                ProgressEvent::Probed { detector, outcome } => {
+                    format!("{}: {:?}", detector, outcome)
+                }
+            };
+            recorded.borrow_mut().push(event);
+        };
+        let env = snapshot([
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("ID_TOKEN", TOKEN),
+            ("CI_ID_PROVIDER", "jenkins"),
+        ]);
+        let result = with_env(env, || {
+            with_progress(callback.clone(), || detect_credentials(None))
+        });
+        assert_eq!(result, Ok(TOKEN.into()));
+        assert_eq!(*events.borrow(), ["probing Jenkins", "Jenkins: Succeeded"]);
 
-        // Make sure the fake executable is in PATH, then test non-default audience
-        run_with_env(
+        // the other detection paths record their probes the same way
+        events.borrow_mut().clear();
+        let env = snapshot([
+            ("JENKINS_URL", "http://jenkins.example.com"),
+            ("ID_TOKEN", TOKEN),
+        ]);
+        let result = with_env(env, || {
+            with_progress(callback, || {
+                detect_credential_from(CIEnvironment::Jenkins, None)?;
+                CredentialRequest::new()
+                    .providers([CIEnvironment::GitLab, CIEnvironment::Jenkins])
+                    .parallel(true)
+                    .detect()
+            })
+        });
+        assert_eq!(result.unwrap().provider, "Jenkins");
+        assert_eq!(
+            *events.borrow(),
             [
-                ("CIRCLECI", Some("1")),
-                ("PATH", Some(dir_path.to_str().unwrap())),
-            ],
-            || {
-                assert_eq!(detect_circleci("my-audience".into()), Ok(TOKEN.into()));
-            },
+                "probing Jenkins",
+                "Jenkins: Succeeded",
+                "probing GitLab Pipelines",
+                "GitLab Pipelines: NotDetected",
+                "probing Jenkins",
+                "Jenkins: Succeeded"
+            ]
         );
 
-        run_with_env(
-            [
-                ("CIRCLECI", Some("1")),
-                ("CIRCLE_OIDC_TOKEN_V2", Some(TOKEN)),
-            ],
+        // the callback is only used within with_progress
+        let env = snapshot([("CI_ID_TOKEN", TOKEN)]);
+        with_env(env, || detect_credentials(None)).unwrap();
+        assert_eq!(events.borrow().len(), 6);
+    }
+
+    #[test]
+    fn detect_credentials_report() {
+        with_env(
+            snapshot([
+                ("JENKINS_URL", "http://jenkins.example.com"),
+                ("CI_ID_TOKEN", TOKEN),
+            ]),
             || {
-                assert_eq!(detect_circleci(None), Ok(TOKEN.into()));
+                let (result, report) = detect_credentials_with_report(None);
+                assert_eq!(result, Ok(TOKEN.into()));
+                assert_eq!(report.succeeded(), Some("Environment variable"));
+                assert_eq!(
+                    report.probes[0],
+                    (
+                        CIEnvironment::ForgejoActions.name().into(),
+                        ProbeOutcome::NotDetected
+                    )
+                );
+                let jenkins = report
+                    .probes
+                    .iter()
+                    .find(|(name, _)| name == "Jenkins")
+                    .unwrap();
+                assert!(matches!(
+                    jenkins.1,
+                    ProbeOutcome::Failed(CIIDError::MissingVariable { .. })
+                ));
+                assert!(report
+                    .to_string()
+                    .ends_with("Environment variable: token found\n"));
             },
         );
     }
 
     #[test]
-    fn github_not_detected() {
-        run_with_env([("GITHUB_ACTIONS", None)], || {
-            assert_eq!(detect_github(None), Err(CIIDError::EnvironmentNotDetected));
+    fn detect_credentials_failures_combined() {
+        with_env(snapshot([]), || {
+            let err =
+                detect_credentials_with(&[&EnvDetector, &EnvDetector], Some("custom")).unwrap_err();
+            let failure = (
+                "Custom".into(),
+                CIIDError::EnvironmentError("Custom: no token".into()),
+            );
+            assert_eq!(
+                err,
+                CIIDError::DetectionFailed {
+                    failures: vec![failure.clone(), failure]
+                }
+            );
+            assert_eq!(
+                err.to_string(),
+                "credential detection failed in all found environments\n  \
+                Custom: no token\n  Custom: no token"
+            );
         });
     }
 
     #[test]
-    fn github_env_failure() {
-        // Missing env variables
-        run_with_env(
-            [
-                ("GITHUB_ACTIONS", Some("1")),
-                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", None),
-            ],
-            || {
-                assert!(matches!(
-                    detect_github(None).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
-            },
+    fn token_validation() {
+        assert_eq!(validate_token(TOKEN.into()), Ok(TOKEN.into()));
+        assert_eq!(validate_token("a-_.b=.c".into()), Ok("a-_.b=.c".into()));
+        assert_eq!(
+            validate_token(format!("{}\n", TOKEN)),
+            Err(CIIDError::MalformedToken)
         );
-        run_with_env(
-            [
-                ("GITHUB_ACTIONS", Some("1")),
-                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", Some("token")),
-                ("ACTIONS_ID_TOKEN_REQUEST_URL", None),
-            ],
-            || {
-                assert!(matches!(
-                    detect_github(None).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
-            },
+        assert_eq!(
+            validate_token("a.b c.d".into()),
+            Err(CIIDError::MalformedToken)
         );
+        assert_eq!(validate_token("a.b".into()), Err(CIIDError::MalformedToken));
+    }
 
-        // request fails
-        run_with_env(
-            [
-                ("GITHUB_ACTIONS", Some("1")),
-                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", Some("token")),
-                ("ACTIONS_ID_TOKEN_REQUEST_URL", Some("http://invalid")),
-            ],
-            || {
-                assert_eq!(
-                    detect_github(None).unwrap_err(),
-                    CIIDError::EnvironmentError("GitHub Actions: Token request failed: error sending request for url (http://invalid/)".into())
+    #[test]
+    fn token_claims_api() {
+        let payload = claims(TOKEN).unwrap();
+        assert_eq!(payload["iss"], "https://oauth2.sigstore.dev/auth");
+        assert_eq!(payload["federated_claims"]["user_id"], "31889");
+
+        assert_eq!(claims("a.b.c"), Err(CIIDError::MalformedToken));
+        assert_eq!(claims("not a token"), Err(CIIDError::MalformedToken));
+    }
+
+    /// Serves (path, body) responses on a local HTTP server, one connection per response.
+    /// `responses` is called with the server URL. Returns the server URL.
+    #[cfg(feature = "verify")]
+    fn serve_http(responses: impl FnOnce(&str) -> Vec<(&'static str, String)>) -> String {
+        use std::io::{BufRead, BufReader};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let responses = responses(&url);
+        std::thread::spawn(move || {
+            for _ in 0..responses.len() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let (status, body) = match responses.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => ("200 OK", body.as_str()),
+                    None => ("404 Not Found", ""),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
                 );
-            },
-        );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
     }
 
-    // TODO This requires mocking reqwest response
-    // fn github_success() { }
+    /// Returns the JWK of a new ES256 key, and a function that signs tokens with the key using
+    /// the given JWT header
+    #[cfg(feature = "verify")]
+    fn test_signing_key() -> (
+        serde_json::Value,
+        impl Fn(&str, &serde_json::Value) -> String,
+    ) {
+        use ring::{
+            rand::SystemRandom,
+            signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+        };
 
-    #[test]
-    fn gitlab_not_detected() {
-        run_with_env([("GITLAB_CI", None)], || {
-            assert_eq!(detect_gitlab(None), Err(CIIDError::EnvironmentNotDetected));
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let point = key.public_key().as_ref().to_vec();
+        let jwk = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        });
+
+        let sign = move |header: &str, claims: &serde_json::Value| {
+            let header = URL_SAFE_NO_PAD.encode(header);
+            let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+            let input = format!("{}.{}", header, payload);
+            let sig = key.sign(&SystemRandom::new(), input.as_bytes()).unwrap();
+            format!("{}.{}", input, URL_SAFE_NO_PAD.encode(sig.as_ref()))
+        };
+        (jwk, sign)
+    }
+
+    /// Returns an issuer URL serving a JWKS, and a function that signs ES256 tokens with the key
+    #[cfg(feature = "verify")]
+    fn test_issuer(connections: usize) -> (String, impl Fn(&serde_json::Value) -> String) {
+        let (mut jwk, sign) = test_signing_key();
+        jwk["kid"] = "test-key".into();
+        let jwks = serde_json::json!({ "keys": [jwk] });
+
+        let issuer = serve_http(|url| {
+            let mut responses = vec![];
+            for _ in 0..connections {
+                responses.push((
+                    "/.well-known/openid-configuration",
+                    format!(r#"{{"jwks_uri":"{}/jwks"}}"#, url),
+                ));
+                responses.push(("/jwks", jwks.to_string()));
+            }
+            responses
         });
+
+        let sign =
+            move |claims: &serde_json::Value| sign(r#"{"alg":"ES256","kid":"test-key"}"#, claims);
+        (issuer, sign)
     }
 
+    #[cfg(feature = "verify")]
     #[test]
-    fn gitlab_env_failure() {
-        // GitLab does not support default audience
-        run_with_env([("GITLAB_CI", Some("1"))], || {
-            assert!(matches!(
-                detect_gitlab(None).unwrap_err(),
-                CIIDError::EnvironmentError(_)
-            ));
+    fn verify_token_signature() {
+        let (issuer, sign) = test_issuer(2);
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let claims = serde_json::json!({"iss": issuer, "aud": "my-audience", "exp": exp});
+        let token = sign(&claims);
+
+        assert_eq!(verify_token(&token, &issuer, Some("my-audience")), Ok(()));
+
+        // Signature does not match the payload
+        let other = sign(&serde_json::json!({"iss": issuer, "aud": "other", "exp": exp}));
+        let parts: Vec<&str> = token.split('.').collect();
+        let other_parts: Vec<&str> = other.split('.').collect();
+        let tampered = format!("{}.{}.{}", parts[0], other_parts[1], parts[2]);
+        assert_eq!(
+            verify_token(&tampered, &issuer, None),
+            Err(CIIDError::VerificationFailed(
+                "Invalid token signature".into()
+            ))
+        );
+    }
+
+    #[cfg(feature = "verify")]
+    #[test]
+    fn verify_token_key_selection() {
+        /// Serves the discovery document and `self.0` as the JWKS of https://issuer.example.com
+        struct Issuer(String);
+
+        impl HttpTransport for Issuer {
+            fn send(&self, request: &HttpRequest) -> std::result::Result<HttpResponse, BoxError> {
+                Ok(match request.url.as_str() {
+                    "https://issuer.example.com/.well-known/openid-configuration" => {
+                        let jwks_uri = "https://issuer.example.com/jwks";
+                        HttpResponse::new(200, format!(r#"{{"jwks_uri":"{}"}}"#, jwks_uri))
+                    }
+                    "https://issuer.example.com/jwks" => HttpResponse::new(200, self.0.clone()),
+                    _ => HttpResponse::new(404, "not found"),
+                })
+            }
+        }
+
+        let issuer = "https://issuer.example.com";
+        let (jwk, sign) = test_signing_key();
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        // without a kid the first key for the algorithm is used
+        let token = sign(
+            r#"{"alg":"ES256"}"#,
+            &serde_json::json!({"iss": issuer, "exp": exp}),
+        );
+        let mut other_alg = jwk.clone();
+        other_alg["alg"] = "ES384".into();
+        let mut encryption = jwk.clone();
+        encryption["use"] = "enc".into();
+        let rsa = serde_json::json!({"kty": "RSA", "n": "AQAB", "e": "AQAB"});
+        let jwks = serde_json::json!({ "keys": [rsa, other_alg, encryption, jwk] });
+        let result = with_transport(Issuer(jwks.to_string()), || {
+            verify_token(&token, issuer, None)
         });
+        assert_eq!(result, Ok(()));
 
-        // Missing token variable for non-default audience
-        run_with_env(
-            [("GITLAB_CI", Some("1")), ("MY_AUD_ID_TOKEN", None)],
-            || {
-                assert!(matches!(
-                    detect_gitlab(Some("my-aud")).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
-            },
+        let jwks = serde_json::json!({ "keys": [rsa, other_alg, encryption] });
+        let result = with_transport(Issuer(jwks.to_string()), || {
+            verify_token(&token, issuer, None)
+        });
+        assert_eq!(
+            result,
+            Err(CIIDError::VerificationFailed(
+                "No ES256 signing key found in issuer JWKS".into()
+            ))
         );
+
+        let result = with_transport(Issuer(String::new()), || {
+            verify_token(&token, "https://other.example.com", None)
+        });
+        assert!(matches!(result, Err(CIIDError::VerificationFailed(_))));
     }
 
+    #[cfg(feature = "verify")]
     #[test]
-    fn gitlab_success() {
-        run_with_env(
-            [("GITLAB_CI", Some("1")), ("MY_AUD_ID_TOKEN", Some(TOKEN))],
-            || {
-                assert_eq!(detect_gitlab(Some("my-aud")), Ok(TOKEN.into()));
-            },
+    fn verify_token_claims() {
+        // Claims are checked before fetching keys
+        let issuer = "https://issuer.example.com";
+        let token = token_with_expiry(SystemTime::now() + Duration::from_secs(600));
+        assert_eq!(
+            verify_token(&token, issuer, None),
+            Err(CIIDError::VerificationFailed(
+                "Token issuer '' does not match expected issuer 'https://issuer.example.com'"
+                    .into()
+            ))
+        );
+        assert_eq!(
+            verify_token(TOKEN, "https://oauth2.sigstore.dev/auth", Some("other")),
+            Err(CIIDError::VerificationFailed(
+                "Token audience [\"sigstore\"] does not match requested audience 'other'".into()
+            ))
+        );
+        assert_eq!(
+            verify_token(TOKEN, "https://oauth2.sigstore.dev/auth", Some("sigstore")),
+            Err(CIIDError::VerificationFailed("Token has expired".into()))
+        );
+        assert_eq!(
+            verify_token("a.b.c", issuer, None),
+            Err(CIIDError::MalformedToken)
         );
     }
 
+    #[cfg(feature = "verify")]
     #[test]
-    fn detect_credentials_no_environments() {
-        run_with_env(
-            [
-                ("BUILDKITE", None),
-                ("CIRCLECI", None),
-                ("GITLAB_CI", None),
-                ("GITHUB_ACTIONS", None),
-            ],
+    fn credential_request_verify() {
+        let (issuer, sign) = test_issuer(1);
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let token = sign(&serde_json::json!({"iss": issuer, "aud": "my-audience", "exp": exp}));
+        with_env(
+            snapshot([("CI_ID_TOKEN_MY_AUDIENCE", token.as_str())]),
             || {
+                let request = CredentialRequest::new()
+                    .audience("my-audience")
+                    .providers([Provider::EnvironmentVariable]);
                 assert_eq!(
-                    detect_credentials(None),
-                    Err(CIIDError::EnvironmentNotDetected)
+                    request
+                        .clone()
+                        .verify(&issuer)
+                        .detect()
+                        .unwrap()
+                        .token
+                        .expose(),
+                    token
                 );
+                assert!(matches!(
+                    request.verify("https://other.example.com").detect(),
+                    Err(CIIDError::VerificationFailed(_))
+                ));
             },
         );
     }
 
+    #[test]
+    fn typed_claims() {
+        let payload = r#"{
+            "iss": "https://token.actions.githubusercontent.com",
+            "sub": "repo:jku/ci-id:ref:refs/heads/main",
+            "aud": "sigstore",
+            "exp": 1729512930,
+            "iat": 1729512870,
+            "repository": "jku/ci-id",
+            "repository_id": "1234",
+            "repository_owner": "jku",
+            "repository_owner_id": "31889",
+            "repository_visibility": "public",
+            "ref": "refs/heads/main",
+            "ref_type": "branch",
+            "sha": "8d94044",
+            "workflow": "CI",
+            "workflow_ref": "jku/ci-id/.github/workflows/ci.yml@refs/heads/main",
+            "job_workflow_ref": "jku/ci-id/.github/workflows/ci.yml@refs/heads/main",
+            "event_name": "push",
+            "actor": "jku",
+            "actor_id": "31889",
+            "run_id": "42",
+            "run_number": "7",
+            "run_attempt": "1",
+            "runner_environment": "github-hosted"
+        }"#;
+        let token = format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(payload));
+        let credential = DetectedCredential::new("GitHub Actions", token);
+
+        let claims: GitHubClaims = credential.claims_as().unwrap();
+        assert_eq!(claims.repository, "jku/ci-id");
+        assert_eq!(claims.git_ref, "refs/heads/main");
+        assert_eq!(claims.environment, None);
+
+        // Claims from another provider do not match
+        assert_eq!(
+            credential.claims_as::<GitLabClaims>(),
+            Err(CIIDError::MalformedToken)
+        );
+    }
+
+    #[test]
+    fn detected_credential() {
+        let credential = DetectedCredential::new("Test", TOKEN.into());
+        assert_eq!(credential.token.expose(), TOKEN);
+        assert_eq!(credential.provider, "Test");
+        assert_eq!(credential.claims["aud"], "sigstore");
+        assert_eq!(
+            credential.issuer.as_deref(),
+            Some("https://oauth2.sigstore.dev/auth")
+        );
+        assert_eq!(
+            credential.expiry,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1729512930))
+        );
+
+        assert_eq!(credential.claim("email"), Some(&"jku@goto.fi".into()));
+        assert_eq!(credential.claim("missing"), None);
+
+        let credential = DetectedCredential::new("Test", "a.b.c".into());
+        assert_eq!(credential.claims, serde_json::Value::Null);
+        assert_eq!(credential.issuer, None);
+        assert_eq!(credential.expiry, None);
+    }
+
     #[test]
     fn detect_credentials_failure() {
         // Unexpected failure in any detector leads to detect_credentials failure.
-        run_with_env(
-            [
-                ("GITHUB_ACTIONS", Some("1")),
-                ("ACTIONS_ID_TOKEN_REQUEST_TOKEN", None),
-            ],
-            || {
-                assert!(matches!(
-                    detect_credentials(None).unwrap_err(),
-                    CIIDError::EnvironmentError(_)
-                ));
-            },
-        );
+        with_env(snapshot([("GITHUB_ACTIONS", "1")]), || {
+            assert!(matches!(
+                detect_credentials(None).unwrap_err(),
+                CIIDError::MissingPermission { .. }
+            ));
+        });
     }
 
     #[test]
     fn detect_credentials_malformed_token() {
         // need to disable GitHub, otherwise we get a "false" positive on CI...
-        run_with_env(
-            [
-                ("GITHUB_ACTIONS", None),
-                ("GITLAB_CI", Some("1")),
-                ("MY_AUD_ID_TOKEN", Some("token value")),
-            ],
+        with_env(
+            snapshot([("GITLAB_CI", "1"), ("MY_AUD_ID_TOKEN", "token value")]),
             || {
                 assert_eq!(
                     detect_credentials(Some("my-aud")),
@@ -618,12 +9558,8 @@ mod tests {
     #[test]
     fn detect_credentials_success() {
         // need to disable GitHub, otherwise we get a "false" positive on CI...
-        run_with_env(
-            [
-                ("GITHUB_ACTIONS", None),
-                ("GITLAB_CI", Some("1")),
-                ("MY_AUD_ID_TOKEN", Some(TOKEN)),
-            ],
+        with_env(
+            snapshot([("GITLAB_CI", "1"), ("MY_AUD_ID_TOKEN", TOKEN)]),
             || {
                 assert_eq!(detect_credentials(Some("my-aud")), Ok(TOKEN.into()));
             },
@@ -0,0 +1,174 @@
+//! CI run metadata from the environment variables of the supported environments
+
+use super::*;
+
+/// Information about the CI run, e.g. for release tooling that records where an artifact was
+/// built.
+///
+/// The values are read from the environment variables of the CI system: fields that the
+/// environment does not provide are None. See [`run_metadata`] and
+/// [`DetectedCredential::run_metadata`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RunMetadata {
+    /// The environment the metadata is from
+    pub environment: Option<CIEnvironment>,
+    /// Repository or project, e.g. "octo/repo" or a repository URL
+    pub repository: Option<String>,
+    /// Branch, tag or full git ref
+    pub git_ref: Option<String>,
+    /// Commit hash
+    pub sha: Option<String>,
+    /// Identifier of the pipeline, workflow run or build
+    pub run_id: Option<String>,
+    /// User that triggered the run
+    pub actor: Option<String>,
+    /// Name of the job
+    pub job: Option<String>,
+}
+
+/// Returns the non-empty value of the environment variable
+fn var(name: &str) -> Option<String> {
+    env_var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Returns "<owner>/<name>" from two environment variables
+fn join(owner: &str, name: &str) -> Option<String> {
+    Some(format!("{}/{}", var(owner)?, var(name)?))
+}
+
+impl CIEnvironment {
+    /// Returns the run metadata from the environment variables of this environment.
+    ///
+    /// The result is only meaningful when the environment is present, see [`run_metadata`].
+    pub fn run_metadata(self) -> RunMetadata {
+        let metadata = match self {
+            // Gitea and Forgejo provide the GitHub Actions variables
+            CIEnvironment::GitHubActions
+            | CIEnvironment::GiteaActions
+            | CIEnvironment::ForgejoActions => RunMetadata {
+                repository: var("GITHUB_REPOSITORY"),
+                git_ref: var("GITHUB_REF"),
+                sha: var("GITHUB_SHA"),
+                run_id: var("GITHUB_RUN_ID"),
+                actor: var("GITHUB_ACTOR"),
+                job: var("GITHUB_JOB"),
+                ..Default::default()
+            },
+            CIEnvironment::GitLab => RunMetadata {
+                repository: var("CI_PROJECT_PATH"),
+                git_ref: var("CI_COMMIT_REF_NAME"),
+                sha: var("CI_COMMIT_SHA"),
+                run_id: var("CI_PIPELINE_ID"),
+                actor: var("GITLAB_USER_LOGIN"),
+                job: var("CI_JOB_NAME"),
+                ..Default::default()
+            },
+            CIEnvironment::CircleCI => RunMetadata {
+                repository: join("CIRCLE_PROJECT_USERNAME", "CIRCLE_PROJECT_REPONAME"),
+                git_ref: var("CIRCLE_TAG").or_else(|| var("CIRCLE_BRANCH")),
+                sha: var("CIRCLE_SHA1"),
+                run_id: var("CIRCLE_WORKFLOW_ID"),
+                actor: var("CIRCLE_USERNAME"),
+                job: var("CIRCLE_JOB"),
+                ..Default::default()
+            },
+            CIEnvironment::Buildkite => RunMetadata {
+                repository: var("BUILDKITE_REPO"),
+                git_ref: var("BUILDKITE_TAG").or_else(|| var("BUILDKITE_BRANCH")),
+                sha: var("BUILDKITE_COMMIT"),
+                run_id: var("BUILDKITE_BUILD_ID"),
+                actor: var("BUILDKITE_BUILD_CREATOR"),
+                job: var("BUILDKITE_LABEL"),
+                ..Default::default()
+            },
+            CIEnvironment::Jenkins => RunMetadata {
+                repository: var("GIT_URL"),
+                git_ref: var("GIT_BRANCH"),
+                sha: var("GIT_COMMIT"),
+                run_id: var("BUILD_TAG"),
+                job: var("JOB_NAME"),
+                ..Default::default()
+            },
+            CIEnvironment::TeamCity => RunMetadata {
+                sha: var("BUILD_VCS_NUMBER"),
+                run_id: var("BUILD_NUMBER"),
+                job: var("TEAMCITY_BUILDCONF_NAME"),
+                ..Default::default()
+            },
+            CIEnvironment::Codefresh => RunMetadata {
+                repository: join("CF_REPO_OWNER", "CF_REPO_NAME"),
+                git_ref: var("CF_BRANCH"),
+                sha: var("CF_REVISION"),
+                run_id: var("CF_BUILD_ID"),
+                actor: var("CF_BUILD_INITIATOR"),
+                job: var("CF_PIPELINE_NAME"),
+                ..Default::default()
+            },
+            CIEnvironment::Bitrise => RunMetadata {
+                repository: var("GIT_REPOSITORY_URL"),
+                git_ref: var("BITRISE_GIT_TAG").or_else(|| var("BITRISE_GIT_BRANCH")),
+                sha: var("BITRISE_GIT_COMMIT"),
+                run_id: var("BITRISE_BUILD_SLUG"),
+                job: var("BITRISE_TRIGGERED_WORKFLOW_ID"),
+                ..Default::default()
+            },
+            CIEnvironment::Vercel => RunMetadata {
+                repository: join("VERCEL_GIT_REPO_OWNER", "VERCEL_GIT_REPO_SLUG"),
+                git_ref: var("VERCEL_GIT_COMMIT_REF"),
+                sha: var("VERCEL_GIT_COMMIT_SHA"),
+                run_id: var("VERCEL_DEPLOYMENT_ID"),
+                actor: var("VERCEL_GIT_COMMIT_AUTHOR_LOGIN"),
+                ..Default::default()
+            },
+            _ => RunMetadata::default(),
+        };
+        RunMetadata {
+            environment: Some(self),
+            ..metadata
+        }
+    }
+}
+
+/// Returns the run metadata of the detected environment, see [`detect_environment`]
+///
+/// ```
+/// if let Some(metadata) = ci_id::run_metadata() {
+///     println!("Built from {:?} at {:?}", metadata.repository, metadata.sha);
+/// }
+/// ```
+pub fn run_metadata() -> Option<RunMetadata> {
+    detect_environment().map(CIEnvironment::run_metadata)
+}
+
+impl DetectedCredential {
+    /// Returns the run metadata of the environment that provided the token. Values that the
+    /// environment variables do not provide are filled from the unverified token claims where
+    /// possible (e.g. `repository`, `ref` and `sha` in GitHub Actions tokens).
+    pub fn run_metadata(&self) -> RunMetadata {
+        let metadata = CIEnvironment::ALL
+            .iter()
+            .find(|env| env.name() == self.provider)
+            .map(|env| env.run_metadata())
+            .unwrap_or_default();
+        let claim = |names: &[&str]| {
+            names.iter().find_map(|name| match self.claim(name)? {
+                serde_json::Value::String(value) => Some(value.clone()),
+                serde_json::Value::Number(value) => Some(value.to_string()),
+                _ => None,
+            })
+        };
+        RunMetadata {
+            repository: metadata
+                .repository
+                .or_else(|| claim(&["repository", "project_path"])),
+            git_ref: metadata.git_ref.or_else(|| claim(&["ref"])),
+            sha: metadata.sha.or_else(|| claim(&["sha", "build_commit"])),
+            run_id: metadata
+                .run_id
+                .or_else(|| claim(&["run_id", "pipeline_id"])),
+            actor: metadata.actor.or_else(|| claim(&["actor", "user_login"])),
+            ..metadata
+        }
+    }
+}
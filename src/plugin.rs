@@ -0,0 +1,148 @@
+//! External detector plugins: `ci-id-provider-<name>` executables in PATH
+//!
+//! A plugin is run without arguments, with the requested audience in `CI_ID_AUDIENCE` (unset if
+//! no audience was requested). The plugin writes the token to stdout, either as is or as a JSON
+//! object `{"token": "..."}`. Empty output means that the plugin did not detect its environment.
+//! If the environment was detected but the token can not be provided, the plugin exits with a
+//! non-zero exit code or writes `{"error": "..."}`.
+//!
+//! On Windows plugins are `.exe`, `.com`, `.bat` or `.cmd` files, e.g. `ci-id-provider-vault.exe`.
+
+use super::*;
+
+use std::ffi::{OsStr, OsString};
+
+/// File name prefix of plugin executables
+const PLUGIN_PREFIX: &str = "ci-id-provider-";
+
+/// The plugins found with the last searched PATH value
+static DISCOVERED: Mutex<Option<(OsString, Vec<PluginDetector>)>> = Mutex::new(None);
+
+/// A detector that runs a plugin executable
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PluginDetector {
+    name: String,
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PluginResponse {
+    Token { token: String },
+    Error { error: String },
+}
+
+/// Returns the plugins found in PATH, in PATH order. If the same plugin is found in multiple
+/// directories, the first one is used. The directories are only searched again if PATH changes.
+pub(crate) fn discover_plugins() -> Vec<PluginDetector> {
+    let paths = match current_env() {
+        Some(env) => env.var("PATH").map(Into::into),
+        None => env::var_os("PATH"),
+    };
+    let Some(paths) = paths else {
+        return vec![];
+    };
+    let mut discovered = DISCOVERED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((searched, plugins)) = &*discovered {
+        if *searched == paths {
+            return plugins.clone();
+        }
+    }
+    let plugins = search_plugins(&paths);
+    *discovered = Some((paths, plugins.clone()));
+    plugins
+}
+
+/// Returns the plugins in the directories of `paths`
+fn search_plugins(paths: &OsStr) -> Vec<PluginDetector> {
+    let mut plugins: Vec<PluginDetector> = vec![];
+    for dir in env::split_paths(paths) {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut found: Vec<PluginDetector> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let file_name = path.file_name()?.to_str()?;
+                let name = file_name.strip_prefix(PLUGIN_PREFIX)?;
+                if name.is_empty() || !is_executable(&path) {
+                    return None;
+                }
+                Some(PluginDetector {
+                    name: file_name.into(),
+                    path,
+                })
+            })
+            .filter(|plugin| !plugins.iter().any(|p| p.name == plugin.name))
+            .collect();
+        found.sort_by(|a, b| a.name.cmp(&b.name));
+        plugins.extend(found);
+    }
+    plugins
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(windows)]
+fn is_executable(path: &Path) -> bool {
+    let executable = path.extension().is_some_and(|ext| {
+        ["exe", "com", "bat", "cmd"]
+            .iter()
+            .any(|known| ext.eq_ignore_ascii_case(known))
+    });
+    executable && path.is_file()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+impl Detector for PluginDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect(&self, audience: Option<&str>) -> Result<String> {
+        let call =
+            CommandCall::new(&self.path.to_string_lossy(), &[]).env("CI_ID_AUDIENCE", audience);
+        let output = run_command(&self.name, call)?;
+        let output = output.trim();
+        if output.is_empty() {
+            return Err(CIIDError::EnvironmentNotDetected);
+        }
+        if !output.starts_with('{') {
+            return Ok(output.into());
+        }
+        match serde_json::from_str(output) {
+            Ok(PluginResponse::Token { token }) => Ok(token),
+            Ok(PluginResponse::Error { error }) => Err(CIIDError::EnvironmentError(format!(
+                "{}: {}",
+                self.name, error
+            ))),
+            Err(e) => Err(CIIDError::EnvironmentError(format!(
+                "{}: Failed to parse plugin response: {}",
+                self.name, e
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncDetector for PluginDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect<'a>(&'a self, audience: Option<&'a str>) -> DetectFuture<'a> {
+        let detector = self.clone();
+        let audience = audience.map(String::from);
+        Box::pin(asynchronous::blocking(move || {
+            Detector::detect(&detector, audience.as_deref())
+        }))
+    }
+}
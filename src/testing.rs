@@ -0,0 +1,123 @@
+//! Test helpers for applications that integrate ci-id, enabled with the `testing` feature: a
+//! fake detector with a canned token, fake tokens and a scoped detection environment. These
+//! allow testing detection flows outside of a real CI system.
+//!
+//! ```
+//! use ci_id::testing::{fake_token, ScopedEnv};
+//!
+//! let token = fake_token(serde_json::json!({
+//!     "iss": "https://ci.example.com",
+//!     "aud": "my-audience",
+//! }));
+//! let _env = ScopedEnv::new([("CI_ID_TOKEN", token.as_str())]);
+//! let credential = ci_id::detect_credential(Some("my-audience")).unwrap();
+//! assert_eq!(credential.issuer.as_deref(), Some("https://ci.example.com"));
+//! ```
+
+use super::*;
+
+use std::marker::PhantomData;
+
+/// Returns an unsigned JWT (`"alg": "none"`) with the given claims. The token passes the
+/// token validation of detection but not `verify_token`.
+pub fn fake_token(claims: serde_json::Value) -> String {
+    let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"none","typ":"JWT"}"#);
+    let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+    format!("{}.{}.", header, payload)
+}
+
+/// A [`Detector`] with a canned result, e.g. for [`detect_credentials_with`].
+///
+/// ```
+/// use ci_id::testing::{fake_token, FakeDetector};
+///
+/// let token = fake_token(serde_json::json!({"aud": "my-audience"}));
+/// let detector = FakeDetector::new("Fake CI", &token);
+/// let detected = ci_id::detect_credentials_with(&[&detector], Some("my-audience"));
+/// assert_eq!(detected.unwrap().expose(), token);
+/// ```
+#[derive(Debug, Clone)]
+pub struct FakeDetector {
+    name: String,
+    result: Result<String>,
+}
+
+impl FakeDetector {
+    /// Returns a detector that provides `token` for any audience
+    pub fn new(name: impl Into<String>, token: impl Into<String>) -> Self {
+        FakeDetector {
+            name: name.into(),
+            result: Ok(token.into()),
+        }
+    }
+
+    /// Returns a detector whose environment is not present
+    pub fn not_detected(name: impl Into<String>) -> Self {
+        FakeDetector {
+            name: name.into(),
+            result: Err(CIIDError::EnvironmentNotDetected),
+        }
+    }
+
+    /// Returns a detector whose environment is present but fails with `error`
+    pub fn failing(name: impl Into<String>, error: CIIDError) -> Self {
+        FakeDetector {
+            name: name.into(),
+            result: Err(error),
+        }
+    }
+}
+
+impl Detector for FakeDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect(&self, _audience: Option<&str>) -> Result<String> {
+        self.result.clone()
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncDetector for FakeDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn detect<'a>(&'a self, audience: Option<&'a str>) -> DetectFuture<'a> {
+        Box::pin(async move { Detector::detect(self, audience) })
+    }
+}
+
+/// Sets the detection environment of this thread until dropped: detection on this thread sees
+/// only the given variables, not the process environment. This is the guard version of
+/// [`with_env`].
+///
+/// The process environment is not modified, so tests using `ScopedEnv` can run in parallel.
+/// Nested guards must be dropped in reverse order of creation.
+pub struct ScopedEnv {
+    previous: Option<Arc<dyn EnvReader>>,
+    // The guard restores a thread local: it must be dropped on the thread that created it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ScopedEnv {
+    /// Sets `vars` as the detection environment of this thread
+    pub fn new<K: Into<String>, V: Into<String>>(vars: impl IntoIterator<Item = (K, V)>) -> Self {
+        let env: HashMap<String, String> = vars
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect();
+        let previous = ENV_READER.replace(Some(Arc::new(env)));
+        ScopedEnv {
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for ScopedEnv {
+    fn drop(&mut self) {
+        ENV_READER.set(self.previous.take());
+    }
+}
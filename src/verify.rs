@@ -0,0 +1,214 @@
+//! Token signature verification, enabled with the `verify` feature
+
+use super::*;
+
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // EC
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+fn verification_error(message: impl Into<String>) -> CIIDError {
+    CIIDError::VerificationFailed(message.into())
+}
+
+/// Returns the JWK key type for the signature algorithm `alg`, or None if `alg` is not supported
+fn key_type(alg: &str) -> Option<&'static str> {
+    match alg {
+        "RS256" | "RS384" | "RS512" => Some("RSA"),
+        "ES256" | "ES384" => Some("EC"),
+        _ => None,
+    }
+}
+
+/// Returns true if `key` is a signing key for `alg` with the given `kid`. Without `kid` any
+/// signing key for `alg` matches.
+fn key_matches(key: &Jwk, alg: &str, kid: Option<&str>) -> bool {
+    key_type(alg) == Some(key.kty.as_str())
+        && key.alg.as_deref().is_none_or(|key_alg| key_alg == alg)
+        && key
+            .key_use
+            .as_deref()
+            .is_none_or(|key_use| key_use == "sig")
+        && kid.is_none_or(|kid| key.kid.as_deref() == Some(kid))
+}
+
+fn decode_part(part: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(part.trim_end_matches('='))
+        .map_err(|_| CIIDError::MalformedToken)
+}
+
+/// Verifies that the token is signed by `issuer`, is not expired and, if `audience` is given,
+/// is issued for the audience.
+///
+/// The signing keys are fetched from the JWKS of the issuer, found via its OIDC discovery document.
+/// The documents are fetched like token requests, with the transport set with [`with_transport`] if
+/// any. `issuer` must be the issuer the caller trusts (e.g. the issuer of the expected CI
+/// environment): the token `iss` claim has to match it.
+///
+/// ```no_run
+/// let token = ci_id::detect_credentials(Some("my-audience")).unwrap();
+/// ci_id::verify_token(
+///     token.expose(),
+///     "https://token.actions.githubusercontent.com",
+///     Some("my-audience"),
+/// )
+/// .unwrap();
+/// ```
+pub fn verify_token(token: &str, issuer: &str, audience: Option<&str>) -> Result<()> {
+    let parts: Vec<&str> = token.split('.').collect();
+    let [encoded_header, payload, sig] = parts[..] else {
+        return Err(CIIDError::MalformedToken);
+    };
+    let header: JwtHeader = serde_json::from_slice(&decode_part(encoded_header)?)
+        .map_err(|_| CIIDError::MalformedToken)?;
+    let claims = token_claims(token).ok_or(CIIDError::MalformedToken)?;
+
+    match claims.get("iss").and_then(|iss| iss.as_str()) {
+        Some(iss) if iss == issuer => {}
+        iss => {
+            return Err(verification_error(format!(
+                "Token issuer '{}' does not match expected issuer '{}'",
+                iss.unwrap_or_default(),
+                issuer
+            )))
+        }
+    }
+    if let Some(audience) = audience {
+        let audiences = token_audiences(token).unwrap_or_default();
+        if !audiences.iter().any(|aud| aud == audience) {
+            return Err(verification_error(format!(
+                "Token audience {:?} does not match requested audience '{}'",
+                audiences, audience
+            )));
+        }
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match claims.get("exp").and_then(|exp| exp.as_u64()) {
+        Some(exp) if exp > now => {}
+        Some(_) => return Err(verification_error("Token has expired")),
+        None => return Err(verification_error("Token has no expiry time")),
+    }
+
+    if key_type(&header.alg).is_none() {
+        return Err(verification_error(format!(
+            "Unsupported signature algorithm {}",
+            header.alg
+        )));
+    }
+    let jwks = fetch_jwks(issuer)?;
+    let kid = header.kid.as_deref();
+    let key = jwks
+        .keys
+        .iter()
+        .find(|key| key_matches(key, &header.alg, kid))
+        .ok_or_else(|| match kid {
+            Some(kid) => {
+                verification_error(format!("Signing key {:?} not found in issuer JWKS", kid))
+            }
+            None => verification_error(format!(
+                "No {} signing key found in issuer JWKS",
+                header.alg
+            )),
+        })?;
+
+    let signing_input = format!("{}.{}", encoded_header, payload);
+    verify_signature(
+        &header.alg,
+        key,
+        signing_input.as_bytes(),
+        &decode_part(sig)?,
+    )
+}
+
+fn fetch_jwks(issuer: &str) -> Result<Jwks> {
+    let discovery_url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let discovery: DiscoveryDocument = fetch(discovery_url)?;
+    fetch(discovery.jwks_uri)
+}
+
+/// Fetches and parses the JSON document at `url`
+fn fetch<T: DeserializeOwned>(url: String) -> Result<T> {
+    log::debug!("Token verification: Fetching {}", url);
+    TokenRequest::document("Token verification", url.clone())
+        .send()
+        .map_err(|e| verification_error(format!("Failed to fetch {}: {}", url, e)))
+}
+
+fn verify_signature(alg: &str, key: &Jwk, message: &[u8], sig: &[u8]) -> Result<()> {
+    let key_error = || verification_error(format!("Signing key does not support {}", alg));
+    let result = match (alg, key.kty.as_str()) {
+        ("RS256" | "RS384" | "RS512", "RSA") => {
+            let (Some(n), Some(e)) = (&key.n, &key.e) else {
+                return Err(key_error());
+            };
+            let params = match alg {
+                "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+            };
+            RsaPublicKeyComponents {
+                n: decode_part(n)?,
+                e: decode_part(e)?,
+            }
+            .verify(params, message, sig)
+        }
+        ("ES256" | "ES384", "EC") => {
+            let (Some(crv), Some(x), Some(y)) = (&key.crv, &key.x, &key.y) else {
+                return Err(key_error());
+            };
+            let params = match (alg, crv.as_str()) {
+                ("ES256", "P-256") => &signature::ECDSA_P256_SHA256_FIXED,
+                ("ES384", "P-384") => &signature::ECDSA_P384_SHA384_FIXED,
+                _ => return Err(key_error()),
+            };
+            // uncompressed point
+            let mut point = vec![0x04];
+            point.extend(decode_part(x)?);
+            point.extend(decode_part(y)?);
+            UnparsedPublicKey::new(params, point).verify(message, sig)
+        }
+        _ => {
+            return Err(verification_error(format!(
+                "Unsupported signature algorithm {}",
+                alg
+            )))
+        }
+    };
+    result.map_err(|_| verification_error("Invalid token signature"))
+}